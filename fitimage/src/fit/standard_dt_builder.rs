@@ -277,10 +277,28 @@ impl StandardFdtBuilder {
     /// Add ramdisk image node
     fn add_ramdisk_image(&mut self, name: &str, component: &ComponentConfig) -> Result<()> {
         self.begin_node(name)?;
-        self.add_property_string("description", "Ramdisk Image")?;
+
+        // Use custom description if provided, otherwise default
+        if let Some(ref desc) = component.description {
+            self.add_property_string("description", desc)?;
+        } else {
+            self.add_property_string("description", "Ramdisk Image")?;
+        }
         self.add_property_string("type", "ramdisk")?;
-        self.add_property_string("arch", "arm64")?;
-        self.add_property_string("os", "linux")?;
+
+        // Use custom arch if provided, otherwise default
+        if let Some(ref arch_str) = component.arch {
+            self.add_property_string("arch", arch_str)?;
+        } else {
+            self.add_property_string("arch", "arm64")?;
+        }
+
+        // Use custom OS if provided, otherwise default
+        if let Some(ref os_str) = component.os {
+            self.add_property_string("os", os_str)?;
+        } else {
+            self.add_property_string("os", "linux")?;
+        }
         // Use custom compression if provided, otherwise default
         if component.compression {
             self.add_property_string("compression", "gzip")?;
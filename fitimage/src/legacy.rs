@@ -0,0 +1,394 @@
+//! Legacy uImage builder
+//!
+//! Produces images with the 64-byte `image_header` used by U-Boot before FIT,
+//! equivalent to `mkimage -A <arch> -O <os> -T kernel|script -C none|gzip`.
+//! All header fields are stored big-endian.
+
+use crate::compression::gzip::GzipCompressor;
+use crate::compression::traits::CompressionInterface;
+use crate::crc::calculate_crc32;
+use crate::error::{MkImageError, Result};
+
+/// Legacy image magic number
+pub const IH_MAGIC: u32 = 0x2705_1956;
+/// Size of the image name field
+pub const IH_NMLEN: usize = 32;
+/// Size of the legacy image header
+pub const LEGACY_HEADER_SIZE: usize = 64;
+
+const IH_COMP_NONE: u8 = 0;
+const IH_COMP_GZIP: u8 = 1;
+
+/// Payload type of a legacy image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyImageType {
+    /// OS kernel image (`-T kernel`)
+    Kernel,
+    /// U-Boot script image (`-T script`), e.g. `boot.scr`
+    Script,
+}
+
+impl LegacyImageType {
+    fn id(&self) -> u8 {
+        match self {
+            LegacyImageType::Kernel => 2,
+            LegacyImageType::Script => 6,
+        }
+    }
+}
+
+/// Decoded legacy image header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyImageHeader {
+    pub header_crc: u32,
+    pub time: u32,
+    pub size: u32,
+    pub load: u32,
+    pub entry: u32,
+    pub data_crc: u32,
+    pub os: u8,
+    pub arch: u8,
+    pub image_type: u8,
+    pub compression: u8,
+    pub name: String,
+}
+
+impl LegacyImageHeader {
+    /// Parse and verify the header and payload CRCs of a legacy image.
+    pub fn parse(image: &[u8]) -> Result<Self> {
+        if image.len() < LEGACY_HEADER_SIZE {
+            return Err(MkImageError::invalid_image_data(
+                "Data too short to contain a legacy image header",
+            ));
+        }
+
+        let be32 =
+            |offset: usize| u32::from_be_bytes(image[offset..offset + 4].try_into().unwrap());
+
+        let magic = be32(0);
+        if magic != IH_MAGIC {
+            return Err(MkImageError::InvalidMagic {
+                expected: IH_MAGIC,
+                found: magic,
+            });
+        }
+
+        let mut header = image[..LEGACY_HEADER_SIZE].to_vec();
+        header[4..8].fill(0);
+        let header_crc = be32(4);
+        let calculated = calculate_crc32(&header);
+        if calculated != header_crc {
+            return Err(MkImageError::crc_mismatch(header_crc, calculated));
+        }
+
+        let size = be32(12);
+        let data = image
+            .get(LEGACY_HEADER_SIZE..LEGACY_HEADER_SIZE + size as usize)
+            .ok_or_else(|| MkImageError::invalid_image_data("Image data is truncated"))?;
+        let data_crc = be32(24);
+        let calculated = calculate_crc32(data);
+        if calculated != data_crc {
+            return Err(MkImageError::crc_mismatch(data_crc, calculated));
+        }
+
+        let name = &image[32..32 + IH_NMLEN];
+        let name_len = name.iter().position(|b| *b == 0).unwrap_or(IH_NMLEN);
+
+        Ok(Self {
+            header_crc,
+            time: be32(8),
+            size,
+            load: be32(16),
+            entry: be32(20),
+            data_crc,
+            os: image[28],
+            arch: image[29],
+            image_type: image[30],
+            compression: image[31],
+            name: String::from_utf8_lossy(&name[..name_len]).to_string(),
+        })
+    }
+}
+
+/// Builder for legacy uImage files
+#[derive(Debug, Clone)]
+pub struct LegacyImageBuilder {
+    name: String,
+    image_type: LegacyImageType,
+    arch: String,
+    os: String,
+    compression: bool,
+    load_address: u64,
+    entry_point: u64,
+    timestamp: Option<u32>,
+}
+
+impl LegacyImageBuilder {
+    /// Create a builder for a kernel image
+    pub fn kernel(name: impl Into<String>) -> Self {
+        Self::new(name, LegacyImageType::Kernel)
+    }
+
+    /// Create a builder for a script image
+    pub fn script(name: impl Into<String>) -> Self {
+        Self::new(name, LegacyImageType::Script)
+    }
+
+    /// Create a builder for an image of the given type
+    pub fn new(name: impl Into<String>, image_type: LegacyImageType) -> Self {
+        Self {
+            name: name.into(),
+            image_type,
+            arch: "arm64".to_string(),
+            os: "linux".to_string(),
+            compression: false,
+            load_address: 0,
+            entry_point: 0,
+            timestamp: None,
+        }
+    }
+
+    /// Set architecture (arm, arm64, riscv, x86, x86_64, mips, powerpc, loongarch)
+    pub fn with_arch(mut self, arch: impl Into<String>) -> Self {
+        self.arch = arch.into();
+        self
+    }
+
+    /// Set OS type (linux, u-boot, ...)
+    pub fn with_os(mut self, os: impl Into<String>) -> Self {
+        self.os = os.into();
+        self
+    }
+
+    /// Gzip the payload. Ignored for scripts.
+    pub fn with_compression(mut self, b: bool) -> Self {
+        self.compression = b;
+        self
+    }
+
+    /// Set load address
+    pub fn with_load_address(mut self, load_address: u64) -> Self {
+        self.load_address = load_address;
+        self
+    }
+
+    /// Set entry point address
+    pub fn with_entry_point(mut self, entry_point: u64) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    /// Set the header timestamp, defaults to `SOURCE_DATE_EPOCH` or the current time
+    pub fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Build the image: header followed by the (possibly compressed) payload
+    pub fn build(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.name.len() > IH_NMLEN {
+            return Err(MkImageError::NameTooLong {
+                len: self.name.len(),
+                max: IH_NMLEN,
+            });
+        }
+
+        let load =
+            u32::try_from(self.load_address).map_err(|_| MkImageError::InvalidLoadAddress {
+                address: self.load_address,
+            })?;
+        let entry =
+            u32::try_from(self.entry_point).map_err(|_| MkImageError::InvalidEntryPoint {
+                address: self.entry_point,
+            })?;
+
+        let (payload, compression) = match self.image_type {
+            LegacyImageType::Script => (script_payload(data)?, IH_COMP_NONE),
+            LegacyImageType::Kernel if self.compression => {
+                (GzipCompressor::default().compress(data)?, IH_COMP_GZIP)
+            }
+            LegacyImageType::Kernel => (data.to_vec(), IH_COMP_NONE),
+        };
+
+        let size = u32::try_from(payload.len()).map_err(|_| MkImageError::DataTooLarge {
+            size: payload.len() as u64,
+            max: u32::MAX as u64,
+        })?;
+
+        let mut header = Vec::with_capacity(LEGACY_HEADER_SIZE);
+        header.extend_from_slice(&IH_MAGIC.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes());
+        header.extend_from_slice(&self.timestamp().to_be_bytes());
+        header.extend_from_slice(&size.to_be_bytes());
+        header.extend_from_slice(&load.to_be_bytes());
+        header.extend_from_slice(&entry.to_be_bytes());
+        header.extend_from_slice(&calculate_crc32(&payload).to_be_bytes());
+        header.push(os_id(&self.os)?);
+        header.push(arch_id(&self.arch)?);
+        header.push(self.image_type.id());
+        header.push(compression);
+
+        let mut name = [0u8; IH_NMLEN];
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        header.extend_from_slice(&name);
+
+        let header_crc = calculate_crc32(&header);
+        header[4..8].copy_from_slice(&header_crc.to_be_bytes());
+
+        header.extend_from_slice(&payload);
+        Ok(header)
+    }
+
+    fn timestamp(&self) -> u32 {
+        if let Some(timestamp) = self.timestamp {
+            return timestamp;
+        }
+        if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            return epoch;
+        }
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or_default()
+    }
+}
+
+/// Script images hold a zero terminated table of big-endian lengths
+/// followed by the script itself, like `mkimage -T script`.
+fn script_payload(script: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(script.len()).map_err(|_| MkImageError::DataTooLarge {
+        size: script.len() as u64,
+        max: u32::MAX as u64,
+    })?;
+
+    let mut payload = Vec::with_capacity(script.len() + 8);
+    payload.extend_from_slice(&len.to_be_bytes());
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    payload.extend_from_slice(script);
+    Ok(payload)
+}
+
+fn os_id(os: &str) -> Result<u8> {
+    let id = match os {
+        "invalid" => 0,
+        "openbsd" => 1,
+        "netbsd" => 2,
+        "freebsd" => 3,
+        "linux" => 5,
+        "vxworks" => 14,
+        "qnx" => 16,
+        "u-boot" => 17,
+        "rtems" => 18,
+        "efi" => 28,
+        _ => {
+            return Err(MkImageError::InvalidImageData(format!(
+                "Unsupported OS type: {os}"
+            )))
+        }
+    };
+    Ok(id)
+}
+
+fn arch_id(arch: &str) -> Result<u8> {
+    let id = match arch {
+        "arm" => 2,
+        "x86" | "i386" => 3,
+        "mips" => 5,
+        "mips64" => 6,
+        "powerpc" | "ppc" => 7,
+        "x86_64" => 24,
+        "arm64" | "aarch64" => 22,
+        "riscv" => 26,
+        "loongarch" | "loongarch64" => 27,
+        _ => return Err(MkImageError::unsupported_arch(arch)),
+    };
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_image() {
+        let data = vec![0xaau8; 100];
+        let image = LegacyImageBuilder::kernel("ostool")
+            .with_arch("arm64")
+            .with_load_address(0x4008_0000)
+            .with_entry_point(0x4008_0000)
+            .with_timestamp(0x1234_5678)
+            .build(&data)
+            .unwrap();
+
+        assert_eq!(image.len(), LEGACY_HEADER_SIZE + data.len());
+        assert_eq!(&image[..4], &IH_MAGIC.to_be_bytes());
+
+        let header = LegacyImageHeader::parse(&image).unwrap();
+        assert_eq!(header.size, 100);
+        assert_eq!(header.load, 0x4008_0000);
+        assert_eq!(header.entry, 0x4008_0000);
+        assert_eq!(header.time, 0x1234_5678);
+        assert_eq!(header.arch, 22);
+        assert_eq!(header.os, 5);
+        assert_eq!(header.image_type, 2);
+        assert_eq!(header.compression, IH_COMP_NONE);
+        assert_eq!(header.name, "ostool");
+        assert_eq!(&image[LEGACY_HEADER_SIZE..], &data[..]);
+    }
+
+    #[test]
+    fn test_compressed_kernel() {
+        let data = b"kernel ".repeat(64);
+        let image = LegacyImageBuilder::kernel("gz")
+            .with_compression(true)
+            .build(&data)
+            .unwrap();
+
+        let header = LegacyImageHeader::parse(&image).unwrap();
+        assert_eq!(header.compression, IH_COMP_GZIP);
+        let payload = &image[LEGACY_HEADER_SIZE..];
+        assert_eq!(GzipCompressor::default().decompress(payload).unwrap(), data);
+    }
+
+    #[test]
+    fn test_script_image() {
+        let script = b"echo hello\n";
+        let image = LegacyImageBuilder::script("boot script")
+            .with_arch("arm")
+            .build(script)
+            .unwrap();
+
+        let header = LegacyImageHeader::parse(&image).unwrap();
+        assert_eq!(header.image_type, 6);
+        assert_eq!(header.size as usize, script.len() + 8);
+
+        let payload = &image[LEGACY_HEADER_SIZE..];
+        assert_eq!(&payload[..4], &(script.len() as u32).to_be_bytes());
+        assert_eq!(&payload[4..8], &[0, 0, 0, 0]);
+        assert_eq!(&payload[8..], script);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = LegacyImageBuilder::kernel("x".repeat(33))
+            .build(&[])
+            .unwrap_err();
+        assert!(matches!(err, MkImageError::NameTooLong { len: 33, .. }));
+
+        let err = LegacyImageBuilder::kernel("k")
+            .with_load_address(0x1_0000_0000)
+            .build(&[])
+            .unwrap_err();
+        assert!(matches!(err, MkImageError::InvalidLoadAddress { .. }));
+
+        let mut image = LegacyImageBuilder::kernel("k").build(&[1, 2, 3]).unwrap();
+        image[LEGACY_HEADER_SIZE] ^= 0xff;
+        assert!(matches!(
+            LegacyImageHeader::parse(&image),
+            Err(MkImageError::CrcMismatch { .. })
+        ));
+    }
+}
@@ -3,6 +3,7 @@ pub mod crc;
 pub mod error;
 pub mod fit;
 pub mod hash;
+pub mod legacy;
 
 // Re-export main types for convenience
 pub use compression::traits::CompressionInterface;
@@ -10,6 +11,7 @@ pub use crc::calculate_crc32;
 pub use error::{MkImageError, Result};
 pub use fit::{ComponentConfig, FitImageBuilder, FitImageConfig};
 pub use hash::{calculate_hashes, default_hash_algorithms, HashAlgorithm, HashResult};
+pub use legacy::{LegacyImageBuilder, LegacyImageHeader, LegacyImageType};
 
 /// Current version of the mkimage implementation
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
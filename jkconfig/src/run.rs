@@ -63,8 +63,14 @@ pub async fn run<C: JsonSchema + DeserializeOwned>(
                 .with_context(|| format!("Failed to write {}", config_path.display()))?;
         }
         "toml" => {
-            let content = toml::to_string_pretty(&val)?;
-            tokio::fs::write(&config_path, content)
+            let mut out = toml::to_string_pretty(&val)?;
+            // keep the `#:schema` line editors use for validation
+            if let Some(first) = content.lines().next()
+                && first.starts_with("#:schema")
+            {
+                out = format!("{first}\n\n{out}");
+            }
+            tokio::fs::write(&config_path, out)
                 .await
                 .with_context(|| format!("Failed to write {}", config_path.display()))?;
         }
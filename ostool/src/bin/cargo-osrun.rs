@@ -3,9 +3,18 @@ use std::{env, path::PathBuf, process::exit};
 use clap::{Parser, Subcommand};
 use log::{LevelFilter, debug};
 use ostool::{
+    build::{
+        config::{DiskImage, OutputFormat},
+        initramfs::INITRAMFS_ENV,
+        size,
+    },
+    config::overrides::Overrides,
     ctx::{AppContext, OutputConfig, PathConfig},
+    event::{Event, MessageFormat},
+    hooks::{HookStage, Hooks, RunnerKind},
     run::{
-        qemu,
+        RunReport, qemu,
+        report::{self, REPORT_ENV, TestRecord, TestReport},
         uboot::{self, RunUbootArgs},
     },
 };
@@ -17,7 +26,8 @@ struct RunnerArgs {
     /// Path to the binary to run on the device
     elf: PathBuf,
 
-    /// Test name
+    /// Test name, the first of the args given to the kernel
+    #[arg(allow_hyphen_values = true)]
     test_name: Option<String>,
 
     /// Objcopy elf to binary before running
@@ -36,6 +46,7 @@ struct RunnerArgs {
     #[arg(short, long)]
     config: Option<PathBuf>,
 
+    /// Print the console output even with `-q`
     #[arg(long("show-output"))]
     show_output: bool,
 
@@ -53,8 +64,8 @@ struct RunnerArgs {
     #[arg(long)]
     dtb_dump: bool,
 
-    #[arg(allow_hyphen_values = true)]
-    /// Arguments to be run
+    #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+    /// Arguments added to the kernel command line
     runner_args: Vec<String>,
 
     #[arg(long)]
@@ -62,6 +73,11 @@ struct RunnerArgs {
 
     #[arg(long)]
     bin_dir: Option<String>,
+
+    /// Adds the result to the JSON and JUnit reports `<path>.json` and
+    /// `<path>.xml`, `$OSTOOL_REPORT` by default
+    #[arg(long)]
+    report: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand, Clone)]
@@ -103,39 +119,89 @@ async fn main() -> anyhow::Result<()> {
         Err(_) => manifest_dir.clone(),
     };
 
-    let bin_dir: Option<PathBuf> = args.bin_dir.map(PathBuf::from);
-    let build_dir: Option<PathBuf> = args.build_dir.map(PathBuf::from);
+    let bin_dir: Option<PathBuf> = args.bin_dir.clone().map(PathBuf::from);
+    let build_dir: Option<PathBuf> = args.build_dir.clone().map(PathBuf::from);
 
     let output_config = OutputConfig { build_dir, bin_dir };
 
-    let mut app = AppContext {
+    let app = AppContext {
         paths: PathConfig {
             workspace: workspace_folder,
             manifest: manifest_dir,
             config: output_config,
             ..Default::default()
         },
+        runner: Some(match args.command {
+            Some(SubCommands::Uboot(_)) => RunnerKind::Uboot,
+            None => RunnerKind::Qemu,
+        }),
+        // Already selected by `ostool run`
+        hooks: Hooks::from_env()?,
+        overrides: Overrides::from_env()?,
+        events: MessageFormat::from_env()?.events(),
         ..Default::default()
     };
 
+    let events = app.events.clone();
+    let runner = app.runner.unwrap_or(RunnerKind::Qemu);
+    let report = args
+        .report
+        .clone()
+        .or_else(|| env::var_os(REPORT_ENV).map(PathBuf::from));
+    let binary = report::binary_name(&args.elf);
+    let name = args
+        .test_name
+        .clone()
+        .filter(|name| !name.starts_with('-'))
+        .unwrap_or_else(|| binary.clone());
+
+    let res = run(app, args).await;
+    if let Some(path) = report {
+        TestReport::append(&path, TestRecord::new(name, binary, runner, &res))?;
+    }
+    let res = res.map(|_| ());
+    if let Err(e) = &res
+        && events.is_json()
+    {
+        events.emit(Event::Error {
+            message: format!("{e:#}"),
+        });
+    }
+    res
+}
+
+async fn run(mut app: AppContext, args: RunnerArgs) -> anyhow::Result<RunReport> {
+    app.paths.artifacts.initramfs = env::var(INITRAMFS_ENV).ok().map(PathBuf::from);
     app.set_elf_path(args.elf).await;
+    app.report_size(&size::limits_from_env()?)?;
     app.objcopy_elf()?;
 
     app.debug = args.debug;
     if args.to_bin {
         app.objcopy_output_bin()?;
     }
+    app.objcopy_output_formats(&OutputFormat::from_env()?)?;
+    if let Some(disk) = DiskImage::from_env()? {
+        app.build_disk_image(&disk)?;
+    }
+    app.run_hooks(HookStage::PostBuild)?;
 
+    let show_output = args.show_output || !args.quiet;
+    // `cargo test -- <args>` hands the args after the ELF to the runner
+    let mut cmdline_args: Vec<String> =
+        args.test_name.into_iter().chain(args.runner_args).collect();
     match args.command {
-        Some(SubCommands::Uboot(_)) => {
+        Some(SubCommands::Uboot(uboot_args)) => {
+            cmdline_args.extend(uboot_args.runner_args);
             uboot::run_uboot(
                 app,
                 RunUbootArgs {
                     config: args.config,
-                    show_output: args.show_output,
+                    show_output,
+                    cmdline_args,
                 },
             )
-            .await?;
+            .await
         }
         None => {
             qemu::run_qemu(
@@ -143,12 +209,11 @@ async fn main() -> anyhow::Result<()> {
                 qemu::RunQemuArgs {
                     qemu_config: args.config,
                     dtb_dump: args.dtb_dump,
-                    show_output: args.show_output,
+                    show_output,
+                    cmdline_args,
                 },
             )
-            .await?;
+            .await
         }
     }
-
-    Ok(())
 }
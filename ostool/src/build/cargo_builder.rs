@@ -1,11 +1,26 @@
 use std::{
     collections::HashMap,
+    io::BufReader,
     path::{Path, PathBuf},
+    process::Stdio,
+    time::Instant,
 };
 
-use colored::Colorize;
-
-use crate::{build::config::Cargo, ctx::AppContext, utils::Command};
+use cargo_metadata::{Artifact, Message};
+
+use crate::{
+    build::{
+        config::Cargo,
+        disk::DISK_ENV,
+        logging::{self, LogFilter, LogSettings},
+        target::TargetSpec,
+    },
+    config::remote,
+    ctx::AppContext,
+    event::Event,
+    hooks::{HOOKS_ENV, HookStage},
+    utils::Command,
+};
 
 pub struct CargoBuilder<'a> {
     ctx: &'a mut AppContext,
@@ -15,6 +30,8 @@ pub struct CargoBuilder<'a> {
     extra_envs: HashMap<String, String>,
     skip_objcopy: bool,
     config_path: Option<PathBuf>,
+    artifacts: Vec<Artifact>,
+    target: Option<TargetSpec>,
 }
 
 impl<'a> CargoBuilder<'a> {
@@ -27,6 +44,8 @@ impl<'a> CargoBuilder<'a> {
             extra_envs: HashMap::new(),
             skip_objcopy: false,
             config_path,
+            artifacts: Vec::new(),
+            target: None,
         }
     }
 
@@ -39,6 +58,8 @@ impl<'a> CargoBuilder<'a> {
             extra_envs: HashMap::new(),
             skip_objcopy: true,
             config_path,
+            artifacts: Vec::new(),
+            target: None,
         }
     }
 
@@ -86,8 +107,8 @@ impl<'a> CargoBuilder<'a> {
     }
 
     pub async fn execute(mut self) -> anyhow::Result<()> {
-        // 1. Pre-build commands
-        self.run_pre_build_cmds()?;
+        // 1. Pre-build hooks and initramfs
+        self.ctx.pre_build().await?;
 
         // 2. Build and run cargo
         self.run_cargo().await?;
@@ -95,22 +116,55 @@ impl<'a> CargoBuilder<'a> {
         // 3. Handle output
         self.handle_output().await?;
 
-        // 4. Post-build commands
-        self.run_post_build_cmds()?;
-
-        Ok(())
-    }
-
-    fn run_pre_build_cmds(&mut self) -> anyhow::Result<()> {
-        for cmd in &self.config.pre_build_cmds {
-            self.ctx.shell_run_cmd(cmd)?;
+        // 4. Size report and post-build hooks, `cargo run` has already handed
+        // the ELF to the runner, which does both itself
+        if !self.is_run() {
+            self.ctx.build_configured_disk()?;
+            self.ctx.report_size(&self.config.size_limits)?;
+            self.ctx.run_hooks(HookStage::PostBuild)?;
         }
+
         Ok(())
     }
 
     async fn run_cargo(&mut self) -> anyhow::Result<()> {
         let mut cmd = self.build_cargo_command().await?;
-        cmd.run()?;
+
+        // `cargo run` hands the terminal to the runner, so its stdout can not
+        // be captured for JSON messages. The runner writes its own events.
+        if self.is_run() {
+            cmd.print_cmd();
+            let status = cmd.status()?;
+            if !status.success() {
+                anyhow::bail!("failed with status: {status}");
+            }
+            return Ok(());
+        }
+
+        cmd.stdout(Stdio::piped());
+        cmd.print_cmd();
+        let start = Instant::now();
+        let mut child = cmd.spawn()?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        for message in Message::parse_stream(stdout) {
+            match message? {
+                Message::CompilerArtifact(artifact) if artifact.executable.is_some() => {
+                    self.artifacts.push(artifact);
+                }
+                Message::TextLine(line) => self.ctx.events.emit(Event::BuildOutput { line }),
+                _ => {}
+            }
+        }
+
+        let status = child.wait()?;
+        self.ctx.events.emit(Event::CargoFinished {
+            success: status.success(),
+            duration: start.elapsed(),
+        });
+        if !status.success() {
+            anyhow::bail!("failed with status: {status}");
+        }
         Ok(())
     }
 
@@ -120,11 +174,20 @@ impl<'a> CargoBuilder<'a> {
         cmd.arg(&self.command);
 
         for (k, v) in &self.config.env {
-            println!("{}", format!("{k}={v}").cyan());
+            self.report_env(k, v);
             cmd.env(k, v);
         }
         for (k, v) in &self.extra_envs {
-            println!("{}", format!("{k}={v}").cyan());
+            // The hooks are already printed when they run
+            if k != HOOKS_ENV && k != DISK_ENV {
+                self.report_env(k, v);
+            }
+            cmd.env(k, v);
+        }
+
+        let log = self.log_settings();
+        for (k, v) in &log.env {
+            self.report_env(k, v);
             cmd.env(k, v);
         }
 
@@ -135,20 +198,56 @@ impl<'a> CargoBuilder<'a> {
         }
 
         // Package and target
+        let target = TargetSpec::resolve(&self.config.target, &self.ctx.paths.manifest)?;
+        if self.ctx.arch.is_none() {
+            self.ctx.arch = target.arch;
+        }
+
         cmd.arg("-p");
         cmd.arg(&self.config.package);
         cmd.arg("--target");
-        cmd.arg(&self.config.target);
+        cmd.arg(target.cargo_arg());
         cmd.arg("-Z");
         cmd.arg("unstable-options");
 
+        let build_std = self.build_std_crates(&target);
+        if !build_std.is_empty() {
+            cmd.arg("-Z");
+            cmd.arg(format!("build-std={}", build_std.join(",")));
+
+            let features = self
+                .config
+                .build_std_features
+                .clone()
+                .unwrap_or_else(|| vec!["compiler-builtins-mem".to_string()]);
+            if !features.is_empty() {
+                cmd.arg("-Z");
+                cmd.arg(format!("build-std-features={}", features.join(",")));
+            }
+        }
+        self.target = Some(target);
+
+        if let Some(bin) = &self.config.bin {
+            cmd.arg("--bin");
+            cmd.arg(bin);
+        }
+        if let Some(example) = &self.config.example {
+            cmd.arg("--example");
+            cmd.arg(example);
+        }
+
+        if !self.is_run() {
+            cmd.arg("--message-format=json-render-diagnostics");
+        }
+
         if let Some(build_dir) = &self.ctx.paths.config.build_dir {
             cmd.arg("--target-dir");
             cmd.arg(build_dir.display().to_string());
         }
 
         // Features
-        let features = self.build_features();
+        let mut features = self.config.features.clone();
+        features.extend(log.features);
         if !features.is_empty() {
             cmd.arg("--features");
             cmd.arg(features.join(","));
@@ -159,8 +258,11 @@ impl<'a> CargoBuilder<'a> {
             cmd.arg(arg);
         }
 
-        // Release mode
-        if !self.ctx.debug {
+        // Profile
+        if let Some(profile) = &self.config.profile {
+            cmd.arg("--profile");
+            cmd.arg(profile);
+        } else if !self.ctx.debug {
             cmd.arg("--release");
         }
 
@@ -176,178 +278,205 @@ impl<'a> CargoBuilder<'a> {
         Ok(cmd)
     }
 
-    async fn handle_output(&mut self) -> anyhow::Result<()> {
-        let target_dir = self.ctx.paths.build_dir();
+    fn report_env(&self, key: &str, value: &str) {
+        self.ctx.events.emit(Event::Env {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
 
-        let elf_path = target_dir
-            .join(&self.config.target)
-            .join(if self.ctx.debug { "debug" } else { "release" })
-            .join(&self.config.package);
+    async fn handle_output(&mut self) -> anyhow::Result<()> {
+        let elf_path = match self.find_executable()? {
+            Some(path) => path,
+            None => self.guess_elf_path(),
+        };
 
         self.ctx.set_elf_path(elf_path).await;
 
-        if self.config.to_bin && !self.skip_objcopy {
-            self.ctx.objcopy_output_bin()?;
+        if !self.skip_objcopy {
+            if self.config.to_bin {
+                self.ctx.objcopy_output_bin()?;
+            }
+            self.ctx
+                .objcopy_output_formats(&self.config.output_formats)?;
         }
 
         Ok(())
     }
 
-    fn run_post_build_cmds(&mut self) -> anyhow::Result<()> {
-        for cmd in &self.config.post_build_cmds {
-            self.ctx.shell_run_cmd(cmd)?;
-        }
-        Ok(())
-    }
-
-    fn build_features(&self) -> Vec<String> {
-        let mut features = self.config.features.clone();
-        if let Some(log_level) = self.log_level_feature() {
-            features.push(log_level);
+    /// Picks the kernel executable from the `compiler-artifact` messages.
+    fn find_executable(&self) -> anyhow::Result<Option<PathBuf>> {
+        if self.artifacts.is_empty() {
+            return Ok(None);
         }
-        features
-    }
 
-    fn log_level_feature(&self) -> Option<String> {
-        let level = self.config.log.clone()?;
-
-        let meta = self.ctx.metadata().ok()?;
-        let pkg = meta
-            .packages
+        let package_id = self
+            .ctx
+            .metadata()
+            .ok()
+            .and_then(|meta| {
+                meta.packages
+                    .into_iter()
+                    .find(|p| p.name.as_str() == self.config.package)
+            })
+            .map(|p| p.id);
+
+        let candidates = self
+            .artifacts
             .iter()
-            .find(|p| p.name == self.config.package)?;
-
-        let has_log = pkg.dependencies.iter().any(|dep| dep.name == "log");
-
-        if has_log {
-            Some(format!(
-                "log/{}max_level_{}",
-                if self.ctx.debug { "" } else { "release_" },
-                format!("{:?}", level).to_lowercase()
-            ))
-        } else {
-            None
-        }
-    }
-
-    async fn cargo_extra_config(&self) -> anyhow::Result<Option<PathBuf>> {
-        let s = match self.config.extra_config.as_ref() {
-            Some(s) => s,
-            None => return Ok(None),
-        };
-
-        // Check if it's a URL (starts with http:// or https://)
-        if s.starts_with("http://") || s.starts_with("https://") {
-            // Convert GitHub URL to raw content URL if needed
-            let download_url = Self::convert_to_raw_url(s);
-
-            // Download to temp directory
-            match self.download_config_to_temp(&download_url).await {
-                Ok(path) => Ok(Some(path)),
-                Err(e) => {
-                    eprintln!("Failed to download config from {}: {}", s, e);
-                    Err(e)
-                }
-            }
-        } else {
-            // It's a local path
-            let extra = Path::new(s);
-
-            if extra.is_relative() {
-                if let Some(ref config_path) = self.config_path {
-                    let combined = config_path
-                        .parent()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid config path"))?
-                        .join(extra);
-                    Ok(Some(combined))
+            .filter(|a| match &package_id {
+                Some(id) => &a.package_id == id,
+                None => true,
+            })
+            .filter(|a| {
+                if let Some(example) = &self.config.example {
+                    a.target.is_example() && &a.target.name == example
+                } else if let Some(bin) = &self.config.bin {
+                    a.target.is_bin() && &a.target.name == bin
                 } else {
-                    Ok(Some(extra.to_path_buf()))
+                    a.target.is_bin()
                 }
-            } else {
-                Ok(Some(extra.to_path_buf()))
+            })
+            .collect::<Vec<_>>();
+
+        let artifact = match candidates.as_slice() {
+            [] => {
+                warn!(
+                    "cargo reported no executable for package `{}`",
+                    self.config.package
+                );
+                return Ok(None);
             }
-        }
+            [one] => *one,
+            many => match many.iter().find(|a| a.target.name == self.config.package) {
+                Some(a) => *a,
+                None => {
+                    let names = many
+                        .iter()
+                        .map(|a| a.target.name.as_str())
+                        .collect::<Vec<_>>();
+                    anyhow::bail!(
+                        "package `{}` has several binaries [{}], set `bin` in the build config",
+                        self.config.package,
+                        names.join(", ")
+                    );
+                }
+            },
+        };
+
+        Ok(artifact
+            .executable
+            .as_ref()
+            .map(|p| p.clone().into_std_path_buf()))
     }
 
-    /// Convert GitHub URL to raw content URL
-    /// Supports:
-    /// - https://github.com/user/repo/blob/branch/path/file -> https://raw.githubusercontent.com/user/repo/branch/path/file
-    /// - https://raw.githubusercontent.com/... (already raw, no change)
-    /// - Other URLs: no change
-    fn convert_to_raw_url(url: &str) -> String {
-        // Already a raw URL
-        if url.contains("raw.githubusercontent.com") || url.contains("raw.github.com") {
-            return url.to_string();
+    fn build_std_crates(&self, target: &TargetSpec) -> Vec<String> {
+        // Respect `-Z build-std` passed by hand.
+        if self.config.args.iter().any(|a| a.contains("build-std")) {
+            return Vec::new();
         }
 
-        // Convert github.com/user/repo/blob/... to raw.githubusercontent.com/user/repo/...
-        if url.contains("github.com") && url.contains("/blob/") {
-            let converted = url
-                .replace("github.com", "raw.githubusercontent.com")
-                .replace("/blob/", "/");
-            println!("Converting GitHub URL to raw: {} -> {}", url, converted);
-            return converted;
+        match &self.config.build_std {
+            Some(crates) => crates.clone(),
+            None if !target.has_prebuilt_std(&self.ctx.paths.manifest) => {
+                info!(
+                    "No prebuilt std for target `{}`, building core and alloc from source",
+                    target.name
+                );
+                vec!["core".to_string(), "alloc".to_string()]
+            }
+            None => Vec::new(),
         }
-
-        // Not a GitHub URL or already in correct format
-        url.to_string()
     }
 
-    async fn download_config_to_temp(&self, url: &str) -> anyhow::Result<PathBuf> {
-        use std::time::SystemTime;
-
-        println!("Downloading cargo config from: {}", url);
+    /// Output path when cargo does not report artifacts, e.g. for `cargo run`.
+    fn guess_elf_path(&self) -> PathBuf {
+        let profile_dir = match self.config.profile.as_deref() {
+            Some("dev") | Some("test") => "debug",
+            Some("release") | Some("bench") => "release",
+            Some(profile) => profile,
+            None if self.ctx.debug => "debug",
+            None => "release",
+        };
 
-        // Get system temp directory
-        let temp_dir = std::env::temp_dir();
+        let target_name = match &self.target {
+            Some(target) => target.name.clone(),
+            None => self.config.target.clone(),
+        };
 
-        // Generate filename with timestamp
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let out_dir = self
+            .ctx
+            .paths
+            .build_dir()
+            .join(target_name)
+            .join(profile_dir);
 
-        // Extract filename from URL or use default
-        let url_path = url.split('/').next_back().unwrap_or("config.toml");
-        let filename = format!("cargo_config_{}_{}", timestamp, url_path);
-        let target_path = temp_dir.join(filename);
+        if let Some(example) = &self.config.example {
+            out_dir.join("examples").join(example)
+        } else {
+            out_dir.join(self.config.bin.as_ref().unwrap_or(&self.config.package))
+        }
+    }
 
-        // Create reqwest client
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .build()
-            .map_err(|e| anyhow::anyhow!("Failed to create HTTP client: {}", e))?;
+    /// Features and env setting the log level of the backends in the
+    /// dependency graph.
+    fn log_settings(&self) -> LogSettings {
+        let Some(level) = self.config.log else {
+            return LogSettings::default();
+        };
+        let filter = LogFilter::new(level, &self.config.log_filters);
+
+        let meta = match cargo_metadata::MetadataCommand::new()
+            .current_dir(&self.ctx.paths.manifest)
+            .exec()
+        {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!("can not read cargo metadata, log level not set: {e}");
+                return LogSettings::default();
+            }
+        };
+        let release = !self.ctx.debug;
+        logging::detect(
+            &meta,
+            &self.config.package,
+            &logging::backends(),
+            &filter,
+            release,
+        )
+        .unwrap_or_else(|e| {
+            warn!("log level not set: {e}");
+            LogSettings::default()
+        })
+    }
 
-        // Build request with User-Agent for GitHub
-        let mut request = client.get(url);
+    async fn cargo_extra_config(&self) -> anyhow::Result<Option<PathBuf>> {
+        let s = match self.config.extra_config.as_ref() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let pin = self.config.extra_config_sha256.as_deref();
 
-        if url.contains("github.com") || url.contains("githubusercontent.com") {
-            // GitHub requires User-Agent
-            request = request.header("User-Agent", "ostool-cargo-downloader");
+        if remote::is_url(s) {
+            return remote::fetch_cached(s, self.ctx.offline, pin)
+                .await
+                .map(Some);
         }
 
-        // Download the file
-        let response = request
-            .send()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to download from {}: {}", url, e))?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("HTTP error {}: {}", response.status(), url));
+        // It's a local path
+        let extra = Path::new(s);
+        let path = match &self.config_path {
+            Some(config_path) if extra.is_relative() => config_path
+                .parent()
+                .ok_or_else(|| anyhow::anyhow!("Invalid config path"))?
+                .join(extra),
+            _ => extra.to_path_buf(),
+        };
+        if let Some(pin) = pin {
+            let data = std::fs::read(&path)
+                .map_err(|e| anyhow!("can not read {}: {e}", path.display()))?;
+            remote::verify_sha256(&path.display().to_string(), &data, pin)?;
         }
-
-        let content = response
-            .bytes()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to read response body: {}", e))?;
-
-        // Write to temp file
-        tokio::fs::write(&target_path, content)
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to write to temp file: {}", e))?;
-
-        println!("Config downloaded to: {}", target_path.display());
-
-        Ok(target_path)
+        Ok(Some(path))
     }
 }
@@ -3,15 +3,73 @@ use std::collections::HashMap;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::hooks::{Hook, Hooks};
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub struct BuildConfig {
     pub system: BuildSystem,
+    /// named profiles, selected with `--profile <name>`
+    /// each profile overrides some fields of `system`
+    #[serde(default)]
+    pub profiles: HashMap<String, BuildProfile>,
+    /// commands run around the build and run stages
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// user-space programs built and packed into the initramfs
+    #[serde(default)]
+    pub userspace: Vec<UserspacePackage>,
+    /// extra content of the initramfs
+    pub initramfs: Option<Initramfs>,
+    /// GPT disk image composed after the build
+    pub disk: Option<DiskImage>,
+}
+
+impl BuildConfig {
+    /// Returns a copy of this config with the profile `name` merged into `system`.
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<BuildConfig> {
+        let Some(profile) = self.profiles.get(name) else {
+            let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            bail!(
+                "build profile `{name}` not found, available profiles: [{}]",
+                names.join(", ")
+            );
+        };
+
+        let mut config = self.clone();
+        profile
+            .apply(&mut config.system)
+            .map_err(|e| anyhow!("build profile `{name}`: {e}"))?;
+        Ok(config)
+    }
+
+    /// Whether an initramfs is built for the kernel
+    pub fn has_initramfs(&self) -> bool {
+        !self.userspace.is_empty() || self.initramfs.is_some()
+    }
+
+    /// `hooks` with the deprecated `pre_build_cmds`/`post_build_cmds` in front.
+    pub fn all_hooks(&self) -> Hooks {
+        let mut hooks = self.hooks.clone();
+        if let BuildSystem::Cargo(cargo) = &self.system {
+            hooks
+                .pre_build
+                .splice(0..0, cargo.pre_build_cmds.iter().map(Hook::shell));
+            hooks
+                .post_build
+                .splice(0..0, cargo.post_build_cmds.iter().map(Hook::shell));
+        }
+        hooks
+    }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub enum BuildSystem {
     Custom(Custom),
     Cargo(Cargo),
+    Make(Make),
+    CMake(CMake),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -22,32 +80,456 @@ pub struct Custom {
     pub elf_path: String,
     /// whether to output as binary
     pub to_bin: bool,
+    /// extra images written next to the ELF
+    #[serde(default)]
+    pub output_formats: Vec<OutputFormat>,
+    /// maximum sizes checked after build, e.g. `text = "512K"`
+    /// keys are section names (leading dot optional) or `total`
+    #[serde(default)]
+    pub size_limits: HashMap<String, String>,
+}
+
+/// A program of the cargo workspace packed into the initramfs
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct UserspacePackage {
+    /// package name
+    pub package: String,
+    /// target triple or path to a custom target JSON spec
+    pub target: String,
+    /// features to enable
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// binary to build (`--bin`)
+    /// defaults to the package's only binary
+    pub bin: Option<String>,
+    /// custom cargo profile (`--profile`)
+    pub profile: Option<String>,
+    /// other cargo args
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// path in the initramfs, defaults to `/bin/<binary name>`
+    pub path: Option<String>,
+}
+
+/// Files packed into the initramfs besides the `userspace` programs
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Initramfs {
+    /// directory copied into the root of the initramfs
+    /// relative to the manifest directory
+    pub overlay: Option<String>,
+    /// single files, initramfs path -> host path relative to the manifest directory
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+}
+
+/// GPT disk image with a FAT32 ESP and raw partitions, for UEFI boots in
+/// QEMU or as an SD-card image
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct DiskImage {
+    /// image size, e.g. `"128M"`, defaults to the end of the last partition
+    pub size: Option<String>,
+    /// FAT32 EFI system partition, or the boot partition of an SD card
+    pub esp: Option<Esp>,
+    /// partitions filled from files
+    #[serde(default)]
+    pub partitions: Vec<RawPartition>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Esp {
+    /// partition size, defaults to `"64M"`
+    pub size: Option<String>,
+    /// byte offset in the image, defaults to `"1M"`
+    pub offset: Option<String>,
+    /// copy the kernel to `EFI/BOOT/BOOT<arch>.EFI`
+    #[serde(default)]
+    pub efi: bool,
+    /// path of the kernel in the partition, overrides the `efi` default
+    pub kernel: Option<String>,
+    /// content of `startup.nsh` in the root of the partition
+    pub startup_nsh: Option<String>,
+    /// FAT volume label, defaults to `ESP`
+    pub label: Option<String>,
+    /// partition path -> host path relative to the manifest directory
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct RawPartition {
+    /// GPT partition name
+    pub name: String,
+    /// content, relative to the manifest directory
+    pub file: Option<String>,
+    /// byte offset in the image, defaults to the next 1M boundary
+    pub offset: Option<String>,
+    /// defaults to the size of `file`
+    pub size: Option<String>,
+    /// `linux`, `data`, `esp` or a partition type GUID, defaults to `linux`
+    #[serde(rename = "type")]
+    pub part_type: Option<String>,
+    /// only write the content, without a GPT entry, e.g. a boot loader at
+    /// the offset the SoC boot ROM reads
+    #[serde(default)]
+    pub no_entry: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Make {
+    /// directory containing the Makefile, passed as `make -C`
+    /// relative to the manifest directory, defaults to it
+    pub build_dir: Option<String>,
+    /// make targets, empty for the default target
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// parallel jobs (`-j`), defaults to the number of CPUs
+    pub jobs: Option<usize>,
+    /// variables passed on the command line as `NAME=value`
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// path or glob of the built ELF, relative to `build_dir`
+    /// the newest match is used
+    pub elf: String,
+    /// whether to output as binary
+    #[serde(default)]
+    pub to_bin: bool,
+    /// extra images written next to the ELF
+    #[serde(default)]
+    pub output_formats: Vec<OutputFormat>,
+    /// maximum sizes checked after build, e.g. `text = "512K"`
+    /// keys are section names (leading dot optional) or `total`
+    #[serde(default)]
+    pub size_limits: HashMap<String, String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct CMake {
+    /// source directory containing `CMakeLists.txt`
+    /// relative to the manifest directory, defaults to it
+    pub source_dir: Option<String>,
+    /// binary directory (`cmake -B`), defaults to `build` in the source directory
+    pub build_dir: Option<String>,
+    /// targets passed to `cmake --build --target`, empty for `all`
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// parallel jobs (`-j`), defaults to the number of CPUs
+    pub jobs: Option<usize>,
+    /// cache entries passed as `-DNAME=value` when configuring
+    #[serde(default)]
+    pub defines: HashMap<String, String>,
+    /// environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// path or glob of the built ELF, relative to `build_dir`
+    /// the newest match is used
+    pub elf: String,
+    /// whether to output as binary
+    #[serde(default)]
+    pub to_bin: bool,
+    /// extra images written next to the ELF
+    #[serde(default)]
+    pub output_formats: Vec<OutputFormat>,
+    /// maximum sizes checked after build, e.g. `text = "512K"`
+    /// keys are section names (leading dot optional) or `total`
+    #[serde(default)]
+    pub size_limits: HashMap<String, String>,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub struct Cargo {
     /// environment variables
     pub env: HashMap<String, String>,
-    /// target triple
+    /// target triple or path to a custom target JSON spec
+    /// spec paths are relative to the manifest directory
     pub target: String,
     /// package name
     pub package: String,
     /// features to enable
     pub features: Vec<String>,
-    /// log level feature
+    /// crates for `-Z build-std`
+    /// unset: `core,alloc` when the target has no prebuilt std, empty: disabled
+    pub build_std: Option<Vec<String>>,
+    /// features for `-Z build-std-features`
+    /// defaults to `compiler-builtins-mem` when `build-std` is used
+    pub build_std_features: Option<Vec<String>>,
+    /// binary to build (`--bin`)
+    /// defaults to the package's only binary
+    pub bin: Option<String>,
+    /// example to build instead of a binary (`--example`)
+    pub example: Option<String>,
+    /// custom cargo profile (`--profile`)
+    /// replaces the default `release`/`dev` selection
+    pub profile: Option<String>,
+    /// log level, set through the `log`, `tracing` or `defmt` crates found
+    /// in the dependency graph
     pub log: Option<LogLevel>,
+    /// per-module levels, e.g. `"kernel::mm" = "Trace"`
+    #[serde(default)]
+    pub log_filters: HashMap<String, LogLevel>,
     /// extra cargo .config.toml file
     /// can be url or local path
     pub extra_config: Option<String>,
+    /// expected SHA-256 of `extra_config`, checked before use
+    pub extra_config_sha256: Option<String>,
     /// other cargo args
     pub args: Vec<String>,
     /// shell commands before build
+    /// deprecated, use `hooks.pre_build`
+    #[serde(default)]
     pub pre_build_cmds: Vec<String>,
     /// shell commands after build
-    /// `KERNEL_ELF` env var is set to the built ELF path
+    /// deprecated, use `hooks.post_build`
+    #[serde(default)]
     pub post_build_cmds: Vec<String>,
     /// whether to output as binary
     pub to_bin: bool,
+    /// extra images written next to the ELF
+    #[serde(default)]
+    pub output_formats: Vec<OutputFormat>,
+    /// maximum sizes checked after build, e.g. `text = "512K"`
+    /// keys are section names (leading dot optional) or `total`
+    #[serde(default)]
+    pub size_limits: HashMap<String, String>,
+}
+
+/// Environment variable carrying the `output_formats` from `ostool` to `cargo-osrun`
+pub const OUTPUT_FORMATS_ENV: &str = "OSTOOL_OUTPUT_FORMATS";
+
+/// Image formats that can be generated from the ELF
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// raw binary, same as `to_bin = true`
+    #[serde(rename = "bin")]
+    Bin,
+    /// Intel HEX
+    #[serde(rename = "ihex")]
+    Ihex,
+    /// Motorola S-record
+    #[serde(rename = "srec")]
+    Srec,
+    /// gzip compressed raw binary
+    #[serde(rename = "bin.gz")]
+    BinGz,
+}
+
+impl OutputFormat {
+    /// Formats handed over by `ostool` in [`OUTPUT_FORMATS_ENV`]
+    pub fn from_env() -> anyhow::Result<Vec<OutputFormat>> {
+        match std::env::var(OUTPUT_FORMATS_ENV) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| anyhow!("invalid `{OUTPUT_FORMATS_ENV}`: {e}")),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// File extension of the generated image
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Bin => "bin",
+            OutputFormat::Ihex => "hex",
+            OutputFormat::Srec => "srec",
+            OutputFormat::BinGz => "bin.gz",
+        }
+    }
+}
+
+/// Overrides applied on top of the base build system.
+/// Unset fields keep the value from `system`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct BuildProfile {
+    /// environment variables
+    /// merged into the base `env`, profile values win
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// target triple or spec path (Cargo only)
+    pub target: Option<String>,
+    /// package name (Cargo only)
+    pub package: Option<String>,
+    /// features to enable, replaces the base list (Cargo only)
+    pub features: Option<Vec<String>>,
+    /// binary to build (Cargo only)
+    pub bin: Option<String>,
+    /// example to build (Cargo only)
+    pub example: Option<String>,
+    /// custom cargo profile (Cargo only)
+    pub profile: Option<String>,
+    /// log level (Cargo only)
+    pub log: Option<LogLevel>,
+    /// per-module levels, merged into the base filters (Cargo only)
+    pub log_filters: Option<HashMap<String, LogLevel>>,
+    /// extra cargo .config.toml file (Cargo only)
+    pub extra_config: Option<String>,
+    /// expected SHA-256 of `extra_config` (Cargo only)
+    pub extra_config_sha256: Option<String>,
+    /// other cargo args, replaces the base list (Cargo only)
+    pub args: Option<Vec<String>>,
+    /// shell command to build the kernel (Custom only)
+    pub build_cmd: Option<String>,
+    /// path to the built ELF file (Custom only)
+    pub elf_path: Option<String>,
+    /// whether to output as binary
+    pub to_bin: Option<bool>,
+    /// extra images written next to the ELF, replaces the base list
+    pub output_formats: Option<Vec<OutputFormat>>,
+    /// size limits, merged into the base limits
+    pub size_limits: Option<HashMap<String, String>>,
+    /// make or cmake targets, replaces the base list (Make and CMake only)
+    pub targets: Option<Vec<String>>,
+}
+
+impl BuildProfile {
+    fn apply(&self, system: &mut BuildSystem) -> anyhow::Result<()> {
+        match system {
+            BuildSystem::Cargo(cargo) => {
+                if self.build_cmd.is_some() || self.elf_path.is_some() {
+                    bail!("`build_cmd` and `elf_path` can only override a Custom build system");
+                }
+                if self.targets.is_some() {
+                    bail!("`targets` can only override a Make or CMake build system");
+                }
+                cargo.env.extend(self.env.clone());
+                if let Some(target) = &self.target {
+                    cargo.target = target.clone();
+                }
+                if let Some(package) = &self.package {
+                    cargo.package = package.clone();
+                }
+                if let Some(features) = &self.features {
+                    cargo.features = features.clone();
+                }
+                if let Some(bin) = &self.bin {
+                    cargo.bin = Some(bin.clone());
+                }
+                if let Some(example) = &self.example {
+                    cargo.example = Some(example.clone());
+                }
+                if let Some(profile) = &self.profile {
+                    cargo.profile = Some(profile.clone());
+                }
+                if let Some(log) = &self.log {
+                    cargo.log = Some(*log);
+                }
+                if let Some(filters) = &self.log_filters {
+                    cargo.log_filters.extend(filters.clone());
+                }
+                if let Some(extra_config) = &self.extra_config {
+                    cargo.extra_config = Some(extra_config.clone());
+                }
+                if let Some(sha256) = &self.extra_config_sha256 {
+                    cargo.extra_config_sha256 = Some(sha256.clone());
+                }
+                if let Some(args) = &self.args {
+                    cargo.args = args.clone();
+                }
+                if let Some(to_bin) = self.to_bin {
+                    cargo.to_bin = to_bin;
+                }
+                if let Some(formats) = &self.output_formats {
+                    cargo.output_formats = formats.clone();
+                }
+                if let Some(limits) = &self.size_limits {
+                    cargo.size_limits.extend(limits.clone());
+                }
+            }
+            BuildSystem::Custom(custom) => {
+                if self.target.is_some()
+                    || self.package.is_some()
+                    || self.features.is_some()
+                    || self.bin.is_some()
+                    || self.example.is_some()
+                    || self.profile.is_some()
+                    || self.log.is_some()
+                    || self.log_filters.is_some()
+                    || self.extra_config.is_some()
+                    || self.extra_config_sha256.is_some()
+                    || self.args.is_some()
+                    || self.targets.is_some()
+                    || !self.env.is_empty()
+                {
+                    bail!(
+                        "only `build_cmd`, `elf_path`, `to_bin`, `output_formats` and `size_limits` can override a Custom build system"
+                    );
+                }
+                if let Some(build_cmd) = &self.build_cmd {
+                    custom.build_cmd = build_cmd.clone();
+                }
+                if let Some(elf_path) = &self.elf_path {
+                    custom.elf_path = elf_path.clone();
+                }
+                if let Some(to_bin) = self.to_bin {
+                    custom.to_bin = to_bin;
+                }
+                if let Some(formats) = &self.output_formats {
+                    custom.output_formats = formats.clone();
+                }
+                if let Some(limits) = &self.size_limits {
+                    custom.size_limits.extend(limits.clone());
+                }
+            }
+            BuildSystem::Make(make) => {
+                self.check_native_overrides()?;
+                make.env.extend(self.env.clone());
+                if let Some(targets) = &self.targets {
+                    make.targets = targets.clone();
+                }
+                if let Some(to_bin) = self.to_bin {
+                    make.to_bin = to_bin;
+                }
+                if let Some(formats) = &self.output_formats {
+                    make.output_formats = formats.clone();
+                }
+                if let Some(limits) = &self.size_limits {
+                    make.size_limits.extend(limits.clone());
+                }
+            }
+            BuildSystem::CMake(cmake) => {
+                self.check_native_overrides()?;
+                cmake.env.extend(self.env.clone());
+                if let Some(targets) = &self.targets {
+                    cmake.targets = targets.clone();
+                }
+                if let Some(to_bin) = self.to_bin {
+                    cmake.to_bin = to_bin;
+                }
+                if let Some(formats) = &self.output_formats {
+                    cmake.output_formats = formats.clone();
+                }
+                if let Some(limits) = &self.size_limits {
+                    cmake.size_limits.extend(limits.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make and CMake take none of the Cargo or Custom only fields.
+    fn check_native_overrides(&self) -> anyhow::Result<()> {
+        if self.target.is_some()
+            || self.package.is_some()
+            || self.features.is_some()
+            || self.bin.is_some()
+            || self.example.is_some()
+            || self.profile.is_some()
+            || self.log.is_some()
+            || self.log_filters.is_some()
+            || self.extra_config.is_some()
+            || self.extra_config_sha256.is_some()
+            || self.args.is_some()
+            || self.build_cmd.is_some()
+            || self.elf_path.is_some()
+        {
+            bail!(
+                "only `env`, `targets`, `to_bin`, `output_formats` and `size_limits` can override a Make or CMake build system"
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -56,7 +538,10 @@ pub struct Depend {
     pub d_features: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+/// Ordered from the most verbose
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
 pub enum LogLevel {
     Trace,
     Debug,
@@ -64,3 +549,96 @@ pub enum LogLevel {
     Warn,
     Error,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[system.Cargo]
+target = "aarch64-unknown-none"
+package = "kernel"
+features = ["base"]
+log = "Info"
+args = []
+pre_build_cmds = []
+post_build_cmds = []
+to_bin = true
+
+[system.Cargo.env]
+BOARD = "qemu"
+RUSTFLAGS = "-Cforce-frame-pointers"
+
+[profiles.rk3588.env]
+BOARD = "rk3588"
+
+[profiles.rk3588]
+features = ["rk3588"]
+
+[profiles.riscv]
+target = "riscv64gc-unknown-none-elf"
+build_cmd = "make"
+"#;
+
+    #[test]
+    fn test_profile_overrides_fields() {
+        let config: BuildConfig = toml::from_str(CONFIG).unwrap();
+        let resolved = config.with_profile("rk3588").unwrap();
+        let BuildSystem::Cargo(cargo) = resolved.system else {
+            panic!("expected Cargo system");
+        };
+
+        assert_eq!(cargo.target, "aarch64-unknown-none");
+        assert_eq!(cargo.features, vec!["rk3588".to_string()]);
+        assert_eq!(cargo.env["BOARD"], "rk3588");
+        assert_eq!(cargo.env["RUSTFLAGS"], "-Cforce-frame-pointers");
+        assert_eq!(cargo.log, Some(LogLevel::Info));
+    }
+
+    #[test]
+    fn test_profile_errors() {
+        let config: BuildConfig = toml::from_str(CONFIG).unwrap();
+
+        let err = config.with_profile("missing").unwrap_err().to_string();
+        assert!(err.contains("rk3588"), "{err}");
+
+        let err = config.with_profile("riscv").unwrap_err().to_string();
+        assert!(err.contains("build_cmd"), "{err}");
+        let config: BuildConfig = toml::from_str(
+            r#"
+[system.Custom]
+build_cmd = "make"
+elf_path = "kernel.elf"
+to_bin = false
+
+[profiles.small]
+output_formats = ["ihex"]
+size_limits = { total = "64K" }
+
+[profiles.cargo]
+features = ["x"]
+"#,
+        )
+        .unwrap();
+        let BuildSystem::Custom(custom) = config.with_profile("small").unwrap().system else {
+            panic!("expected Custom system");
+        };
+        assert_eq!(custom.output_formats, [OutputFormat::Ihex]);
+        let err = config.with_profile("cargo").unwrap_err().to_string();
+        assert!(err.contains("`output_formats` and `size_limits`"), "{err}");
+    }
+
+    #[test]
+    fn test_profiles_are_optional() {
+        let config: BuildConfig = toml::from_str(
+            r#"
+[system.Custom]
+build_cmd = "make"
+elf_path = "kernel.elf"
+to_bin = false
+"#,
+        )
+        .unwrap();
+        assert!(config.profiles.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+//! Minimal writer for CPIO `newc` archives, the format Linux and most
+//! hobby kernels accept as initramfs.
+
+use std::collections::BTreeMap;
+
+const MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Dir,
+    File { mode: u32, data: Vec<u8> },
+    Symlink { target: String },
+}
+
+/// Archive built in memory. Entries are written sorted by path, parent
+/// directories are added automatically.
+#[derive(Debug, Clone, Default)]
+pub struct CpioBuilder {
+    entries: BTreeMap<String, Entry>,
+    mtime: u32,
+}
+
+impl CpioBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Modification time of all entries, 0 by default for reproducible archives.
+    pub fn with_mtime(mut self, mtime: u32) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Adds a regular file. `mode` holds the permission bits, e.g. `0o755`.
+    pub fn file(&mut self, path: &str, mode: u32, data: Vec<u8>) -> anyhow::Result<()> {
+        let path = normalize(path)?;
+        self.add_parents(&path);
+        if let Some(Entry::Dir) = self.entries.get(&path) {
+            bail!("`/{path}` is already a directory in the initramfs");
+        }
+        self.entries.insert(
+            path,
+            Entry::File {
+                mode: mode & 0o7777,
+                data,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn dir(&mut self, path: &str) -> anyhow::Result<()> {
+        let path = normalize(path)?;
+        self.add_parents(&path);
+        if let Some(Entry::File { .. } | Entry::Symlink { .. }) = self.entries.get(&path) {
+            bail!("`/{path}` is already a file in the initramfs");
+        }
+        self.entries.insert(path, Entry::Dir);
+        Ok(())
+    }
+
+    /// Adds a symbolic link, `target` is stored as is.
+    pub fn symlink(&mut self, path: &str, target: &str) -> anyhow::Result<()> {
+        let path = normalize(path)?;
+        self.add_parents(&path);
+        if let Some(Entry::Dir) = self.entries.get(&path) {
+            bail!("`/{path}` is already a directory in the initramfs");
+        }
+        self.entries.insert(
+            path,
+            Entry::Symlink {
+                target: target.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (ino, (path, entry)) in self.entries.iter().enumerate() {
+            let (mode, nlink, data): (u32, u32, &[u8]) = match entry {
+                Entry::Dir => (S_IFDIR | 0o755, 2, &[]),
+                Entry::File { mode, data } => (S_IFREG | mode, 1, data),
+                Entry::Symlink { target } => (S_IFLNK | 0o777, 1, target.as_bytes()),
+            };
+            write_entry(
+                &mut out,
+                ino as u32 + 1,
+                mode,
+                nlink,
+                self.mtime,
+                path,
+                data,
+            );
+        }
+        write_entry(&mut out, 0, 0, 1, 0, TRAILER, &[]);
+        out
+    }
+
+    fn add_parents(&mut self, path: &str) {
+        let mut parent = String::new();
+        let mut parts = path.split('/').peekable();
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                break;
+            }
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(part);
+            self.entries.entry(parent.clone()).or_insert(Entry::Dir);
+        }
+    }
+}
+
+/// `/bin/init` -> `bin/init`
+fn normalize(path: &str) -> anyhow::Result<String> {
+    let parts = path
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<_>>();
+    if parts.is_empty() || parts.contains(&"..") {
+        bail!("invalid initramfs path `{path}`");
+    }
+    Ok(parts.join("/"))
+}
+
+fn write_entry(
+    out: &mut Vec<u8>,
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    name: &str,
+    data: &[u8],
+) {
+    let fields = [
+        ino,
+        mode,
+        0, // uid
+        0, // gid
+        nlink,
+        mtime,
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    out.extend_from_slice(MAGIC.as_bytes());
+    for field in fields {
+        out.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    pad4(out);
+    out.extend_from_slice(data);
+    pad4(out);
+}
+
+fn pad4(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (name, mode, data) of every entry, read back from the archive
+    fn parse(archive: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        loop {
+            assert_eq!(&archive[pos..pos + 6], MAGIC.as_bytes());
+            let field = |i: usize| {
+                let s = std::str::from_utf8(&archive[pos + 6 + i * 8..pos + 14 + i * 8]).unwrap();
+                u32::from_str_radix(s, 16).unwrap() as usize
+            };
+            let (mode, size, namesize) = (field(1), field(6), field(11));
+            let name_start = pos + 110;
+            let name =
+                String::from_utf8(archive[name_start..name_start + namesize - 1].to_vec()).unwrap();
+            let data_start = (name_start + namesize).next_multiple_of(4);
+            let data = archive[data_start..data_start + size].to_vec();
+            pos = (data_start + size).next_multiple_of(4);
+            if name == TRAILER {
+                assert_eq!(pos, archive.len());
+                return entries;
+            }
+            entries.push((name, mode as u32, data));
+        }
+    }
+
+    #[test]
+    fn test_newc_archive() {
+        let mut cpio = CpioBuilder::new();
+        cpio.file("/sbin/init", 0o755, b"\x7fELF".to_vec()).unwrap();
+        cpio.file("etc/hostname", 0o644, b"ostool\n".to_vec())
+            .unwrap();
+        cpio.dir("/tmp").unwrap();
+        cpio.symlink("/bin/sh", "busybox").unwrap();
+
+        let entries = parse(&cpio.build());
+        let names = entries.iter().map(|e| e.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "bin",
+                "bin/sh",
+                "etc",
+                "etc/hostname",
+                "sbin",
+                "sbin/init",
+                "tmp"
+            ]
+        );
+        assert_eq!(entries[0].1, 0o040755);
+        assert_eq!(entries[5].1, 0o100755);
+        assert_eq!(entries[3].2, b"ostool\n");
+        assert_eq!(entries[1].1, 0o120777);
+        assert_eq!(entries[1].2, b"busybox");
+
+        assert!(cpio.file("/tmp", 0o644, vec![]).is_err());
+        assert!(cpio.file("../escape", 0o644, vec![]).is_err());
+    }
+}
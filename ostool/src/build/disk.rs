@@ -0,0 +1,289 @@
+//! Composes the `disk` image of the build config: a GPT disk with a FAT32
+//! ESP and raw partitions, attached by QEMU or written to an SD card.
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use object::Architecture;
+
+use crate::{
+    build::{
+        config::{DiskImage, Esp},
+        fat::FatBuilder,
+        gpt::{self, GptPartition, SECTOR},
+        size::parse_size,
+    },
+    ctx::AppContext,
+    event::ArtifactKind,
+};
+
+/// Environment variable carrying the disk config from `ostool` to `cargo-osrun`
+pub const DISK_ENV: &str = "OSTOOL_DISK";
+
+const ALIGN: u64 = 1 << 20;
+const DEFAULT_ESP_SIZE: u64 = 64 << 20;
+
+/// A region of the image and its content
+struct Region {
+    name: String,
+    offset: u64,
+    size: u64,
+    data: Vec<u8>,
+    /// `None` for data written without a GPT entry
+    type_guid: Option<[u8; 16]>,
+}
+
+impl DiskImage {
+    pub fn from_env() -> anyhow::Result<Option<DiskImage>> {
+        match std::env::var(DISK_ENV) {
+            Ok(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| anyhow!("invalid `{DISK_ENV}`: {e}")),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl AppContext {
+    /// Where the disk image of this build is written
+    pub fn disk_image_path(&self) -> PathBuf {
+        self.paths.build_dir().join("ostool").join("disk.img")
+    }
+
+    /// Composes the `disk` of the build config, if any.
+    pub fn build_configured_disk(&mut self) -> anyhow::Result<()> {
+        match self.build_config.as_ref().and_then(|c| c.disk.clone()) {
+            Some(disk) => self.build_disk_image(&disk),
+            None => Ok(()),
+        }
+    }
+
+    /// Composes `disk` from the current artifacts.
+    pub fn build_disk_image(&mut self, disk: &DiskImage) -> anyhow::Result<()> {
+        let mut regions = Vec::new();
+        let mut end = 0;
+
+        if let Some(esp) = &disk.esp {
+            let offset = size_or(esp.offset.as_deref(), ALIGN)?;
+            let size = size_or(esp.size.as_deref(), DEFAULT_ESP_SIZE)?;
+            let data = self
+                .esp_content(esp)?
+                .build(size, (offset / SECTOR) as u32)
+                .context("can not format the ESP")?;
+            end = offset + size;
+            regions.push(Region {
+                name: "ESP".into(),
+                offset,
+                size,
+                data,
+                type_guid: Some(gpt::parse_guid(gpt::ESP_TYPE)?),
+            });
+        }
+
+        for part in &disk.partitions {
+            let data = match &part.file {
+                Some(file) => {
+                    let path = self.paths.manifest.join(file);
+                    std::fs::read(&path).with_context(|| {
+                        format!("can not read partition `{}`: {}", part.name, path.display())
+                    })?
+                }
+                None => Vec::new(),
+            };
+            let offset = size_or(part.offset.as_deref(), end.next_multiple_of(ALIGN))?;
+            let size = size_or(part.size.as_deref(), data.len() as u64)?.next_multiple_of(SECTOR);
+            if size == 0 {
+                bail!("partition `{}` needs a `file` or a `size`", part.name);
+            }
+            if size < data.len() as u64 {
+                bail!(
+                    "partition `{}` is {size} bytes, its content {}",
+                    part.name,
+                    data.len()
+                );
+            }
+            let type_guid = match part.part_type.as_deref().unwrap_or("linux") {
+                "linux" => gpt::LINUX_TYPE,
+                "data" => gpt::DATA_TYPE,
+                "esp" => gpt::ESP_TYPE,
+                guid => guid,
+            };
+            end = end.max(offset + size);
+            regions.push(Region {
+                name: part.name.clone(),
+                offset,
+                size,
+                data,
+                type_guid: match part.no_entry {
+                    true => None,
+                    false => Some(gpt::parse_guid(type_guid)?),
+                },
+            });
+        }
+
+        let sectors = match &disk.size {
+            Some(size) => disk_sectors(size)?,
+            // Room for the backup GPT after the last partition
+            None => (end + 33 * SECTOR).next_multiple_of(ALIGN) / SECTOR,
+        };
+        check_layout(&mut regions, sectors)?;
+
+        let seed = self
+            .paths
+            .manifest
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let entries = regions
+            .iter()
+            .filter_map(|r| {
+                r.type_guid.map(|type_guid| GptPartition {
+                    name: r.name.clone(),
+                    type_guid,
+                    guid: gpt::stable_guid(&format!("{seed}/{}", r.name)),
+                    first_lba: r.offset / SECTOR,
+                    last_lba: (r.offset + r.size) / SECTOR - 1,
+                })
+            })
+            .collect::<Vec<_>>();
+        let table = gpt::write_table(sectors, gpt::stable_guid(&seed), &entries)?;
+
+        let path = self.disk_image_path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&path)
+            .with_context(|| format!("can not create disk image {}", path.display()))?;
+        file.set_len(sectors * SECTOR)?;
+        let chunks = table
+            .iter()
+            .map(|(offset, data)| (*offset, data.as_slice()))
+            .chain(regions.iter().map(|r| (r.offset, r.data.as_slice())));
+        for (offset, data) in chunks {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(data)?;
+        }
+
+        self.artifact(ArtifactKind::Disk, &path);
+        self.paths.artifacts.disk = Some(path);
+        Ok(())
+    }
+
+    fn esp_content(&self, esp: &Esp) -> anyhow::Result<FatBuilder> {
+        let mut fat = FatBuilder::new(esp.label.as_deref().unwrap_or("ESP"));
+
+        let kernel_path = match &esp.kernel {
+            Some(path) => Some(path.clone()),
+            None if esp.efi => Some(self.efi_boot_path()?),
+            None => None,
+        };
+        if let Some(dest) = kernel_path {
+            let kernel = self
+                .paths
+                .artifacts
+                .bin
+                .as_ref()
+                .or(self.paths.artifacts.elf.as_ref())
+                .ok_or_else(|| anyhow!("no kernel to copy into the ESP"))?;
+            let data = std::fs::read(kernel)
+                .with_context(|| format!("can not read {}", kernel.display()))?;
+            fat.file(&dest, data)?;
+        }
+
+        if let Some(script) = &esp.startup_nsh {
+            fat.file("startup.nsh", script.as_bytes().to_vec())?;
+        }
+
+        let mut files = esp.files.iter().collect::<Vec<_>>();
+        files.sort();
+        for (dest, src) in files {
+            let src = self.paths.manifest.join(src);
+            let data = std::fs::read(&src)
+                .with_context(|| format!("can not read ESP file {}", src.display()))?;
+            fat.file(dest, data)?;
+        }
+        Ok(fat)
+    }
+
+    /// Removable media boot path the firmware looks for
+    fn efi_boot_path(&self) -> anyhow::Result<String> {
+        let name = match self.arch {
+            Some(Architecture::X86_64) => "BOOTX64.EFI",
+            Some(Architecture::Aarch64) => "BOOTAA64.EFI",
+            Some(Architecture::Riscv64) => "BOOTRISCV64.EFI",
+            Some(Architecture::LoongArch64) => "BOOTLOONGARCH64.EFI",
+            Some(Architecture::I386) => "BOOTIA32.EFI",
+            Some(Architecture::Arm) => "BOOTARM.EFI",
+            arch => bail!("no UEFI boot path for {arch:?}, set `esp.kernel`"),
+        };
+        Ok(format!("EFI/BOOT/{name}"))
+    }
+}
+
+fn size_or(size: Option<&str>, default: u64) -> anyhow::Result<u64> {
+    size.map(parse_size)
+        .transpose()
+        .map(|s| s.unwrap_or(default))
+}
+
+/// Sectors of a disk of `size`, which must fit both GPT copies.
+fn disk_sectors(size: &str) -> anyhow::Result<u64> {
+    let sectors = parse_size(size)? / SECTOR;
+    if sectors < gpt::MIN_SECTORS {
+        bail!(
+            "`disk.size` {size:?} is too small for a GPT, at least {} bytes are needed",
+            gpt::MIN_SECTORS * SECTOR
+        );
+    }
+    Ok(sectors)
+}
+
+/// Regions must be sector aligned, must not overlap each other or the GPT.
+fn check_layout(regions: &mut [Region], sectors: u64) -> anyhow::Result<()> {
+    regions.sort_by_key(|r| r.offset);
+    let (first, last) = gpt::usable_range(sectors);
+    let mut prev: Option<&Region> = None;
+    for region in regions.iter() {
+        if region.offset % SECTOR != 0 {
+            bail!(
+                "partition `{}` is not aligned to {SECTOR} bytes",
+                region.name
+            );
+        }
+        if region.offset < first * SECTOR || region.offset + region.size > (last + 1) * SECTOR {
+            bail!(
+                "partition `{}` at {:#x}..{:#x} overlaps the GPT, the image ends at {:#x}",
+                region.name,
+                region.offset,
+                region.offset + region.size,
+                sectors * SECTOR
+            );
+        }
+        if let Some(prev) = prev
+            && prev.offset + prev.size > region.offset
+        {
+            bail!("partitions `{}` and `{}` overlap", prev.name, region.name);
+        }
+        prev = Some(region);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_sectors() {
+        assert_eq!(disk_sectors("1M").unwrap(), 2048);
+        let min = gpt::MIN_SECTORS * SECTOR;
+        assert_eq!(disk_sectors(&min.to_string()).unwrap(), gpt::MIN_SECTORS);
+        assert!(disk_sectors(&(min - SECTOR).to_string()).is_err());
+        let err = disk_sectors("8K").unwrap_err().to_string();
+        assert!(err.contains("`disk.size` \"8K\""), "{err}");
+    }
+}
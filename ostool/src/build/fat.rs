@@ -0,0 +1,510 @@
+//! Minimal FAT32 formatter for EFI system partitions and SD-card boot
+//! partitions. Files are laid out contiguously, long names get VFAT entries.
+
+use std::collections::{BTreeMap, HashSet};
+
+const SECTOR: usize = 512;
+const RESERVED_SECTORS: usize = 32;
+const FATS: usize = 2;
+/// Volumes with fewer clusters are FAT16 for spec compliant drivers
+const MIN_FAT32_CLUSTERS: usize = 65525;
+const EOC: u32 = 0x0fff_ffff;
+
+const ATTR_VOLUME: u8 = 0x08;
+const ATTR_DIR: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0f;
+/// 1980-01-01, the FAT epoch, for reproducible images
+const DATE: u16 = (1 << 5) | 1;
+
+type Entry = [u8; 32];
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir(BTreeMap<String, Node>),
+    File(Vec<u8>),
+}
+
+/// File tree of a FAT32 volume, formatted by [`FatBuilder::build`].
+#[derive(Debug, Clone)]
+pub struct FatBuilder {
+    root: BTreeMap<String, Node>,
+    label: String,
+}
+
+impl FatBuilder {
+    pub fn new(label: &str) -> Self {
+        Self {
+            root: BTreeMap::new(),
+            label: label.to_string(),
+        }
+    }
+
+    /// Adds a file, parent directories are created. Names are case-insensitive.
+    pub fn file(&mut self, path: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let parts = split(path)?;
+        let (name, dirs) = parts.split_last().unwrap();
+        let dir = self.dir_mut(path, dirs)?;
+        if let Some(Node::Dir(_)) = child(dir, name) {
+            bail!("`{path}` is already a directory in the FAT image");
+        }
+        let key = key(dir, name);
+        dir.insert(key, Node::File(data));
+        Ok(())
+    }
+
+    pub fn dir(&mut self, path: &str) -> anyhow::Result<()> {
+        let parts = split(path)?;
+        self.dir_mut(path, &parts).map(|_| ())
+    }
+
+    fn dir_mut(
+        &mut self,
+        path: &str,
+        dirs: &[&str],
+    ) -> anyhow::Result<&mut BTreeMap<String, Node>> {
+        let mut dir = &mut self.root;
+        for name in dirs {
+            let key = key(dir, name);
+            let node = dir.entry(key).or_insert_with(|| Node::Dir(BTreeMap::new()));
+            dir = match node {
+                Node::Dir(children) => children,
+                Node::File(_) => bail!("`{name}` in `{path}` is a file in the FAT image"),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Formats a volume of `size` bytes starting `hidden_sectors` into the disk.
+    pub fn build(&self, size: u64, hidden_sectors: u32) -> anyhow::Result<Vec<u8>> {
+        let sectors = (size / SECTOR as u64) as usize;
+        let sectors_per_cluster = match size >> 20 {
+            0..=260 => 1,
+            261..=8192 => 8,
+            8193..=16384 => 16,
+            _ => 32,
+        };
+        let cluster_size = sectors_per_cluster * SECTOR;
+
+        let mut fat_sectors = 1;
+        let clusters = loop {
+            let data = sectors
+                .checked_sub(RESERVED_SECTORS + FATS * fat_sectors)
+                .ok_or_else(|| anyhow!("FAT partition of {size} bytes is too small"))?;
+            let clusters = data / sectors_per_cluster;
+            let needed = ((clusters + 2) * 4).div_ceil(SECTOR);
+            if needed <= fat_sectors {
+                break clusters;
+            }
+            fat_sectors = needed;
+        };
+        if clusters < MIN_FAT32_CLUSTERS {
+            warn!(
+                "FAT32 partition has only {clusters} clusters, some firmware wants at least {MIN_FAT32_CLUSTERS} (about 33M)"
+            );
+        }
+
+        let label = short_label(&self.label)?;
+        let mut layout = Layout {
+            cluster_size,
+            clusters,
+            fat: vec![0x0fff_fff8, EOC],
+            data: Vec::new(),
+        };
+        let root = layout.alloc(dir_size(&self.root, true)?)?;
+        layout.write_dir(&self.root, root, 0, Some(label))?;
+
+        let used = layout.fat.len() - 2;
+        let mut out = vec![0u8; (RESERVED_SECTORS + FATS * fat_sectors) * SECTOR];
+
+        let boot = boot_sector(
+            sectors as u32,
+            sectors_per_cluster as u8,
+            fat_sectors as u32,
+            hidden_sectors,
+            &label,
+        );
+        let info = fs_info((clusters - used) as u32, layout.fat.len() as u32);
+        for (sector, data) in [(0, &boot), (1, &info), (6, &boot), (7, &info)] {
+            out[sector * SECTOR..(sector + 1) * SECTOR].copy_from_slice(data);
+        }
+
+        for i in 0..FATS {
+            let start = (RESERVED_SECTORS + i * fat_sectors) * SECTOR;
+            for (j, entry) in layout.fat.iter().enumerate() {
+                out[start + j * 4..start + j * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+
+        out.extend_from_slice(&layout.data);
+        Ok(out)
+    }
+}
+
+struct Layout {
+    cluster_size: usize,
+    clusters: usize,
+    fat: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl Layout {
+    /// Contiguous clusters for `len` bytes, 0 for an empty file.
+    fn alloc(&mut self, len: usize) -> anyhow::Result<u32> {
+        if len == 0 {
+            return Ok(0);
+        }
+        let count = len.div_ceil(self.cluster_size);
+        let first = self.fat.len();
+        if first - 2 + count > self.clusters {
+            bail!("the content does not fit into the FAT partition");
+        }
+        for i in 0..count {
+            let next = if i + 1 == count {
+                EOC
+            } else {
+                (first + i + 1) as u32
+            };
+            self.fat.push(next);
+        }
+        self.data
+            .resize(self.data.len() + count * self.cluster_size, 0);
+        Ok(first as u32)
+    }
+
+    fn write(&mut self, cluster: u32, bytes: &[u8]) {
+        let offset = (cluster as usize - 2) * self.cluster_size;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Writes the entries of a directory at `cluster`, `label` is set for the root.
+    fn write_dir(
+        &mut self,
+        children: &BTreeMap<String, Node>,
+        cluster: u32,
+        parent: u32,
+        label: Option<[u8; 11]>,
+    ) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        match label {
+            Some(label) => entries.push(short_entry(label, ATTR_VOLUME, 0, 0)),
+            None => {
+                entries.push(short_entry(*b".          ", ATTR_DIR, cluster, 0));
+                entries.push(short_entry(*b"..         ", ATTR_DIR, parent, 0));
+            }
+        }
+        // `..` of a child of the root points to cluster 0
+        let this = if label.is_some() { 0 } else { cluster };
+
+        for ((name, node), (short, lfn)) in children.iter().zip(short_names(children)) {
+            let (attr, first, size) = match node {
+                Node::File(data) => {
+                    let first = self.alloc(data.len())?;
+                    if first != 0 {
+                        self.write(first, data);
+                    }
+                    (ATTR_ARCHIVE, first, data.len() as u32)
+                }
+                Node::Dir(sub) => {
+                    let first = self.alloc(dir_size(sub, false)?)?;
+                    self.write_dir(sub, first, this, None)?;
+                    (ATTR_DIR, first, 0)
+                }
+            };
+            if lfn {
+                entries.extend(lfn_entries(name, &short)?);
+            }
+            entries.push(short_entry(short, attr, first, size));
+        }
+
+        self.write(cluster, &entries.concat());
+        Ok(())
+    }
+}
+
+fn split(path: &str) -> anyhow::Result<Vec<&str>> {
+    let parts = path
+        .split(['/', '\\'])
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<_>>();
+    if parts.is_empty() || parts.contains(&"..") {
+        bail!("invalid FAT path `{path}`");
+    }
+    for part in &parts {
+        if part.chars().any(|c| c < ' ' || "\"*:<>?|".contains(c)) {
+            bail!("invalid character in FAT path `{path}`");
+        }
+    }
+    Ok(parts)
+}
+
+fn child<'a>(dir: &'a BTreeMap<String, Node>, name: &str) -> Option<&'a Node> {
+    dir.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+/// Existing key matching `name` case-insensitively, or `name`
+fn key(dir: &BTreeMap<String, Node>, name: &str) -> String {
+    dir.keys()
+        .find(|k| k.eq_ignore_ascii_case(name))
+        .cloned()
+        .unwrap_or_else(|| name.to_string())
+}
+
+fn dir_size(children: &BTreeMap<String, Node>, root: bool) -> anyhow::Result<usize> {
+    let mut entries = if root { 1 } else { 2 };
+    for (name, (_, lfn)) in children.keys().zip(short_names(children)) {
+        entries += 1;
+        if lfn {
+            entries += lfn_count(name)?;
+        }
+    }
+    Ok(entries * 32)
+}
+
+/// Short name of every child and whether it needs long name entries.
+fn short_names(children: &BTreeMap<String, Node>) -> Vec<([u8; 11], bool)> {
+    let mut used = HashSet::new();
+    children
+        .keys()
+        .map(|name| short_name(name, &mut used))
+        .collect()
+}
+
+fn short_name(name: &str, used: &mut HashSet<[u8; 11]>) -> ([u8; 11], bool) {
+    let upper = name.to_ascii_uppercase();
+    if let Some(short) = exact_short_name(&upper)
+        && used.insert(short)
+    {
+        return (short, upper != name);
+    }
+
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+    let clean = |s: &str| {
+        s.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if is_short_char(c) { c as u8 } else { b'_' }
+            })
+            .collect::<Vec<_>>()
+    };
+    let base = clean(base);
+    let ext = clean(ext);
+
+    for n in 1.. {
+        let tail = format!("~{n}");
+        let keep = (8 - tail.len()).min(base.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext_len = ext.len().min(3);
+        short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+        if used.insert(short) {
+            return (short, true);
+        }
+    }
+    unreachable!()
+}
+
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(is_short_char)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+fn short_label(label: &str) -> anyhow::Result<[u8; 11]> {
+    let label = label.to_ascii_uppercase();
+    if label.len() > 11 || !label.chars().all(|c| c == ' ' || is_short_char(c)) {
+        bail!("invalid FAT volume label `{label}`, use up to 11 of A-Z, 0-9 and _");
+    }
+    let mut out = *b"NO NAME    ";
+    if !label.is_empty() {
+        out = [b' '; 11];
+        out[..label.len()].copy_from_slice(label.as_bytes());
+    }
+    Ok(out)
+}
+
+fn short_entry(name: [u8; 11], attr: u8, cluster: u32, size: u32) -> Entry {
+    let mut e = [0u8; 32];
+    e[0..11].copy_from_slice(&name);
+    e[11] = attr;
+    e[16..18].copy_from_slice(&DATE.to_le_bytes());
+    e[18..20].copy_from_slice(&DATE.to_le_bytes());
+    e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    e[24..26].copy_from_slice(&DATE.to_le_bytes());
+    e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    e[28..32].copy_from_slice(&size.to_le_bytes());
+    e
+}
+
+fn lfn_count(name: &str) -> anyhow::Result<usize> {
+    let len = name.encode_utf16().count();
+    if len > 255 {
+        bail!("FAT file name `{name}` is longer than 255 characters");
+    }
+    Ok(len.div_ceil(13))
+}
+
+/// VFAT long name entries of `name`, in on-disk order.
+fn lfn_entries(name: &str, short: &[u8; 11]) -> anyhow::Result<Vec<Entry>> {
+    let count = lfn_count(name)?;
+    let checksum = short
+        .iter()
+        .fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b));
+
+    let mut units = name.encode_utf16().collect::<Vec<_>>();
+    if units.len() < count * 13 {
+        units.push(0);
+    }
+    units.resize(count * 13, 0xffff);
+
+    let mut entries = Vec::new();
+    for (i, chunk) in units.chunks(13).enumerate() {
+        let mut e = [0u8; 32];
+        e[0] = (i + 1) as u8;
+        if i + 1 == count {
+            e[0] |= 0x40;
+        }
+        e[11] = ATTR_LFN;
+        e[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain([28, 30]);
+        for (unit, offset) in chunk.iter().zip(offsets) {
+            e[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(e);
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+fn boot_sector(
+    sectors: u32,
+    sectors_per_cluster: u8,
+    fat_sectors: u32,
+    hidden_sectors: u32,
+    label: &[u8; 11],
+) -> Vec<u8> {
+    let mut b = vec![0u8; SECTOR];
+    b[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    b[3..11].copy_from_slice(b"OSTOOL  ");
+    b[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    b[13] = sectors_per_cluster;
+    b[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    b[16] = FATS as u8;
+    b[21] = 0xf8;
+    b[24..26].copy_from_slice(&32u16.to_le_bytes());
+    b[26..28].copy_from_slice(&64u16.to_le_bytes());
+    b[28..32].copy_from_slice(&hidden_sectors.to_le_bytes());
+    b[32..36].copy_from_slice(&sectors.to_le_bytes());
+    b[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+    b[44..48].copy_from_slice(&2u32.to_le_bytes());
+    b[48..50].copy_from_slice(&1u16.to_le_bytes());
+    b[50..52].copy_from_slice(&6u16.to_le_bytes());
+    b[64] = 0x80;
+    b[66] = 0x29;
+    b[67..71].copy_from_slice(&fitimage::calculate_crc32(label).to_le_bytes());
+    b[71..82].copy_from_slice(label);
+    b[82..90].copy_from_slice(b"FAT32   ");
+    b[510] = 0x55;
+    b[511] = 0xaa;
+    b
+}
+
+fn fs_info(free: u32, next_free: u32) -> Vec<u8> {
+    let mut b = vec![0u8; SECTOR];
+    b[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    b[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    b[488..492].copy_from_slice(&free.to_le_bytes());
+    b[492..496].copy_from_slice(&next_free.to_le_bytes());
+    b[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_names() {
+        let mut used = HashSet::new();
+        assert_eq!(
+            short_name("BOOTX64.EFI", &mut used),
+            (*b"BOOTX64 EFI", false)
+        );
+        assert_eq!(
+            short_name("startup.nsh", &mut used),
+            (*b"STARTUP NSH", true)
+        );
+        assert_eq!(
+            short_name("kernel-image.bin", &mut used),
+            (*b"KERNEL~1BIN", true)
+        );
+        assert_eq!(
+            short_name("kernel image.bin", &mut used),
+            (*b"KERNEL~2BIN", true)
+        );
+    }
+
+    #[test]
+    fn test_fat32_volume() {
+        let mut fat = FatBuilder::new("ESP");
+        fat.file("/EFI/BOOT/BOOTX64.EFI", vec![0x4d; 1500]).unwrap();
+        fat.file("startup.nsh", b"\\EFI\\BOOT\\BOOTX64.EFI\r\n".to_vec())
+            .unwrap();
+        assert!(fat.file("/efi", vec![]).is_err());
+
+        let image = fat.build(64 << 20, 2048).unwrap();
+        assert_eq!(&image[82..90], b"FAT32   ");
+        assert_eq!(image[510..512], [0x55, 0xaa]);
+        assert_eq!(image[..SECTOR], image[6 * SECTOR..7 * SECTOR]);
+
+        let fat_sectors = u32::from_le_bytes(image[36..40].try_into().unwrap()) as usize;
+        let data = (RESERVED_SECTORS + FATS * fat_sectors) * SECTOR;
+        let root = &image[data..data + SECTOR];
+        assert_eq!(&root[0..11], b"ESP        ");
+        assert_eq!(root[11], ATTR_VOLUME);
+        // `EFI` directory, then the long name and short entry of startup.nsh
+        assert_eq!(&root[32..43], b"EFI        ");
+        assert_eq!(root[64], 0x41);
+        assert_eq!(root[64 + 11], ATTR_LFN);
+        assert_eq!(&root[96..107], b"STARTUP NSH");
+
+        // root, EFI and BOOT take clusters 2-4, BOOTX64.EFI the next three
+        let fat_start = RESERVED_SECTORS * SECTOR;
+        let entry = |i: usize| {
+            u32::from_le_bytes(
+                image[fat_start + i * 4..fat_start + i * 4 + 4]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        assert_eq!([entry(5), entry(6), entry(7)], [6, 7, EOC]);
+
+        assert!(FatBuilder::new("ESP").build(1 << 20, 0).is_ok());
+        let mut big = FatBuilder::new("ESP");
+        big.file("big", vec![0; 2 << 20]).unwrap();
+        assert!(big.build(1 << 20, 0).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! GUID partition table writer: protective MBR, primary and backup headers.
+
+use sha2::{Digest, Sha256};
+
+pub const SECTOR: u64 = 512;
+
+const ENTRIES: u64 = 128;
+const ENTRY_SIZE: u64 = 128;
+/// Sectors taken by the entry array
+const ENTRY_SECTORS: u64 = ENTRIES * ENTRY_SIZE / SECTOR;
+/// Smallest disk holding the MBR, both GPT copies and one partition sector
+pub const MIN_SECTORS: u64 = 2 * (2 + ENTRY_SECTORS) + 1;
+
+pub const ESP_TYPE: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+pub const LINUX_TYPE: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+pub const DATA_TYPE: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+/// On-disk byte order of a GUID string, the first three fields are little endian.
+pub fn parse_guid(s: &str) -> anyhow::Result<[u8; 16]> {
+    let hex = s.replace('-', "");
+    let parts = s.split('-').map(str::len).collect::<Vec<_>>();
+    if parts != [8, 4, 4, 4, 12] || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid GUID `{s}`");
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Ok(bytes)
+}
+
+/// Version 4 style GUID derived from `seed`, so images are reproducible.
+pub fn stable_guid(seed: &str) -> [u8; 16] {
+    let hash = Sha256::digest(seed.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+#[derive(Debug, Clone)]
+pub struct GptPartition {
+    pub name: String,
+    pub type_guid: [u8; 16],
+    pub guid: [u8; 16],
+    pub first_lba: u64,
+    /// inclusive
+    pub last_lba: u64,
+}
+
+/// First and last LBA partitions may use on a disk of `sectors`, at least [`MIN_SECTORS`].
+pub fn usable_range(sectors: u64) -> (u64, u64) {
+    (2 + ENTRY_SECTORS, sectors - 2 - ENTRY_SECTORS)
+}
+
+/// `(offset, data)` chunks of the partition table of a disk of `sectors`.
+pub fn write_table(
+    sectors: u64,
+    disk_guid: [u8; 16],
+    partitions: &[GptPartition],
+) -> anyhow::Result<Vec<(u64, Vec<u8>)>> {
+    if partitions.len() as u64 > ENTRIES {
+        bail!("a GPT holds at most {ENTRIES} partitions");
+    }
+    let (first, last) = usable_range(sectors);
+
+    let mut entries = vec![0u8; (ENTRIES * ENTRY_SIZE) as usize];
+    for (i, part) in partitions.iter().enumerate() {
+        if part.first_lba < first || part.last_lba > last || part.first_lba > part.last_lba {
+            bail!(
+                "partition `{}` (LBA {}..={}) is outside the usable range {first}..={last}",
+                part.name,
+                part.first_lba,
+                part.last_lba
+            );
+        }
+        let e = &mut entries[i * ENTRY_SIZE as usize..(i + 1) * ENTRY_SIZE as usize];
+        e[0..16].copy_from_slice(&part.type_guid);
+        e[16..32].copy_from_slice(&part.guid);
+        e[32..40].copy_from_slice(&part.first_lba.to_le_bytes());
+        e[40..48].copy_from_slice(&part.last_lba.to_le_bytes());
+        let name = part.name.encode_utf16().collect::<Vec<_>>();
+        if name.len() > 36 {
+            bail!(
+                "partition name `{}` is longer than 36 characters",
+                part.name
+            );
+        }
+        for (j, c) in name.iter().enumerate() {
+            e[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let entries_crc = fitimage::calculate_crc32(&entries);
+
+    let header = |current: u64, backup: u64, entries_lba: u64| {
+        let mut h = vec![0u8; SECTOR as usize];
+        h[0..8].copy_from_slice(b"EFI PART");
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&current.to_le_bytes());
+        h[32..40].copy_from_slice(&backup.to_le_bytes());
+        h[40..48].copy_from_slice(&first.to_le_bytes());
+        h[48..56].copy_from_slice(&last.to_le_bytes());
+        h[56..72].copy_from_slice(&disk_guid);
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        h[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        h[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = fitimage::calculate_crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        h
+    };
+
+    let last_lba = sectors - 1;
+    let backup_entries = last_lba - ENTRY_SECTORS;
+    Ok(vec![
+        (0, protective_mbr(sectors)),
+        (SECTOR, header(1, last_lba, 2)),
+        (2 * SECTOR, entries.clone()),
+        (backup_entries * SECTOR, entries),
+        (last_lba * SECTOR, header(last_lba, 1, backup_entries)),
+    ])
+}
+
+fn protective_mbr(sectors: u64) -> Vec<u8> {
+    let mut mbr = vec![0u8; SECTOR as usize];
+    let e = &mut mbr[446..462];
+    e[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    e[4] = 0xee;
+    e[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    e[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let size = (sectors - 1).min(u32::MAX as u64) as u32;
+    e[12..16].copy_from_slice(&size.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    mbr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpt_headers() {
+        let sectors = 8192;
+        let part = GptPartition {
+            name: "ESP".into(),
+            type_guid: parse_guid(ESP_TYPE).unwrap(),
+            guid: stable_guid("esp"),
+            first_lba: 2048,
+            last_lba: 4095,
+        };
+        let chunks = write_table(sectors, stable_guid("disk"), &[part]).unwrap();
+
+        let primary = &chunks[1].1;
+        assert_eq!(&primary[0..8], b"EFI PART");
+        let mut zeroed = primary[..92].to_vec();
+        zeroed[16..20].fill(0);
+        assert_eq!(
+            fitimage::calculate_crc32(&zeroed).to_le_bytes(),
+            primary[16..20],
+            "header CRC"
+        );
+        assert_eq!(chunks[4].0, (sectors - 1) * SECTOR);
+        assert_eq!(chunks[2].1, chunks[3].1);
+        // ESP type GUID in on-disk byte order
+        assert_eq!(&chunks[2].1[0..4], &[0x28, 0x73, 0x2a, 0xc1]);
+        assert_eq!(chunks[0].1[450], 0xee);
+
+        let outside = GptPartition {
+            name: "data".into(),
+            type_guid: parse_guid(LINUX_TYPE).unwrap(),
+            guid: [0; 16],
+            first_lba: 8000,
+            last_lba: 8191,
+        };
+        assert!(write_table(sectors, [0; 16], &[outside]).is_err());
+        assert!(parse_guid("not-a-guid").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Builds the `userspace` packages and packs them, with the `initramfs`
+//! files, into a CPIO `newc` archive next to the kernel build.
+
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::Context;
+use cargo_metadata::Message;
+
+use crate::{
+    build::{
+        config::{Initramfs, UserspacePackage},
+        cpio::CpioBuilder,
+        target::TargetSpec,
+    },
+    ctx::AppContext,
+    event::{ArtifactKind, Event},
+};
+
+/// Environment variable carrying the initramfs path from `ostool` to `cargo-osrun`
+pub const INITRAMFS_ENV: &str = "OSTOOL_INITRAMFS";
+
+impl AppContext {
+    /// Where the initramfs of this build is written
+    pub fn initramfs_path(&self) -> PathBuf {
+        self.paths.build_dir().join("ostool").join("initramfs.cpio")
+    }
+
+    /// Builds the initramfs if the build config asks for one.
+    pub async fn build_initramfs(&mut self) -> anyhow::Result<()> {
+        let Some(config) = self.build_config.clone() else {
+            return Ok(());
+        };
+        if !config.has_initramfs() {
+            return Ok(());
+        }
+
+        let mut cpio = CpioBuilder::new().with_mtime(source_date_epoch());
+
+        for package in &config.userspace {
+            let elf = self.build_userspace(package)?;
+            let name = elf
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let path = package
+                .path
+                .clone()
+                .unwrap_or_else(|| format!("/bin/{name}"));
+            let data =
+                std::fs::read(&elf).with_context(|| format!("can not read {}", elf.display()))?;
+            cpio.file(&path, 0o755, data)?;
+        }
+
+        if let Some(initramfs) = &config.initramfs {
+            self.add_initramfs_files(&mut cpio, initramfs)?;
+        }
+
+        let path = self.initramfs_path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, cpio.build())?;
+        self.artifact(ArtifactKind::Initramfs, &path);
+        self.paths.artifacts.initramfs = Some(path);
+        Ok(())
+    }
+
+    fn build_userspace(&self, package: &UserspacePackage) -> anyhow::Result<PathBuf> {
+        let target = TargetSpec::resolve(&package.target, &self.paths.manifest)?;
+
+        let mut cmd = self.command("cargo");
+        cmd.arg("build");
+        for (k, v) in &package.env {
+            cmd.env(k, v);
+        }
+        cmd.arg("-p");
+        cmd.arg(&package.package);
+        cmd.arg("--target");
+        cmd.arg(target.cargo_arg());
+        if let Some(bin) = &package.bin {
+            cmd.arg("--bin");
+            cmd.arg(bin);
+        }
+        if !package.features.is_empty() {
+            cmd.arg("--features");
+            cmd.arg(package.features.join(","));
+        }
+        if let Some(build_dir) = &self.paths.config.build_dir {
+            cmd.arg("--target-dir");
+            cmd.arg(build_dir.display().to_string());
+        }
+        if let Some(profile) = &package.profile {
+            cmd.arg("--profile");
+            cmd.arg(profile);
+        } else if !self.debug {
+            cmd.arg("--release");
+        }
+        cmd.args(&package.args);
+        cmd.arg("--message-format=json-render-diagnostics");
+
+        cmd.stdout(Stdio::piped());
+        cmd.print_cmd();
+        let mut child = cmd.spawn()?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let package_id = self.metadata().ok().and_then(|meta| {
+            meta.packages
+                .into_iter()
+                .find(|p| p.name.as_str() == package.package)
+                .map(|p| p.id)
+        });
+
+        let mut executables = Vec::new();
+        for message in Message::parse_stream(stdout) {
+            match message? {
+                Message::CompilerArtifact(artifact) => {
+                    let Some(executable) = artifact.executable else {
+                        continue;
+                    };
+                    let is_package = package_id
+                        .as_ref()
+                        .is_none_or(|id| &artifact.package_id == id);
+                    let is_bin = match &package.bin {
+                        Some(bin) => artifact.target.is_bin() && &artifact.target.name == bin,
+                        None => artifact.target.is_bin(),
+                    };
+                    if is_package && is_bin {
+                        executables.push(executable.into_std_path_buf());
+                    }
+                }
+                Message::TextLine(line) => self.events.emit(Event::BuildOutput { line }),
+                _ => {}
+            }
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            bail!(
+                "userspace package `{}` failed with {status}",
+                package.package
+            );
+        }
+
+        match executables.as_slice() {
+            [one] => Ok(one.clone()),
+            [] => bail!(
+                "cargo reported no executable for userspace package `{}`",
+                package.package
+            ),
+            _ => bail!(
+                "userspace package `{}` has several binaries, set `bin`",
+                package.package
+            ),
+        }
+    }
+
+    fn add_initramfs_files(
+        &self,
+        cpio: &mut CpioBuilder,
+        initramfs: &Initramfs,
+    ) -> anyhow::Result<()> {
+        if let Some(overlay) = &initramfs.overlay {
+            let root = self.paths.manifest.join(overlay);
+            add_dir(cpio, &root, "")
+                .with_context(|| format!("can not add initramfs overlay {}", root.display()))?;
+        }
+
+        let mut files = initramfs.files.iter().collect::<Vec<_>>();
+        files.sort();
+        for (dest, src) in files {
+            let src = self.paths.manifest.join(src);
+            let data = std::fs::read(&src)
+                .with_context(|| format!("can not read initramfs file {}", src.display()))?;
+            cpio.file(dest, file_mode(&src), data)?;
+        }
+        Ok(())
+    }
+}
+
+fn add_dir(cpio: &mut CpioBuilder, dir: &Path, prefix: &str) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = format!("{prefix}/{}", entry.file_name().to_string_lossy());
+        // Links are kept as links, following them could loop forever
+        let file_type = std::fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            cpio.symlink(&name, &target.to_string_lossy())?;
+        } else if file_type.is_dir() {
+            cpio.dir(&name)?;
+            add_dir(cpio, &path, &name)?;
+        } else {
+            cpio.file(&name, file_mode(&path), std::fs::read(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.permissions().mode())
+        .unwrap_or(0o644)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> u32 {
+    0o644
+}
+
+/// `SOURCE_DATE_EPOCH` for reproducible archives, 0 if unset
+fn source_date_epoch() -> u32 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_dir_keeps_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("bin")).unwrap();
+        std::fs::write(dir.path().join("bin/init"), b"init").unwrap();
+        std::os::unix::fs::symlink(".", dir.path().join("lib")).unwrap();
+
+        let mut cpio = CpioBuilder::new();
+        add_dir(&mut cpio, dir.path(), "").unwrap();
+        let archive = cpio.build();
+        let contains = |s: &[u8]| archive.windows(s.len()).any(|w| w == s);
+        assert!(contains(b"bin/init\0"));
+        assert!(contains(b"lib\0"));
+        assert!(!contains(b"lib/"));
+    }
+}
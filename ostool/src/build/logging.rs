@@ -0,0 +1,381 @@
+//! Log level injection for the logging crates of the kernel.
+//!
+//! The dependency graph of the package is walked with `cargo metadata` and
+//! every [`LogBackend`] whose crate shows up sets its cargo features or
+//! environment variables: `log` and `tracing` get their static
+//! `max_level_*` features, `defmt` gets `DEFMT_LOG`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use cargo_metadata::{DependencyKind, Metadata, Package, PackageId};
+
+use crate::build::config::LogLevel;
+
+/// Build-time copy of the per-module filters for `log` and `tracing`,
+/// readable with `option_env!("OSTOOL_LOG")`. Not `RUST_LOG`, which would
+/// also reach the loggers of cargo-osrun and build scripts
+pub const FILTER_ENV: &str = "OSTOOL_LOG";
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+/// Base level and per-module levels
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    pub level: LogLevel,
+    /// sorted by module path
+    pub modules: Vec<(String, LogLevel)>,
+}
+
+impl LogFilter {
+    pub fn new(level: LogLevel, modules: &HashMap<String, LogLevel>) -> Self {
+        let mut modules = modules
+            .iter()
+            .map(|(m, l)| (m.clone(), *l))
+            .collect::<Vec<_>>();
+        modules.sort();
+        Self { level, modules }
+    }
+
+    /// Most verbose level of the base and all modules, what must be compiled in
+    pub fn max_level(&self) -> LogLevel {
+        self.modules
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.level, std::cmp::min)
+    }
+
+    /// `info,kernel::mm=trace`, the syntax of `RUST_LOG` and `DEFMT_LOG`
+    pub fn directives(&self) -> String {
+        let mut out = self.level.as_str().to_string();
+        for (module, level) in &self.modules {
+            out.push_str(&format!(",{module}={}", level.as_str()));
+        }
+        out
+    }
+}
+
+/// Cargo features and environment variables for the build
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogSettings {
+    pub features: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+impl LogSettings {
+    pub fn set_env(&mut self, key: &str, value: String) {
+        self.env.retain(|(k, _)| k != key);
+        self.env.push((key.to_string(), value));
+    }
+}
+
+/// A logging crate whose level is set at build time.
+pub(crate) trait LogBackend {
+    /// Crate selecting this backend when it is in the dependency graph
+    fn crate_name(&self) -> &'static str;
+
+    /// Whether levels are set with features of the crate, which cargo only
+    /// accepts for direct dependencies
+    fn needs_direct_dependency(&self) -> bool {
+        false
+    }
+
+    /// `dep` is the dependency key when the crate is a direct dependency.
+    fn configure(
+        &self,
+        dep: Option<&str>,
+        filter: &LogFilter,
+        release: bool,
+        out: &mut LogSettings,
+    );
+}
+
+/// `log` and `tracing` share the `[release_]max_level_*` features.
+struct MaxLevelFeatures(&'static str);
+
+impl LogBackend for MaxLevelFeatures {
+    fn crate_name(&self) -> &'static str {
+        self.0
+    }
+
+    fn needs_direct_dependency(&self) -> bool {
+        true
+    }
+
+    fn configure(
+        &self,
+        dep: Option<&str>,
+        filter: &LogFilter,
+        release: bool,
+        out: &mut LogSettings,
+    ) {
+        let Some(dep) = dep else {
+            return;
+        };
+        out.features.push(format!(
+            "{dep}/{}max_level_{}",
+            if release { "release_" } else { "" },
+            filter.max_level().as_str()
+        ));
+        if !filter.modules.is_empty() {
+            out.set_env(FILTER_ENV, filter.directives());
+        }
+    }
+}
+
+struct Defmt;
+
+impl LogBackend for Defmt {
+    fn crate_name(&self) -> &'static str {
+        "defmt"
+    }
+
+    fn configure(
+        &self,
+        _dep: Option<&str>,
+        filter: &LogFilter,
+        _release: bool,
+        out: &mut LogSettings,
+    ) {
+        out.set_env("DEFMT_LOG", filter.directives());
+    }
+}
+
+/// The built-in backends: `log`, `tracing` and `defmt`
+pub(crate) fn backends() -> Vec<Box<dyn LogBackend>> {
+    vec![
+        Box::new(MaxLevelFeatures("log")),
+        Box::new(MaxLevelFeatures("tracing")),
+        Box::new(Defmt),
+    ]
+}
+
+/// Settings of every backend found in the dependency graph of `package`.
+/// `meta` must be resolved, i.e. not `--no-deps`.
+pub(crate) fn detect(
+    meta: &Metadata,
+    package: &str,
+    backends: &[Box<dyn LogBackend>],
+    filter: &LogFilter,
+    release: bool,
+) -> anyhow::Result<LogSettings> {
+    let root = meta
+        .packages
+        .iter()
+        .find(|p| p.name.as_str() == package && meta.workspace_members.contains(&p.id))
+        .or_else(|| meta.packages.iter().find(|p| p.name.as_str() == package))
+        .ok_or_else(|| anyhow!("package `{package}` not found in cargo metadata"))?;
+    let reachable = reachable(meta, &root.id)?;
+
+    let mut settings = LogSettings::default();
+    for backend in backends {
+        let name = backend.crate_name();
+        if !reachable.contains(name) {
+            continue;
+        }
+        let dep = direct_dependency(root, name);
+        if dep.is_none() && backend.needs_direct_dependency() {
+            warn!(
+                "`{name}` is not a direct dependency of `{package}`, add it to [dependencies] to set its level"
+            );
+        }
+        debug!("Log backend `{name}` found");
+        backend.configure(dep, filter, release, &mut settings);
+    }
+    Ok(settings)
+}
+
+/// Names of the packages `root` links against, build and dev dependencies left out
+fn reachable(meta: &Metadata, root: &PackageId) -> anyhow::Result<HashSet<String>> {
+    let resolve = meta
+        .resolve
+        .as_ref()
+        .ok_or_else(|| anyhow!("cargo metadata has no dependency graph"))?;
+    let nodes = resolve
+        .nodes
+        .iter()
+        .map(|n| (&n.id, n))
+        .collect::<HashMap<_, _>>();
+    let names = meta
+        .packages
+        .iter()
+        .map(|p| (&p.id, p.name.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([root]);
+    while let Some(id) = queue.pop_front() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let Some(node) = nodes.get(id) else {
+            continue;
+        };
+        for dep in &node.deps {
+            if dep
+                .dep_kinds
+                .iter()
+                .any(|k| k.kind == DependencyKind::Normal)
+            {
+                queue.push_back(&dep.pkg);
+            }
+        }
+    }
+    Ok(seen
+        .into_iter()
+        .filter(|id| *id != root)
+        .filter_map(|id| names.get(id).cloned())
+        .collect())
+}
+
+/// Key of `name` in the `[dependencies]` of `package`, its rename if any
+fn direct_dependency<'a>(package: &'a Package, name: &str) -> Option<&'a str> {
+    package
+        .dependencies
+        .iter()
+        .find(|d| d.name == name && d.kind == DependencyKind::Normal)
+        .map(|d| d.rename.as_deref().unwrap_or(&d.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings of all backends, `direct` are the direct dependencies
+    fn settings(direct: &[&str], filter: &LogFilter, release: bool) -> LogSettings {
+        let mut out = LogSettings::default();
+        for backend in backends() {
+            let dep = direct.iter().copied().find(|d| *d == backend.crate_name());
+            backend.configure(dep, filter, release, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_backend_settings() {
+        let filter = LogFilter::new(LogLevel::Info, &HashMap::new());
+        let out = settings(&["log", "tracing"], &filter, true);
+        assert_eq!(
+            out.features,
+            [
+                "log/release_max_level_info",
+                "tracing/release_max_level_info"
+            ]
+        );
+        assert_eq!(out.env, [("DEFMT_LOG".to_string(), "info".to_string())]);
+
+        let modules = HashMap::from([
+            ("kernel::mm".to_string(), LogLevel::Trace),
+            ("kernel::net".to_string(), LogLevel::Warn),
+        ]);
+        let filter = LogFilter::new(LogLevel::Info, &modules);
+        assert_eq!(filter.max_level(), LogLevel::Trace);
+        assert_eq!(
+            filter.directives(),
+            "info,kernel::mm=trace,kernel::net=warn"
+        );
+
+        let out = settings(&["log"], &filter, false);
+        assert_eq!(out.features[0], "log/max_level_trace");
+        assert_eq!(
+            out.env,
+            [
+                (FILTER_ENV.to_string(), filter.directives()),
+                ("DEFMT_LOG".to_string(), filter.directives()),
+            ]
+        );
+
+        // Transitive only: no features, defmt still works through the env
+        let out = settings(&[], &filter, false);
+        assert!(out.features.is_empty());
+        assert_eq!(out.env.len(), 1);
+    }
+
+    /// `(name, rename, kind)` of a dependency
+    type Dep<'a> = (&'a str, Option<&'a str>, Option<&'a str>);
+
+    /// `cargo metadata` of `packages`, each a name and its dependencies.
+    /// The first package is the workspace member.
+    fn metadata(packages: &[(&str, &[Dep])]) -> Metadata {
+        let id = |name: &str| format!("path+file:///work/{name}#0.1.0");
+        let kinds = |kind: Option<&str>| serde_json::json!([{ "kind": kind, "target": null }]);
+        let json = serde_json::json!({
+            "packages": packages.iter().map(|(name, deps)| serde_json::json!({
+                "name": name,
+                "version": "0.1.0",
+                "id": id(name),
+                "manifest_path": format!("/work/{name}/Cargo.toml"),
+                "targets": [],
+                "features": {},
+                "dependencies": deps.iter().map(|(dep, rename, kind)| serde_json::json!({
+                    "name": dep,
+                    "req": "*",
+                    "kind": kind,
+                    "rename": rename,
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null,
+                })).collect::<Vec<_>>(),
+            })).collect::<Vec<_>>(),
+            "workspace_members": [id(packages[0].0)],
+            "resolve": {
+                "root": id(packages[0].0),
+                "nodes": packages.iter().map(|(name, deps)| serde_json::json!({
+                    "id": id(name),
+                    "dependencies": deps.iter().map(|(dep, _, _)| id(dep)).collect::<Vec<_>>(),
+                    "deps": deps.iter().map(|(dep, rename, kind)| serde_json::json!({
+                        "name": rename.unwrap_or(dep),
+                        "pkg": id(dep),
+                        "dep_kinds": kinds(*kind),
+                    })).collect::<Vec<_>>(),
+                })).collect::<Vec<_>>(),
+            },
+            "workspace_root": "/work",
+            "target_directory": "/work/target",
+            "version": 1,
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_detect() {
+        let meta = metadata(&[
+            (
+                "kernel",
+                &[
+                    ("log", Some("logger"), None),
+                    ("tracing", None, Some("dev")),
+                    ("defmt", None, Some("build")),
+                    ("driver", None, None),
+                ],
+            ),
+            ("driver", &[("tracing", None, None)]),
+            ("log", &[]),
+            ("tracing", &[]),
+            ("defmt", &[]),
+        ]);
+        let filter = LogFilter::new(LogLevel::Debug, &HashMap::new());
+
+        // `log` under its rename, `tracing` only through `driver` so without
+        // features, `defmt` only at build time so not at all
+        let out = detect(&meta, "kernel", &backends(), &filter, false).unwrap();
+        assert_eq!(out.features, ["logger/max_level_debug"]);
+        assert!(out.env.is_empty());
+
+        // a dependency of a dependency is found
+        let out = detect(&meta, "driver", &backends(), &filter, true).unwrap();
+        assert_eq!(out.features, ["tracing/release_max_level_debug"]);
+
+        assert!(detect(&meta, "missing", &backends(), &filter, false).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Make and CMake build systems.
+//!
+//! The ELF is found with a glob relative to the build directory, the most
+//! recently modified match wins. It is then handled like a Cargo build.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    build::config::{CMake, Make},
+    ctx::AppContext,
+    utils::Command,
+};
+
+impl AppContext {
+    pub async fn build_make(&mut self, config: &Make) -> anyhow::Result<()> {
+        self.pre_build().await?;
+
+        let build_dir = self.native_dir(config.build_dir.as_deref(), &self.paths.manifest);
+
+        let mut cmd = self.command("make");
+        set_envs(&mut cmd, &config.env);
+        cmd.arg("-C");
+        cmd.arg(build_dir.display().to_string());
+        cmd.arg(format!("-j{}", jobs(config.jobs)));
+        for (k, v) in sorted(&config.vars) {
+            cmd.arg(format!("{k}={v}"));
+        }
+        cmd.args(&config.targets);
+        cmd.run()?;
+
+        let elf = find_elf(&build_dir, &config.elf)?;
+        info!("Found ELF: {}", elf.display());
+        self.handle_elf(
+            elf,
+            config.to_bin,
+            &config.output_formats,
+            &config.size_limits,
+        )
+        .await
+    }
+
+    pub async fn build_cmake(&mut self, config: &CMake) -> anyhow::Result<()> {
+        self.pre_build().await?;
+
+        let source_dir = self.native_dir(config.source_dir.as_deref(), &self.paths.manifest);
+        let build_dir = self.native_dir(config.build_dir.as_deref(), &source_dir.join("build"));
+
+        let mut cmd = self.command("cmake");
+        set_envs(&mut cmd, &config.env);
+        cmd.arg("-S");
+        cmd.arg(source_dir.display().to_string());
+        cmd.arg("-B");
+        cmd.arg(build_dir.display().to_string());
+        for (k, v) in sorted(&config.defines) {
+            cmd.arg(format!("-D{k}={v}"));
+        }
+        cmd.run()?;
+
+        let mut cmd = self.command("cmake");
+        set_envs(&mut cmd, &config.env);
+        cmd.arg("--build");
+        cmd.arg(build_dir.display().to_string());
+        cmd.arg("-j");
+        cmd.arg(jobs(config.jobs).to_string());
+        if !config.targets.is_empty() {
+            cmd.arg("--target");
+            cmd.args(&config.targets);
+        }
+        cmd.run()?;
+
+        let elf = find_elf(&build_dir, &config.elf)?;
+        info!("Found ELF: {}", elf.display());
+        self.handle_elf(
+            elf,
+            config.to_bin,
+            &config.output_formats,
+            &config.size_limits,
+        )
+        .await
+    }
+
+    /// `dir` relative to the manifest directory, or `default`.
+    fn native_dir(&self, dir: Option<&str>, default: &Path) -> PathBuf {
+        match dir {
+            Some(dir) => self.paths.manifest.join(dir),
+            None => default.to_path_buf(),
+        }
+    }
+}
+
+/// The most recently modified file matching `pattern`, relative to `dir`.
+pub fn find_elf(dir: &Path, pattern: &str) -> anyhow::Result<PathBuf> {
+    let full = dir.join(pattern);
+    let full = full.to_string_lossy();
+    let paths = glob::glob(&full).map_err(|e| anyhow!("invalid ELF pattern `{pattern}`: {e}"))?;
+
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    for path in paths.flatten() {
+        if !path.is_file() {
+            continue;
+        }
+        let modified = path
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        if newest.as_ref().is_none_or(|(t, _)| modified > *t) {
+            newest = Some((modified, path));
+        }
+    }
+
+    newest
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow!("no ELF matches `{pattern}` in {}", dir.display()))
+}
+
+fn jobs(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    })
+}
+
+fn set_envs(cmd: &mut Command, env: &HashMap<String, String>) {
+    for (k, v) in sorted(env) {
+        cmd.env(k, v);
+    }
+}
+
+fn sorted(map: &HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut items = map.iter().collect::<Vec<_>>();
+    items.sort();
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_find_elf_newest_match() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        let old = dir.join("out/kernel-old.elf");
+        let new = dir.join("out/kernel-new.elf");
+        std::fs::write(&old, b"old").unwrap();
+        std::fs::write(&new, b"new").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(find_elf(dir, "out/*.elf").unwrap(), new);
+        assert_eq!(find_elf(dir, "out/kernel-old.elf").unwrap(), old);
+        let err = find_elf(dir, "*.bin").unwrap_err().to_string();
+        assert!(err.contains("no ELF matches `*.bin`"), "{err}");
+    }
+}
@@ -1,17 +1,29 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use anyhow::Context;
 
 use crate::{
     build::{
         cargo_builder::CargoBuilder,
-        config::{Cargo, Custom},
+        config::{Cargo, Custom, OUTPUT_FORMATS_ENV, OutputFormat},
     },
+    config::overrides::OVERRIDES_ENV,
     ctx::AppContext,
+    event::{Event, MESSAGE_FORMAT_ENV},
+    hooks::{HOOKS_ENV, HookStage, Hooks},
 };
 
 pub mod cargo_builder;
 pub mod config;
+pub mod cpio;
+pub mod disk;
+pub mod fat;
+pub mod gpt;
+pub mod initramfs;
+pub mod logging;
+pub mod make;
+pub mod size;
+pub mod target;
 
 pub enum CargoRunnerKind {
     Qemu {
@@ -26,24 +38,101 @@ pub enum CargoRunnerKind {
 
 impl AppContext {
     pub async fn build_with_config(&mut self, config: &config::BuildConfig) -> anyhow::Result<()> {
+        let (system, package) = match &config.system {
+            config::BuildSystem::Cargo(cargo) => ("cargo", Some(cargo.package.clone())),
+            config::BuildSystem::Custom(_) => ("custom", None),
+            config::BuildSystem::Make(_) => ("make", None),
+            config::BuildSystem::CMake(_) => ("cmake", None),
+        };
+        self.events.emit(Event::BuildStarted {
+            system: system.to_string(),
+            package,
+            profile: self.build_profile.clone(),
+        });
         match &config.system {
-            config::BuildSystem::Custom(custom) => self.build_custom(custom)?,
+            config::BuildSystem::Custom(custom) => self.build_custom(custom).await?,
             config::BuildSystem::Cargo(cargo) => {
                 self.cargo_build(cargo).await?;
             }
+            config::BuildSystem::Make(make) => self.build_make(make).await?,
+            config::BuildSystem::CMake(cmake) => self.build_cmake(cmake).await?,
         }
         Ok(())
     }
 
     pub async fn build(&mut self, config_path: Option<PathBuf>) -> anyhow::Result<()> {
         let build_config = self.prepare_build_config(config_path, false).await?;
-        println!("Build configuration: {:?}", build_config);
+        debug!("Build configuration: {:?}", build_config);
         self.build_with_config(&build_config).await
     }
 
-    pub fn build_custom(&mut self, config: &Custom) -> anyhow::Result<()> {
+    /// Pre-build hooks, then the initramfs the kernel may embed.
+    pub async fn pre_build(&mut self) -> anyhow::Result<()> {
+        self.run_hooks(HookStage::PreBuild)?;
+        self.build_initramfs().await
+    }
+
+    pub async fn build_custom(&mut self, config: &Custom) -> anyhow::Result<()> {
+        self.pre_build().await?;
         self.shell_run_cmd(&config.build_cmd)?;
-        Ok(())
+        self.handle_elf(
+            config.elf_path.clone().into(),
+            config.to_bin,
+            &config.output_formats,
+            &config.size_limits,
+        )
+        .await
+    }
+
+    /// Images, size report and post-build hooks for the ELF of a non-Cargo build.
+    async fn handle_elf(
+        &mut self,
+        elf: PathBuf,
+        to_bin: bool,
+        output_formats: &[OutputFormat],
+        size_limits: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.set_elf_path(elf).await;
+        if to_bin {
+            self.objcopy_output_bin()?;
+        }
+        self.objcopy_output_formats(output_formats)?;
+        self.build_configured_disk()?;
+        self.report_size(size_limits)?;
+        self.run_hooks(HookStage::PostBuild)
+    }
+
+    /// Reports the ELF size against the previous build and checks `limits`.
+    pub fn report_size(&self, limits: &HashMap<String, String>) -> anyhow::Result<()> {
+        let elf = self
+            .paths
+            .artifacts
+            .elf
+            .as_ref()
+            .ok_or(anyhow!("elf not exist"))?;
+        let data = std::fs::read(elf)?;
+        let report = size::SizeReport::from_elf(&data)?;
+
+        let build_dir = self.paths.build_dir();
+        let build_dir = build_dir.canonicalize().unwrap_or(build_dir);
+        let elf = elf.canonicalize().unwrap_or(elf.clone());
+        let report_path = size::report_path(&build_dir, &elf);
+
+        let previous = std::fs::read(&report_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<size::SizeReport>(&data).ok());
+        self.events.emit(Event::SizeReport {
+            report: report.clone(),
+            previous,
+        });
+
+        if let Some(parent) = report_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&report_path, serde_json::to_vec_pretty(&report)?)
+            .with_context(|| format!("can not save size report: {}", report_path.display()))?;
+
+        report.check_limits(limits)
     }
 
     pub async fn cargo_build(&mut self, config: &Cargo) -> anyhow::Result<()> {
@@ -96,7 +185,61 @@ impl AppContext {
             .map(normalize)
             .transpose()?;
 
-        let mut builder = CargoBuilder::run(self, config, build_config_path);
+        // The runner stages happen in `cargo-osrun`
+        let hooks = Hooks {
+            pre_build: vec![],
+            on_failure: vec![],
+            ..self.hooks.clone()
+        };
+        let hooks = serde_json::to_string(&hooks)?;
+
+        let initramfs = self
+            .build_config
+            .as_ref()
+            .is_some_and(|c| c.has_initramfs())
+            .then(|| self.initramfs_path());
+        let disk = self
+            .build_config
+            .as_ref()
+            .and_then(|c| c.disk.as_ref())
+            .map(serde_json::to_string)
+            .transpose()?;
+
+        let output_formats = match config.output_formats.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&config.output_formats)?),
+        };
+
+        let size_limits = match config.size_limits.is_empty() {
+            true => None,
+            false => Some(serde_json::to_string(&config.size_limits)?),
+        };
+        let overrides = match self.overrides.is_empty() {
+            true => None,
+            false => Some(self.overrides.to_env()?),
+        };
+
+        let json = self.events.is_json();
+
+        let mut builder = CargoBuilder::run(self, config, build_config_path).env(HOOKS_ENV, hooks);
+        if let Some(initramfs) = initramfs {
+            builder = builder.env(initramfs::INITRAMFS_ENV, initramfs.display().to_string());
+        }
+        if let Some(disk) = disk {
+            builder = builder.env(disk::DISK_ENV, disk);
+        }
+        if let Some(formats) = output_formats {
+            builder = builder.env(OUTPUT_FORMATS_ENV, formats);
+        }
+        if let Some(limits) = size_limits {
+            builder = builder.env(size::SIZE_LIMITS_ENV, limits);
+        }
+        if let Some(overrides) = overrides {
+            builder = builder.env(OVERRIDES_ENV, overrides);
+        }
+        if json {
+            builder = builder.env(MESSAGE_FORMAT_ENV, "json");
+        }
 
         builder = builder.arg("--");
 
@@ -125,7 +268,6 @@ impl AppContext {
                 if *dtb_dump {
                     builder = builder.arg("--dtb-dump");
                 }
-                builder = builder.arg("qemu");
             }
             CargoRunnerKind::Uboot { uboot_config } => {
                 if let Some(cfg) = uboot_config {
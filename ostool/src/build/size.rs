@@ -0,0 +1,370 @@
+//! ELF size report and size budgets.
+//!
+//! After a build the allocated sections and loadable segments of the kernel
+//! ELF are printed together with the change since the previous build. The
+//! previous report is kept as JSON under the build directory.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use byte_unit::{Byte, UnitType};
+use colored::Colorize;
+use object::{Object, ObjectSection, ObjectSegment, SectionFlags, SegmentFlags, elf};
+use serde::{Deserialize, Serialize};
+
+/// Environment variable carrying the `size_limits` from `ostool` to `cargo-osrun`
+pub const SIZE_LIMITS_ENV: &str = "OSTOOL_SIZE_LIMITS";
+
+/// Limit key checked against the sum of all loadable segments
+pub const TOTAL_KEY: &str = "total";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SectionSize {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SegmentSize {
+    /// `LOAD0`, `LOAD1`, ... in program header order
+    pub name: String,
+    /// `R`, `W`, `X` permission flags
+    pub flags: String,
+    pub address: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub sections: Vec<SectionSize>,
+    pub segments: Vec<SegmentSize>,
+}
+
+impl SizeReport {
+    /// Collects allocated sections and `PT_LOAD` segments of an ELF file.
+    pub fn from_elf(data: &[u8]) -> anyhow::Result<Self> {
+        let file = object::File::parse(data).context("can not parse ELF")?;
+
+        let sections = file
+            .sections()
+            .filter(|s| match s.flags() {
+                SectionFlags::Elf { sh_flags } => sh_flags & elf::SHF_ALLOC as u64 != 0,
+                _ => true,
+            })
+            .filter(|s| s.size() > 0)
+            .map(|s| SectionSize {
+                name: s.name().unwrap_or("?").to_string(),
+                address: s.address(),
+                size: s.size(),
+            })
+            .collect();
+
+        let segments = file
+            .segments()
+            .enumerate()
+            .map(|(i, s)| {
+                let flags = match s.flags() {
+                    SegmentFlags::Elf { p_flags } => {
+                        [(elf::PF_R, 'R'), (elf::PF_W, 'W'), (elf::PF_X, 'X')]
+                            .iter()
+                            .map(|(bit, c)| if p_flags & bit != 0 { *c } else { '-' })
+                            .collect()
+                    }
+                    _ => String::new(),
+                };
+                SegmentSize {
+                    name: format!("LOAD{i}"),
+                    flags,
+                    address: s.address(),
+                    file_size: s.file_range().1,
+                    mem_size: s.size(),
+                }
+            })
+            .collect();
+
+        Ok(Self { sections, segments })
+    }
+
+    /// Memory occupied by all loadable segments
+    pub fn total(&self) -> u64 {
+        self.segments.iter().map(|s| s.mem_size).sum()
+    }
+
+    /// Size for a limit key: `total`, or a section name with or without the leading dot.
+    pub fn size_of(&self, key: &str) -> Option<u64> {
+        if key == TOTAL_KEY {
+            return Some(self.total());
+        }
+        let name = key.trim_start_matches('.');
+        let sizes = self
+            .sections
+            .iter()
+            .filter(|s| s.name.trim_start_matches('.') == name)
+            .map(|s| s.size)
+            .collect::<Vec<_>>();
+        if sizes.is_empty() {
+            None
+        } else {
+            Some(sizes.iter().sum())
+        }
+    }
+
+    /// The section and segment tables, with the change since `previous`.
+    pub fn render(&self, previous: Option<&SizeReport>) -> String {
+        let mut out = String::new();
+        let name_width = self
+            .sections
+            .iter()
+            .map(|s| s.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(8);
+
+        let _ = writeln!(out, "{}", "ELF size report:".bold().purple());
+        let _ = writeln!(
+            out,
+            "  {:<name_width$}  {:>18}  {:>12}  change",
+            "section", "address", "size"
+        );
+        for section in &self.sections {
+            let old = previous.map(|p| {
+                p.sections
+                    .iter()
+                    .find(|s| s.name == section.name)
+                    .map(|s| s.size)
+            });
+            let _ = writeln!(
+                out,
+                "  {:<name_width$}  {:#018x}  {:>12}  {}",
+                section.name,
+                section.address,
+                human(section.size),
+                change(section.size, old)
+            );
+        }
+        if let Some(previous) = previous {
+            for section in &previous.sections {
+                if !self.sections.iter().any(|s| s.name == section.name) {
+                    let _ = writeln!(
+                        out,
+                        "  {:<name_width$}  {:>18}  {:>12}  {}",
+                        section.name,
+                        "",
+                        "removed",
+                        change(0, Some(Some(section.size)))
+                    );
+                }
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "  {:<name_width$}  {:>5}  {:>18}  {:>12}  {:>12}  change",
+            "segment", "flags", "address", "file size", "mem size"
+        );
+        for segment in &self.segments {
+            let old = previous.map(|p| {
+                p.segments
+                    .iter()
+                    .find(|s| s.name == segment.name)
+                    .map(|s| s.mem_size)
+            });
+            let _ = writeln!(
+                out,
+                "  {:<name_width$}  {:>5}  {:#018x}  {:>12}  {:>12}  {}",
+                segment.name,
+                segment.flags,
+                segment.address,
+                human(segment.file_size),
+                human(segment.mem_size),
+                change(segment.mem_size, old)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "  {:<name_width$}  {:>5}  {:>18}  {:>12}  {:>12}  {}",
+            TOTAL_KEY,
+            "",
+            "",
+            "",
+            human(self.total()),
+            change(self.total(), previous.map(|p| Some(p.total())))
+        );
+        out
+    }
+
+    /// Fails if any entry of `limits` is exceeded. Keys are section names or `total`,
+    /// values human sizes such as `512K`.
+    pub fn check_limits(&self, limits: &HashMap<String, String>) -> anyhow::Result<()> {
+        let mut keys = limits.keys().collect::<Vec<_>>();
+        keys.sort();
+
+        let mut exceeded = Vec::new();
+        for key in keys {
+            let limit =
+                parse_size(&limits[key]).with_context(|| format!("invalid size limit `{key}`"))?;
+            let Some(size) = self.size_of(key) else {
+                warn!("size limit `{key}`: no such section in ELF");
+                continue;
+            };
+            if size > limit {
+                exceeded.push(format!(
+                    "{key}: {} > {} (over by {})",
+                    human(size),
+                    human(limit),
+                    human(size - limit)
+                ));
+            }
+        }
+
+        if !exceeded.is_empty() {
+            bail!("size limits exceeded:\n  {}", exceeded.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a human size. A bare `K`, `M` or `G` suffix is binary as in linker
+/// scripts (`512K` = 512 KiB); `KB`, `MB`, ... keep their decimal meaning.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let s = match s.chars().last() {
+        Some(c) if matches!(c.to_ascii_uppercase(), 'K' | 'M' | 'G' | 'T') => {
+            format!("{}{}iB", &s[..s.len() - 1], c.to_ascii_uppercase())
+        }
+        _ => s.to_string(),
+    };
+    let byte = Byte::parse_str(&s, true).map_err(|e| anyhow!("invalid size `{s}`: {e}"))?;
+    Ok(byte.as_u64())
+}
+
+/// Where the report of `elf` from the previous build is kept.
+pub fn report_path(build_dir: &Path, elf: &Path) -> PathBuf {
+    let relative = elf.strip_prefix(build_dir).unwrap_or(elf);
+    let name = relative
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(s) => Some(s.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    build_dir.join("ostool").join("size").join(name + ".json")
+}
+
+/// Limits handed over by `ostool` in [`SIZE_LIMITS_ENV`]
+pub fn limits_from_env() -> anyhow::Result<HashMap<String, String>> {
+    match std::env::var(SIZE_LIMITS_ENV) {
+        Ok(json) => {
+            serde_json::from_str(&json).map_err(|e| anyhow!("invalid `{SIZE_LIMITS_ENV}`: {e}"))
+        }
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+fn human(size: u64) -> String {
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    format!(
+        "{:.2}",
+        Byte::from(size).get_appropriate_unit(UnitType::Binary)
+    )
+}
+
+fn change(size: u64, old: Option<Option<u64>>) -> String {
+    match old {
+        None => String::new(),
+        Some(None) => "new".yellow().to_string(),
+        Some(Some(old)) if old == size => "-".to_string(),
+        Some(Some(old)) if size > old => format!("+{}", human(size - old)).red().to_string(),
+        Some(Some(old)) => format!("-{}", human(old - size)).green().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> SizeReport {
+        SizeReport {
+            sections: vec![
+                SectionSize {
+                    name: ".text".into(),
+                    address: 0x4008_0000,
+                    size: 600 * 1024,
+                },
+                SectionSize {
+                    name: ".bss".into(),
+                    address: 0x4010_0000,
+                    size: 4096,
+                },
+            ],
+            segments: vec![SegmentSize {
+                name: "LOAD0".into(),
+                flags: "R-X".into(),
+                address: 0x4008_0000,
+                file_size: 600 * 1024,
+                mem_size: 604 * 1024,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("2m").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("512KiB").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("512KB").unwrap(), 512_000);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let report = report();
+        let limits = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        assert!(
+            report
+                .check_limits(&limits(&[("text", "1M"), ("total", "1M")]))
+                .is_ok()
+        );
+
+        let err = report
+            .check_limits(&limits(&[
+                ("text", "512K"),
+                (".bss", "8K"),
+                ("total", "600K"),
+            ]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("text: 600.00 KiB > 512.00 KiB"), "{err}");
+        assert!(err.contains("total"), "{err}");
+        assert!(!err.contains("bss"), "{err}");
+    }
+
+    #[test]
+    fn test_report_path() {
+        let path = report_path(
+            Path::new("/work/target"),
+            Path::new("/work/target/aarch64-unknown-none/release/kernel"),
+        );
+        assert_eq!(
+            path,
+            Path::new("/work/target/ostool/size/aarch64-unknown-none-release-kernel.json")
+        );
+    }
+}
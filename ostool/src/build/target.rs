@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use object::Architecture;
+
+/// A cargo `--target`, either a built-in triple or a custom JSON spec file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetSpec {
+    /// Target name, used by cargo for the output directory
+    /// e.g. `aarch64-unknown-none` or `aarch64-myos` for `targets/aarch64-myos.json`
+    pub name: String,
+    /// Absolute path of the spec file for custom targets
+    pub path: Option<PathBuf>,
+    /// Architecture from the spec's `arch` field or the triple
+    pub arch: Option<Architecture>,
+}
+
+impl TargetSpec {
+    /// Resolves `target` from the build config. Spec paths are relative to `workdir`.
+    pub fn resolve(target: &str, workdir: &Path) -> anyhow::Result<Self> {
+        if !target.ends_with(".json") {
+            return Ok(Self {
+                name: target.to_string(),
+                path: None,
+                arch: arch_from_name(target.split('-').next().unwrap_or_default()),
+            });
+        }
+
+        let path = workdir.join(target);
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("can not read target spec: {}", path.display()))?;
+        let spec: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("invalid target spec: {}", path.display()))?;
+
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow!("invalid target spec path: {}", path.display()))?;
+
+        let arch = spec
+            .get("arch")
+            .and_then(|a| a.as_str())
+            .and_then(arch_from_name);
+
+        Ok(Self {
+            name,
+            path: Some(path.canonicalize().unwrap_or(path)),
+            arch,
+        })
+    }
+
+    /// Value to pass to `cargo --target`
+    pub fn cargo_arg(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Whether the toolchain used in `workdir` ships a prebuilt std for this target.
+    /// Custom specs never have one.
+    pub fn has_prebuilt_std(&self, workdir: &Path) -> bool {
+        if self.path.is_some() {
+            return false;
+        }
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let Ok(output) = std::process::Command::new(rustc)
+            .args(["--print", "sysroot"])
+            .current_dir(workdir)
+            .output()
+        else {
+            // Can not tell, let cargo report the real error.
+            return true;
+        };
+
+        let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        PathBuf::from(sysroot)
+            .join("lib")
+            .join("rustlib")
+            .join(&self.name)
+            .join("lib")
+            .is_dir()
+    }
+}
+
+/// Maps a target spec `arch` or the first component of a triple to [`Architecture`].
+pub fn arch_from_name(arch: &str) -> Option<Architecture> {
+    let arch = match arch {
+        "aarch64" | "arm64" => Architecture::Aarch64,
+        "x86_64" => Architecture::X86_64,
+        "x86" | "i386" | "i586" | "i686" => Architecture::I386,
+        "loongarch64" => Architecture::LoongArch64,
+        "mips" | "mipsel" => Architecture::Mips,
+        "mips64" | "mips64el" => Architecture::Mips64,
+        "powerpc" => Architecture::PowerPc,
+        "powerpc64" | "powerpc64le" => Architecture::PowerPc64,
+        a if a.starts_with("riscv64") => Architecture::Riscv64,
+        a if a.starts_with("riscv32") => Architecture::Riscv32,
+        a if a.starts_with("arm") || a.starts_with("thumb") => Architecture::Arm,
+        _ => return None,
+    };
+    Some(arch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_triple() {
+        let spec = TargetSpec::resolve("riscv64gc-unknown-none-elf", Path::new(".")).unwrap();
+        assert_eq!(spec.name, "riscv64gc-unknown-none-elf");
+        assert_eq!(spec.cargo_arg(), "riscv64gc-unknown-none-elf");
+        assert_eq!(spec.arch, Some(Architecture::Riscv64));
+    }
+
+    #[test]
+    fn test_json_spec() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("targets")).unwrap();
+        std::fs::write(
+            dir.join("targets/aarch64-myos.json"),
+            r#"{"arch": "aarch64", "llvm-target": "aarch64-unknown-none"}"#,
+        )
+        .unwrap();
+
+        let spec = TargetSpec::resolve("targets/aarch64-myos.json", dir).unwrap();
+        assert_eq!(spec.name, "aarch64-myos");
+        assert_eq!(spec.arch, Some(Architecture::Aarch64));
+        assert!(spec.cargo_arg().ends_with("aarch64-myos.json"));
+        assert!(!spec.has_prebuilt_std(dir));
+    }
+}
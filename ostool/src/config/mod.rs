@@ -0,0 +1,372 @@
+//! Loading of ostool's own TOML config files (`.build.toml`, `.qemu.toml`,
+//! `.uboot.toml`).
+//!
+//! A config file may be composed from other files with the top-level keys:
+//!
+//! - `extends = "../common/board-base.toml"`: a single base file.
+//! - `include = ["a.toml", "https://github.com/org/repo/blob/main/b.toml"]`:
+//!   extra fragments merged after the base.
+//!
+//! Layers are merged in the order `extends`, `include`, then the file itself,
+//! so the file that names the others always wins. Tables are merged
+//! recursively, arrays are concatenated and scalars are replaced. Relative
+//! paths are resolved against the directory (or URL) of the file naming them.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+pub mod overrides;
+pub mod remote;
+pub mod schema;
+pub mod validate;
+pub mod vars;
+
+/// Key naming the base file of a config
+pub const EXTENDS_KEY: &str = "extends";
+/// Key naming extra fragments of a config
+pub const INCLUDE_KEY: &str = "include";
+
+/// Location of one config layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Path(PathBuf),
+    Url(String),
+}
+
+impl ConfigSource {
+    /// Resolves `reference` relative to this source.
+    pub fn join(&self, reference: &str) -> ConfigSource {
+        if remote::is_url(reference) {
+            return ConfigSource::Url(reference.to_string());
+        }
+
+        match self {
+            ConfigSource::Path(path) => {
+                let reference = Path::new(reference);
+                if reference.is_absolute() {
+                    ConfigSource::Path(reference.to_path_buf())
+                } else {
+                    let dir = path.parent().unwrap_or(Path::new("."));
+                    ConfigSource::Path(dir.join(reference))
+                }
+            }
+            ConfigSource::Url(url) => {
+                let base = url.rsplit_once('/').map(|(b, _)| b).unwrap_or(url);
+                let mut base = base.to_string();
+                let mut reference = reference;
+                while let Some(rest) = reference.strip_prefix("../") {
+                    if let Some((parent, _)) = base.rsplit_once('/') {
+                        base = parent.to_string();
+                    }
+                    reference = rest;
+                }
+                let reference = reference.trim_start_matches("./");
+                ConfigSource::Url(format!("{base}/{reference}"))
+            }
+        }
+    }
+
+    async fn read(&self) -> anyhow::Result<String> {
+        match self {
+            ConfigSource::Path(path) => tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("can not open config file: {}", path.display())),
+            ConfigSource::Url(url) => remote::fetch_text(url).await,
+        }
+    }
+
+    fn identity(&self) -> String {
+        match self {
+            ConfigSource::Path(path) => path
+                .canonicalize()
+                .unwrap_or_else(|_| path.clone())
+                .display()
+                .to_string(),
+            ConfigSource::Url(url) => remote::convert_to_raw_url(url),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Path(path) => write!(f, "{}", path.display()),
+            ConfigSource::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// A config file with all its `extends`/`include` layers merged
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    /// The file that was requested
+    pub path: PathBuf,
+    /// Merged content, without the `extends`/`include` keys
+    pub table: Table,
+    /// Every layer that contributed, in merge order; the last one is `path`
+    pub sources: Vec<ConfigSource>,
+}
+
+impl LoadedConfig {
+    /// Whether the file pulls in other layers
+    pub fn is_layered(&self) -> bool {
+        self.sources.len() > 1
+    }
+
+    /// Substitutes the `${...}` variables in every value.
+    pub fn substitute(&mut self, vars: &vars::Variables) -> anyhow::Result<()> {
+        let mut value = Value::Table(std::mem::take(&mut self.table));
+        let res = vars::substitute_value(&mut value, vars, &self.path);
+        if let Value::Table(table) = value {
+            self.table = table;
+        }
+        res
+    }
+
+    /// Deserializes the merged content.
+    pub fn parse<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Value::Table(self.table.clone())
+            .try_into::<T>()
+            .with_context(|| format!("invalid config file: {}", self.path.display()))
+    }
+}
+
+/// Loads `path` and every layer it references.
+pub async fn load(path: &Path) -> anyhow::Result<LoadedConfig> {
+    let mut sources = Vec::new();
+    let mut stack = Vec::new();
+    let table = load_source(
+        ConfigSource::Path(path.to_path_buf()),
+        &mut stack,
+        &mut sources,
+    )
+    .await?;
+    Ok(LoadedConfig {
+        path: path.to_path_buf(),
+        table,
+        sources,
+    })
+}
+
+/// Returns `true` if `content` names other layers with `extends`/`include`.
+pub fn uses_layers(content: &str) -> bool {
+    content
+        .parse::<Table>()
+        .map(|t| t.contains_key(EXTENDS_KEY) || t.contains_key(INCLUDE_KEY))
+        .unwrap_or(false)
+}
+
+fn load_source<'a>(
+    source: ConfigSource,
+    stack: &'a mut Vec<String>,
+    sources: &'a mut Vec<ConfigSource>,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<Table>> + 'a>> {
+    Box::pin(async move {
+        let identity = source.identity();
+        if stack.contains(&identity) {
+            bail!(
+                "config files extend each other in a cycle: {} -> {}",
+                stack.join(" -> "),
+                identity
+            );
+        }
+
+        let content = source.read().await?;
+        let mut table: Table = content
+            .parse()
+            .with_context(|| format!("invalid config file: {source}"))?;
+
+        let mut layers = Vec::new();
+        if let Some(extends) = table.remove(EXTENDS_KEY) {
+            match extends {
+                Value::String(s) => layers.push(s),
+                _ => bail!("{source}: `{EXTENDS_KEY}` must be a string"),
+            }
+        }
+        if let Some(include) = table.remove(INCLUDE_KEY) {
+            match include {
+                Value::String(s) => layers.push(s),
+                Value::Array(items) => {
+                    for item in items {
+                        match item {
+                            Value::String(s) => layers.push(s),
+                            _ => bail!("{source}: `{INCLUDE_KEY}` must be a list of strings"),
+                        }
+                    }
+                }
+                _ => bail!("{source}: `{INCLUDE_KEY}` must be a string or a list of strings"),
+            }
+        }
+
+        stack.push(identity);
+        let mut merged = Table::new();
+        for layer in layers {
+            let layer_source = source.join(&layer);
+            debug!("{source}: loading layer {layer_source}");
+            let layer_table = load_source(layer_source.clone(), stack, sources).await?;
+            merge_table(&mut merged, layer_table, &layer_source, "")?;
+        }
+        stack.pop();
+
+        merge_table(&mut merged, table, &source, "")?;
+        sources.push(source);
+        Ok(merged)
+    })
+}
+
+/// Deep merges `overlay` into `base`. `source` and `prefix` only name the
+/// offending file and key in errors.
+pub fn merge_table(
+    base: &mut Table,
+    overlay: Table,
+    source: &ConfigSource,
+    prefix: &str,
+) -> anyhow::Result<()> {
+    for (key, value) in overlay {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        match base.get_mut(&key) {
+            None => {
+                base.insert(key, value);
+            }
+            Some(existing) => match (existing, value) {
+                (Value::Table(existing), Value::Table(value)) => {
+                    merge_table(existing, value, source, &path)?;
+                }
+                (Value::Array(existing), Value::Array(value)) => {
+                    existing.extend(value);
+                }
+                (existing, value) if existing.type_str() == value.type_str() => {
+                    *existing = value;
+                }
+                (existing, value) => {
+                    bail!(
+                        "{source}: can not merge key `{path}`: {} overrides {} from a base config",
+                        value.type_str(),
+                        existing.type_str()
+                    );
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(s: &str) -> Table {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_merge_tables_and_arrays() {
+        let source = ConfigSource::Path("child.toml".into());
+        let mut base = table(
+            r#"
+args = ["-nographic"]
+uefi = false
+[net]
+interface = "eth0"
+"#,
+        );
+        let overlay = table(
+            r#"
+args = ["-smp", "2"]
+uefi = true
+[net]
+board_ip = "10.0.0.2"
+"#,
+        );
+        merge_table(&mut base, overlay, &source, "").unwrap();
+
+        assert_eq!(
+            base["args"].as_array().unwrap().len(),
+            3,
+            "arrays are concatenated"
+        );
+        assert_eq!(base["uefi"].as_bool(), Some(true));
+        assert_eq!(base["net"]["interface"].as_str(), Some("eth0"));
+        assert_eq!(base["net"]["board_ip"].as_str(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_merge_error_names_file_and_key() {
+        let source = ConfigSource::Path("board.toml".into());
+        let mut base = table("[net]\ninterface = \"eth0\"\n");
+        let overlay = table("net = \"eth0\"\n");
+        let err = merge_table(&mut base, overlay, &source, "")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("board.toml"), "{err}");
+        assert!(err.contains("`net`"), "{err}");
+    }
+
+    #[test]
+    fn test_source_join() {
+        let path = ConfigSource::Path("/work/os/.build.toml".into());
+        assert_eq!(
+            path.join("../common/base.toml"),
+            ConfigSource::Path("/work/os/../common/base.toml".into())
+        );
+
+        let url = ConfigSource::Url("https://example.com/cfg/boards/rk3588.toml".into());
+        assert_eq!(
+            url.join("../common.toml"),
+            ConfigSource::Url("https://example.com/cfg/common.toml".into())
+        );
+        assert_eq!(
+            url.join("./uart.toml"),
+            ConfigSource::Url("https://example.com/cfg/boards/uart.toml".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("common")).unwrap();
+        std::fs::write(
+            dir.join("common/base.toml"),
+            "serial = \"/dev/ttyUSB0\"\nbaud_rate = \"115200\"\nsuccess_regex = [\"ok\"]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("common/net.toml"), "[net]\ninterface = \"eth0\"\n").unwrap();
+        std::fs::write(
+            dir.join(".uboot.toml"),
+            "extends = \"common/base.toml\"\ninclude = [\"common/net.toml\"]\nbaud_rate = \"1500000\"\n",
+        )
+        .unwrap();
+
+        let loaded = load(&dir.join(".uboot.toml")).await.unwrap();
+        assert!(loaded.is_layered());
+        assert_eq!(loaded.sources.len(), 3);
+        assert!(!loaded.table.contains_key(EXTENDS_KEY));
+        assert_eq!(loaded.table["baud_rate"].as_str(), Some("1500000"));
+        assert_eq!(loaded.table["serial"].as_str(), Some("/dev/ttyUSB0"));
+        assert_eq!(loaded.table["net"]["interface"].as_str(), Some("eth0"));
+
+        std::fs::write(
+            dir.join("common/base.toml"),
+            "extends = \"../.uboot.toml\"\n",
+        )
+        .unwrap();
+        let err = load(&dir.join(".uboot.toml"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("cycle"), "{err}");
+    }
+}
@@ -0,0 +1,356 @@
+//! Command-line overrides of config keys, applied on top of the loaded
+//! configs and never written back to disk.
+//!
+//! - `--set qemu.uefi=true` replaces a value.
+//! - `--set build.system.Cargo.features+=foo` appends to an array.
+//! - `OSTOOL_QEMU__UEFI=true` and `OSTOOL_BUILD__SYSTEM__CARGO__FEATURES__APPEND=foo`
+//!   do the same from the environment, keys match case-insensitively.
+//!
+//! Values are TOML (`true`, `3`, `["-s", "-S"]`), anything else is a string.
+//! They are checked against the schema of the config before anything runs.
+
+use anyhow::Context;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::config::{schema::SchemaKind, validate::check_value};
+
+/// Environment variable handing the overrides from `ostool` to `cargo-osrun`
+pub const OVERRIDES_ENV: &str = "OSTOOL_OVERRIDES";
+
+const ENV_PREFIX: &str = "OSTOOL_";
+const ENV_SEPARATOR: &str = "__";
+const ENV_APPEND: &str = "APPEND";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub kind: SchemaKind,
+    /// Keys from the root of the config
+    pub keys: Vec<String>,
+    pub append: bool,
+    pub value: toml::Value,
+}
+
+impl Override {
+    /// Parses and type checks `config.key.path=value` or `config.key.path+=value`.
+    pub fn parse(spec: &str) -> anyhow::Result<Override> {
+        let (key, raw) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid override `{spec}`, expected `KEY=VALUE`"))?;
+        let (key, append) = match key.strip_suffix('+') {
+            Some(key) => (key, true),
+            None => (key, false),
+        };
+        let keys = key.trim().split('.').collect::<Vec<_>>();
+        Self::resolve(&keys, append, raw, false)
+            .with_context(|| format!("invalid override `{spec}`"))
+    }
+
+    /// `OSTOOL_QEMU__UEFI=true` style variable, `None` if `name` is not an override.
+    fn from_env_var(name: &str, raw: &str) -> Option<anyhow::Result<Override>> {
+        let rest = name.strip_prefix(ENV_PREFIX)?;
+        let mut keys = rest.split(ENV_SEPARATOR).collect::<Vec<_>>();
+        if keys.len() < 2 || kind_of(keys[0]).is_none() {
+            return None;
+        }
+        let append = keys.last() == Some(&ENV_APPEND);
+        if append {
+            keys.pop();
+        }
+        Some(
+            Self::resolve(&keys, append, raw, true)
+                .with_context(|| format!("invalid override `{name}={raw}`")),
+        )
+    }
+
+    fn resolve(keys: &[&str], append: bool, raw: &str, ignore_case: bool) -> anyhow::Result<Self> {
+        let (config, keys) = keys.split_first().ok_or_else(|| anyhow!("empty key"))?;
+        let kind = kind_of(config).ok_or_else(|| {
+            anyhow!("unknown config `{config}`, expected `build`, `qemu` or `uboot`")
+        })?;
+        let root = kind.schema()?;
+
+        let mut schema = &root;
+        let mut names = vec![];
+        for key in keys {
+            if key.is_empty() {
+                bail!("empty key");
+            }
+            let (name, child) =
+                child(&root, schema, key, ignore_case).ok_or_else(|| match names.is_empty() {
+                    true => anyhow!("unknown key `{key}` in {}", kind.config_file()),
+                    false => anyhow!("unknown key `{key}` in `{}`", names.join(".")),
+                })?;
+            names.push(name);
+            schema = child;
+        }
+        if names.is_empty() {
+            bail!("a key is needed after `{config}`");
+        }
+        let path = names.join(".");
+
+        let value = match append {
+            false => typed(&root, schema, raw, &path)?,
+            true => {
+                let items = array_items(&root, schema)
+                    .ok_or_else(|| anyhow!("`{path}` is not an array, `+=` appends to arrays"))?;
+                match parse_toml(raw) {
+                    Some(toml::Value::Array(values)) => {
+                        for (i, v) in values.iter().enumerate() {
+                            let errors = check_value(&root, items, &serde_json::to_value(v)?, "");
+                            if let Some(e) = errors.first() {
+                                bail!("`{path}` item {i}: {e}");
+                            }
+                        }
+                        toml::Value::Array(values)
+                    }
+                    _ => toml::Value::Array(vec![typed(&root, items, raw, &path)?]),
+                }
+            }
+        };
+
+        Ok(Override {
+            kind,
+            keys: names,
+            append,
+            value,
+        })
+    }
+
+    /// `qemu.uefi=true`, parsed back by [`Override::parse`]
+    pub fn spec(&self) -> String {
+        let op = if self.append { "+=" } else { "=" };
+        let value = match &self.value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Array(items) if self.append && items.len() == 1 => match &items[0] {
+                toml::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            },
+            v => v.to_string(),
+        };
+        format!(
+            "{}.{}{op}{value}",
+            format!("{:?}", self.kind).to_lowercase(),
+            self.keys.join(".")
+        )
+    }
+
+    fn apply(&self, table: &mut toml::Table) -> anyhow::Result<()> {
+        let (last, parents) = self.keys.split_last().expect("override without keys");
+        let mut table = table;
+        for (i, key) in parents.iter().enumerate() {
+            let entry = table
+                .entry(key.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+            table = entry
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("`{}` is not a table", self.keys[..=i].join(".")))?;
+        }
+        match (self.append, table.get_mut(last)) {
+            (true, Some(toml::Value::Array(items))) => {
+                if let toml::Value::Array(values) = &self.value {
+                    items.extend(values.iter().cloned());
+                }
+            }
+            _ => {
+                table.insert(last.clone(), self.value.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Overrides in the order they apply, the environment first, then `--set`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides(pub Vec<Override>);
+
+impl Overrides {
+    pub fn parse(specs: &[String]) -> anyhow::Result<Overrides> {
+        specs
+            .iter()
+            .map(|s| Override::parse(s))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Overrides)
+    }
+
+    /// The overrides handed over in [`OVERRIDES_ENV`], or else the
+    /// `OSTOOL_<CONFIG>__<KEY>` variables.
+    pub fn from_env() -> anyhow::Result<Overrides> {
+        if let Ok(json) = std::env::var(OVERRIDES_ENV) {
+            let specs: Vec<String> = serde_json::from_str(&json)
+                .map_err(|e| anyhow!("invalid `{OVERRIDES_ENV}`: {e}"))?;
+            return Self::parse(&specs);
+        }
+
+        let mut vars = std::env::vars().collect::<Vec<_>>();
+        vars.sort();
+        vars.iter()
+            .filter_map(|(name, value)| Override::from_env_var(name, value))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Overrides)
+    }
+
+    pub fn extend(&mut self, other: Overrides) {
+        self.0.extend(other.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Value of [`OVERRIDES_ENV`] for the runner
+    pub fn to_env(&self) -> anyhow::Result<String> {
+        let specs = self.0.iter().map(Override::spec).collect::<Vec<_>>();
+        Ok(serde_json::to_string(&specs)?)
+    }
+
+    /// `config` with the overrides of `kind` applied.
+    pub fn apply<T: Serialize + DeserializeOwned>(
+        &self,
+        kind: SchemaKind,
+        config: T,
+    ) -> anyhow::Result<T> {
+        let overrides = self.0.iter().filter(|o| o.kind == kind).collect::<Vec<_>>();
+        if overrides.is_empty() {
+            return Ok(config);
+        }
+
+        let mut value = toml::Value::try_from(&config)?;
+        let table = value
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{} is not a table", kind.config_file()))?;
+        for o in overrides {
+            info!("Override {}", o.spec());
+            o.apply(table)?;
+        }
+        value
+            .try_into()
+            .with_context(|| format!("invalid {} after overrides", kind.config_file()))
+    }
+}
+
+fn kind_of(name: &str) -> Option<SchemaKind> {
+    match name.to_lowercase().as_str() {
+        "build" => Some(SchemaKind::Build),
+        "qemu" => Some(SchemaKind::Qemu),
+        "uboot" => Some(SchemaKind::Uboot),
+        _ => None,
+    }
+}
+
+fn parse_toml(raw: &str) -> Option<toml::Value> {
+    format!("v = {raw}")
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+}
+
+/// `raw` as TOML if `schema` accepts it, else as a string.
+fn typed(root: &Value, schema: &Value, raw: &str, path: &str) -> anyhow::Result<toml::Value> {
+    let string = toml::Value::String(raw.to_string());
+    let mut errors = vec![];
+    for candidate in parse_toml(raw).into_iter().chain([string]) {
+        match check_value(root, schema, &serde_json::to_value(&candidate)?, "").first() {
+            None => return Ok(candidate),
+            Some(e) => errors.push(e.clone()),
+        }
+    }
+    bail!("`{path}`: {}", errors[0])
+}
+
+fn deref<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema
+        .get("$ref")
+        .and_then(|r| r.as_str())
+        .and_then(|r| r.strip_prefix('#'))
+        .and_then(|p| root.pointer(p))
+    {
+        Some(target) => deref(root, target),
+        None => schema,
+    }
+}
+
+fn branches(schema: &Value) -> impl Iterator<Item = &Value> {
+    ["oneOf", "anyOf"]
+        .into_iter()
+        .filter_map(|k| schema.get(k).and_then(|b| b.as_array()))
+        .flatten()
+}
+
+/// Schema of `key` in the table described by `schema`, with the key as spelled in the schema.
+fn child<'a>(
+    root: &'a Value,
+    schema: &'a Value,
+    key: &str,
+    ignore_case: bool,
+) -> Option<(String, &'a Value)> {
+    let schema = deref(root, schema);
+    if let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) {
+        let found = properties
+            .iter()
+            .find(|(name, _)| *name == key || (ignore_case && name.eq_ignore_ascii_case(key)));
+        if let Some((name, prop)) = found {
+            return Some((name.clone(), prop));
+        }
+    }
+    if let Some(found) = branches(schema).find_map(|b| child(root, b, key, ignore_case)) {
+        return Some(found);
+    }
+    match schema.get("additionalProperties") {
+        Some(extra @ Value::Object(_)) => Some((key.to_string(), extra)),
+        _ => None,
+    }
+}
+
+fn array_items<'a>(root: &'a Value, schema: &'a Value) -> Option<&'a Value> {
+    let schema = deref(root, schema);
+    if let Some(items) = schema.get("items") {
+        return Some(items);
+    }
+    branches(schema).find_map(|b| array_items(root, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{qemu::QemuConfig, uboot::UbootConfig};
+
+    #[test]
+    fn test_overrides() {
+        let overrides = Overrides::parse(&[
+            "qemu.uefi=true".into(),
+            "qemu.args+=-s".into(),
+            "qemu.args+=[\"-smp\", \"4\"]".into(),
+            "uboot.baud_rate=1500000".into(),
+        ])
+        .unwrap();
+
+        let qemu = QemuConfig {
+            args: vec!["-nographic".into()],
+            ..Default::default()
+        };
+        let qemu = overrides.apply(SchemaKind::Qemu, qemu).unwrap();
+        assert!(qemu.uefi);
+        assert_eq!(qemu.args, ["-nographic", "-s", "-smp", "4"]);
+
+        let uboot = overrides
+            .apply(SchemaKind::Uboot, UbootConfig::template())
+            .unwrap();
+        assert_eq!(uboot.baud_rate, "1500000");
+
+        let respec =
+            Overrides::parse(&overrides.0.iter().map(Override::spec).collect::<Vec<_>>()).unwrap();
+        assert_eq!(respec, overrides);
+
+        let env = Override::from_env_var("OSTOOL_BUILD__SYSTEM__CARGO__FEATURES__APPEND", "foo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(env.spec(), "build.system.Cargo.features+=foo");
+        assert!(Override::from_env_var("OSTOOL_CACHE_DIR", "/tmp").is_none());
+
+        assert!(Override::parse("qemu.uefi=maybe").is_err());
+        assert!(Override::parse("qemu.unknown=1").is_err());
+        assert!(Override::parse("qemu.uefi+=true").is_err());
+        assert!(Override::parse("board.uefi=true").is_err());
+    }
+}
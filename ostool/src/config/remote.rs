@@ -0,0 +1,371 @@
+//! Fetching of config files referenced by URL.
+//!
+//! Cargo `extra_config` downloads are kept in a persistent cache: the
+//! content is stored by its SHA-256 and each URL remembers the hash, ETag
+//! and Last-Modified of its last response for revalidation.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use reqwest::{RequestBuilder, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Returns `true` if `s` is an `http://` or `https://` URL.
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// Convert GitHub URL to raw content URL
+/// Supports:
+/// - https://github.com/user/repo/blob/branch/path/file -> https://raw.githubusercontent.com/user/repo/branch/path/file
+/// - https://raw.githubusercontent.com/... (already raw, no change)
+/// - Other URLs: no change
+pub fn convert_to_raw_url(url: &str) -> String {
+    // Already a raw URL
+    if url.contains("raw.githubusercontent.com") || url.contains("raw.github.com") {
+        return url.to_string();
+    }
+
+    // Convert github.com/user/repo/blob/... to raw.githubusercontent.com/user/repo/...
+    if url.contains("github.com") && url.contains("/blob/") {
+        return url
+            .replace("github.com", "raw.githubusercontent.com")
+            .replace("/blob/", "/");
+    }
+
+    // Not a GitHub URL or already in correct format
+    url.to_string()
+}
+
+fn request(url: &str) -> anyhow::Result<RequestBuilder> {
+    let download_url = convert_to_raw_url(url);
+    if download_url != url {
+        debug!("Converting GitHub URL to raw: {} -> {}", url, download_url);
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
+
+    let mut request = client.get(&download_url);
+
+    if download_url.contains("github.com") || download_url.contains("githubusercontent.com") {
+        // GitHub requires User-Agent
+        request = request.header("User-Agent", "ostool-cargo-downloader");
+    }
+    Ok(request)
+}
+
+/// Downloads `url` and returns the response body.
+pub async fn fetch_bytes(url: &str) -> anyhow::Result<Vec<u8>> {
+    let download_url = convert_to_raw_url(url);
+    let response = request(url)?
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to download from {}: {}", download_url, e))?;
+
+    if !response.status().is_success() {
+        bail!("HTTP error {}: {}", response.status(), download_url);
+    }
+
+    let content = response
+        .bytes()
+        .await
+        .map_err(|e| anyhow!("Failed to read response body: {}", e))?;
+
+    Ok(content.to_vec())
+}
+
+/// Downloads `url` as UTF-8 text.
+pub async fn fetch_text(url: &str) -> anyhow::Result<String> {
+    let content = fetch_bytes(url).await?;
+    String::from_utf8(content).map_err(|_| anyhow!("{url} is not valid UTF-8"))
+}
+
+/// `$OSTOOL_CACHE_DIR`, or `ostool` in the user cache directory
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("OSTOOL_CACHE_DIR") {
+        return dir.into();
+    }
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("ostool")
+}
+
+/// Where `fetch_cached` keeps its downloads
+fn downloads_dir() -> PathBuf {
+    cache_dir().join("downloads")
+}
+
+/// Removes all cached downloads and returns the removed directory. Only the
+/// downloads are removed, `$OSTOOL_CACHE_DIR` may be shared with other tools.
+pub fn clean_cache() -> anyhow::Result<Option<PathBuf>> {
+    let dir = downloads_dir();
+    if !dir.exists() {
+        return Ok(None);
+    }
+    std::fs::remove_dir_all(&dir).map_err(|e| anyhow!("can not remove {}: {e}", dir.display()))?;
+    Ok(Some(dir))
+}
+
+/// What the cache remembers about a URL
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    sha256: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Fails unless `data` hashes to `expected`.
+pub fn verify_sha256(what: &str, data: &[u8], expected: &str) -> anyhow::Result<()> {
+    let actual = sha256_hex(data);
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!("sha256 of {what} is {actual}, but {expected} is pinned");
+    }
+    Ok(())
+}
+
+/// Fetches `url` through the cache and returns the path of its content.
+///
+/// A cached copy is revalidated with ETag/If-Modified-Since, and used as is
+/// when the server can not be reached, in `offline` mode, or when it
+/// matches the `sha256` pin.
+pub async fn fetch_cached(
+    url: &str,
+    offline: bool,
+    sha256: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    fetch_cached_in(&downloads_dir(), url, offline, sha256).await
+}
+
+async fn fetch_cached_in(
+    dir: &Path,
+    url: &str,
+    offline: bool,
+    sha256: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let blob = |hash: &str| dir.join("blobs").join(format!("{hash}.toml"));
+    let entry_path = dir
+        .join("urls")
+        .join(format!("{}.json", sha256_hex(url.as_bytes())));
+
+    let use_blob = |hash: &str| -> anyhow::Result<PathBuf> {
+        let path = blob(hash);
+        let data = std::fs::read(&path)?;
+        if let Some(pin) = sha256 {
+            verify_sha256(url, &data, pin)?;
+        }
+        Ok(path)
+    };
+
+    if let Some(pin) = sha256
+        && blob(&pin.trim().to_ascii_lowercase()).exists()
+    {
+        return use_blob(&pin.trim().to_ascii_lowercase());
+    }
+
+    let cached = std::fs::read(&entry_path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<CacheEntry>(&data).ok())
+        .filter(|entry| blob(&entry.sha256).exists());
+
+    if offline {
+        let Some(entry) = cached else {
+            bail!("{url} is not cached, run once without `--offline`");
+        };
+        info!("Offline, using cached {url}");
+        return use_blob(&entry.sha256);
+    }
+
+    info!("Downloading cargo config from: {url}");
+    let mut request = request(url)?;
+    if let Some(entry) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(modified) = &entry.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, modified);
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) if response.status() == StatusCode::NOT_MODIFIED => {
+            if let Some(entry) = &cached {
+                debug!("{url} not modified");
+                return use_blob(&entry.sha256);
+            }
+            bail!("HTTP 304 for {url} without a cached copy");
+        }
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => match &cached {
+            Some(entry) => {
+                warn!(
+                    "HTTP {} for {url}, using the cached copy",
+                    response.status()
+                );
+                return use_blob(&entry.sha256);
+            }
+            None => bail!("HTTP error {}: {url}", response.status()),
+        },
+        Err(e) => match &cached {
+            Some(entry) => {
+                warn!("can not reach {url}: {e}, using the cached copy");
+                return use_blob(&entry.sha256);
+            }
+            None => bail!("Failed to download from {url}: {e}"),
+        },
+    };
+
+    let header_value = |name: header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header_value(header::ETAG);
+    let last_modified = header_value(header::LAST_MODIFIED);
+    let data = response
+        .bytes()
+        .await
+        .map_err(|e| anyhow!("Failed to read response body: {}", e))?;
+    if let Some(pin) = sha256 {
+        verify_sha256(url, &data, pin)?;
+    }
+
+    let entry = CacheEntry {
+        url: url.to_string(),
+        sha256: sha256_hex(&data),
+        etag,
+        last_modified,
+    };
+    let path = blob(&entry.sha256);
+    write_atomic(&path, &data)?;
+    write_atomic(&entry_path, &serde_json::to_vec_pretty(&entry)?)?;
+    debug!("Cached {url} as {}", path.display());
+    Ok(path)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path).map_err(|e| anyhow!("can not write {}: {e}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const BODY: &str = "[target.aarch64-unknown-none]\nrunner = \"cargo osrun\"\n";
+
+    /// Serves `BODY` with an ETag, and 304 to requests revalidating it.
+    /// Returns the URL and the number of requests served.
+    async fn serve() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/config.toml", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                let response = match request.contains("if-none-match: \"v1\"") {
+                    true => "HTTP/1.1 304 Not Modified\r\ncontent-length: 0\r\n\r\n".to_string(),
+                    false => format!(
+                        "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: {}\r\n\r\n{BODY}",
+                        BODY.len()
+                    ),
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (url, requests) = serve().await;
+
+        let path = fetch_cached_in(tmp.path(), &url, false, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), BODY);
+
+        // revalidated with the ETag, the server answers 304
+        let again = fetch_cached_in(tmp.path(), &url, false, None)
+            .await
+            .unwrap();
+        assert_eq!(again, path);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // a cached pin and offline mode never reach the server
+        let pin = sha256_hex(BODY.as_bytes());
+        let pinned = fetch_cached_in(tmp.path(), &url, false, Some(&pin)).await;
+        assert_eq!(pinned.unwrap(), path);
+        let offline = fetch_cached_in(tmp.path(), &url, true, None).await;
+        assert_eq!(offline.unwrap(), path);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cached_pin_mismatch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (url, _) = serve().await;
+
+        let pin = sha256_hex(b"something else");
+        let err = fetch_cached_in(tmp.path(), &url, false, Some(&pin))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("is pinned"), "{err}");
+        assert!(
+            !tmp.path().join("urls").exists(),
+            "a mismatching download is not cached"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cached_offline_without_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let err = fetch_cached_in(tmp.path(), "http://127.0.0.1:9/config.toml", true, None)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("is not cached"), "{err}");
+    }
+}
@@ -0,0 +1,244 @@
+//! JSON schemas of the config files, written next to them so editors that
+//! understand `#:schema` comments (taplo, Even Better TOML) validate them.
+
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+use jkconfig::data::app_data::default_schema_by_init;
+use object::Architecture;
+use serde_json::{Value, json};
+use tokio::fs;
+
+use crate::{
+    build::config::{BuildConfig, BuildSystem, Cargo},
+    run::{qemu::QemuConfig, uboot::UbootConfig},
+};
+
+/// Comment on the first line of a TOML file naming its schema
+pub const SCHEMA_DIRECTIVE: &str = "#:schema";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaKind {
+    Build,
+    Qemu,
+    Uboot,
+    All,
+}
+
+impl SchemaKind {
+    /// The kinds `self` stands for, `all` being every config type
+    pub fn expand(self) -> Vec<SchemaKind> {
+        match self {
+            SchemaKind::All => vec![SchemaKind::Build, SchemaKind::Qemu, SchemaKind::Uboot],
+            kind => vec![kind],
+        }
+    }
+
+    /// Default config file name
+    pub fn config_file(self) -> &'static str {
+        match self {
+            SchemaKind::Build => ".build.toml",
+            SchemaKind::Qemu => ".qemu.toml",
+            SchemaKind::Uboot => ".uboot.toml",
+            SchemaKind::All => unreachable!("`all` is not a config type"),
+        }
+    }
+
+    /// Schema file name, `.build.toml` -> `.build-schema.json`
+    pub fn schema_file(self) -> String {
+        schema_path(Path::new(self.config_file()))
+            .display()
+            .to_string()
+    }
+
+    /// Schema with an ostool title, version and an example config
+    pub fn schema(self) -> anyhow::Result<Value> {
+        let (schema, example) = match self {
+            SchemaKind::Build => (
+                schemars::schema_for!(BuildConfig),
+                serde_json::to_value(example_build())?,
+            ),
+            SchemaKind::Qemu => (
+                schemars::schema_for!(QemuConfig),
+                serde_json::to_value(QemuConfig::template(Some(Architecture::Aarch64)))?,
+            ),
+            SchemaKind::Uboot => (
+                schemars::schema_for!(UbootConfig),
+                serde_json::to_value(UbootConfig::template())?,
+            ),
+            SchemaKind::All => bail!("`all` is not a config type"),
+        };
+        let version = env!("CARGO_PKG_VERSION");
+        let mut schema = serde_json::to_value(schema)?;
+        if let Some(obj) = schema.as_object_mut() {
+            obj.insert(
+                "$id".into(),
+                json!(format!("urn:ostool:{version}:{}", self.schema_file())),
+            );
+            obj.insert(
+                "title".into(),
+                json!(format!("ostool {} ({version})", self.config_file())),
+            );
+            obj.insert("x-ostool-version".into(), json!(version));
+            obj.insert("examples".into(), json!([example]));
+        }
+        Ok(schema)
+    }
+
+    pub fn schema_json(self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&self.schema()?)? + "\n")
+    }
+}
+
+fn example_build() -> BuildConfig {
+    BuildConfig {
+        system: BuildSystem::Cargo(Cargo {
+            target: "aarch64-unknown-none".into(),
+            package: "kernel".into(),
+            to_bin: true,
+            ..Default::default()
+        }),
+        profiles: Default::default(),
+        hooks: Default::default(),
+        userspace: vec![],
+        initramfs: None,
+        disk: None,
+    }
+}
+
+/// `.build.toml` -> `.build-schema.json`
+pub fn schema_path(config: &Path) -> PathBuf {
+    default_schema_by_init(config)
+}
+
+/// Writes the schema of `kind` next to `config` and returns its path.
+pub async fn write_schema(kind: SchemaKind, config: &Path) -> anyhow::Result<PathBuf> {
+    let path = schema_path(config);
+    write_schema_to(kind, &path).await?;
+    Ok(path)
+}
+
+/// Writes the schema of `kind` to `path`, leaving the file alone if it is up to date.
+pub async fn write_schema_to(kind: SchemaKind, path: &Path) -> anyhow::Result<()> {
+    let content = kind.schema_json()?;
+    if fs::read_to_string(path).await.ok().as_deref() != Some(content.as_str()) {
+        fs::write(path, content).await?;
+    }
+    Ok(())
+}
+
+/// `content` with a `#:schema` line pointing to `schema`, replacing an existing one.
+pub fn with_schema_header(content: &str, schema: &str) -> String {
+    let body = match content.lines().next() {
+        Some(first) if first.starts_with(SCHEMA_DIRECTIVE) => {
+            content[first.len()..].trim_start_matches(['\r', '\n'])
+        }
+        _ => content,
+    };
+    format!("{SCHEMA_DIRECTIVE} {schema}\n\n{body}")
+}
+
+/// `content` with the `#:schema` line of `previous`, if it has one.
+pub fn keep_schema_header(previous: &str, content: String) -> String {
+    match previous.lines().next() {
+        Some(first) if first.starts_with(SCHEMA_DIRECTIVE) => {
+            let schema = first[SCHEMA_DIRECTIVE.len()..].trim();
+            with_schema_header(&content, schema)
+        }
+        _ => content,
+    }
+}
+
+/// Relative reference to the schema of `config`, as used in its header.
+pub fn schema_reference(config: &Path) -> String {
+    let name = schema_path(config)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("./{name}")
+}
+
+/// Adds or updates the `#:schema` line of `config` to point to `schema`.
+/// Returns whether the file changed.
+pub async fn annotate(config: &Path, schema: &Path) -> anyhow::Result<bool> {
+    let content = fs::read_to_string(config).await?;
+    let config = std::path::absolute(config)?;
+    let dir = config.parent().unwrap_or(Path::new("/"));
+    let reference = relative_reference(&std::path::absolute(schema)?, dir);
+    let annotated = with_schema_header(&content, &reference);
+    if annotated == content {
+        return Ok(false);
+    }
+    fs::write(&config, annotated).await?;
+    Ok(true)
+}
+
+/// `path` relative to `dir` with `/` separators, absolute if they share no root.
+fn relative_reference(path: &Path, dir: &Path) -> String {
+    let path_parts = path.components().collect::<Vec<_>>();
+    let dir_parts = dir.components().collect::<Vec<_>>();
+    let common = path_parts
+        .iter()
+        .zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 && path.is_absolute() {
+        return path.display().to_string();
+    }
+
+    let mut parts = vec![];
+    for part in &dir_parts[common..] {
+        if !matches!(part, Component::CurDir) {
+            parts.push("..".to_string());
+        }
+    }
+    if parts.is_empty() {
+        parts.push(".".to_string());
+    }
+    for part in &path_parts[common..] {
+        parts.push(part.as_os_str().to_string_lossy().to_string());
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_header() {
+        let config = Path::new("/work/.build.toml");
+        assert_eq!(schema_reference(config), "./.build-schema.json");
+
+        let added = with_schema_header("[system]\n", "./.build-schema.json");
+        assert_eq!(added, "#:schema ./.build-schema.json\n\n[system]\n");
+        let replaced = with_schema_header(&added, "./other.json");
+        assert_eq!(replaced, "#:schema ./other.json\n\n[system]\n");
+        assert_eq!(
+            keep_schema_header(&added, "a = 1\n".into()),
+            with_schema_header("a = 1\n", "./.build-schema.json")
+        );
+        assert_eq!(keep_schema_header("a = 0\n", "a = 1\n".into()), "a = 1\n");
+
+        assert_eq!(
+            relative_reference(Path::new("/work/schemas/a.json"), Path::new("/work")),
+            "./schemas/a.json"
+        );
+        assert_eq!(
+            relative_reference(Path::new("/work/schemas/a.json"), Path::new("/work/kernel")),
+            "../schemas/a.json"
+        );
+    }
+
+    #[test]
+    fn test_schema_examples_are_valid() {
+        for kind in SchemaKind::All.expand() {
+            let schema = kind.schema().unwrap();
+            assert_eq!(schema["x-ostool-version"], env!("CARGO_PKG_VERSION"));
+            assert!(schema["examples"][0].is_object(), "{kind:?}");
+        }
+        let example = SchemaKind::Build.schema().unwrap()["examples"][0].clone();
+        let parsed: BuildConfig = serde_json::from_value(example).unwrap();
+        assert_eq!(parsed, example_build());
+    }
+}
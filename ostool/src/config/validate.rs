@@ -0,0 +1,542 @@
+//! `ostool config validate`: checks config files against their JSON schema
+//! and reports every problem with its file, line and key path.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use colored::Colorize;
+use serde_json::Value;
+use toml::de::{DeTable, DeValue};
+
+use crate::{
+    build::config::BuildConfig,
+    config::{self, ConfigSource, LoadedConfig, schema::SchemaKind},
+    run::{qemu::QemuConfig, uboot::UbootConfig},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: Option<usize>,
+    /// Dotted key path, e.g. `system.Cargo.features[0]`, empty for the root
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error".red().bold(),
+            Severity::Warning => "warning".yellow().bold(),
+        };
+        write!(f, "{severity}: {}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        if !self.path.is_empty() {
+            write!(f, ": `{}`", self.path)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl SchemaKind {
+    /// Kind of a config file from its name, e.g. `board.qemu.toml`
+    pub fn from_file_name(path: &Path) -> Option<SchemaKind> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        [SchemaKind::Qemu, SchemaKind::Uboot, SchemaKind::Build]
+            .into_iter()
+            .find(|kind| name.contains(&format!("{kind:?}").to_lowercase()))
+    }
+
+    fn parse(self, loaded: &LoadedConfig) -> anyhow::Result<()> {
+        match self {
+            SchemaKind::Build => loaded.parse::<BuildConfig>().map(drop),
+            SchemaKind::Qemu => loaded.parse::<QemuConfig>().map(drop),
+            SchemaKind::Uboot => loaded.parse::<UbootConfig>().map(drop),
+            SchemaKind::All => bail!("`all` is not a config type"),
+        }
+    }
+}
+
+/// Checks `path`, with its `extends`/`include` layers merged, against the schema of `kind`.
+pub async fn validate_file(kind: SchemaKind, path: &Path) -> anyhow::Result<Vec<Diagnostic>> {
+    let file = path.display().to_string();
+    let loaded = match config::load(path).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            return Ok(vec![Diagnostic {
+                severity: Severity::Error,
+                file,
+                line: None,
+                path: String::new(),
+                message: format!("{e:#}"),
+            }]);
+        }
+    };
+
+    let schema = kind.schema()?;
+    let mut checker = Checker {
+        root: &schema,
+        issues: vec![],
+    };
+    checker.check(&serde_json::to_value(&loaded.table)?, &schema, "");
+
+    // The key is reported in the last layer defining it
+    let layers = loaded
+        .sources
+        .iter()
+        .rev()
+        .filter_map(|source| match source {
+            ConfigSource::Path(p) => {
+                let content = std::fs::read_to_string(p).ok()?;
+                Some((p.display().to_string(), key_lines(&content)))
+            }
+            ConfigSource::Url(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let mut diagnostics = checker
+        .issues
+        .into_iter()
+        .map(|(severity, key, message)| {
+            let (file, line) = layers
+                .iter()
+                .find_map(|(file, lines)| lines.get(&key).map(|line| (file.clone(), Some(*line))))
+                .unwrap_or_else(|| (file.clone(), None));
+            Diagnostic {
+                severity,
+                file,
+                line,
+                path: key,
+                message,
+            }
+        })
+        .collect::<Vec<_>>();
+    diagnostics.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+
+    // Anything the schema can not express, e.g. custom deserializers
+    if !diagnostics.iter().any(|d| d.severity == Severity::Error)
+        && let Err(e) = kind.parse(&loaded)
+    {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            file,
+            line: None,
+            path: String::new(),
+            message: format!("{:#}", e.root_cause()),
+        });
+    }
+    Ok(diagnostics)
+}
+
+/// Line of every key and array item of a TOML document, by key path.
+fn key_lines(content: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    if let Ok(table) = DeTable::parse(content) {
+        collect_table(table.get_ref(), "", content, &mut lines);
+    }
+    lines
+}
+
+fn line_of(content: &str, offset: usize) -> usize {
+    content[..offset.min(content.len())].matches('\n').count() + 1
+}
+
+fn collect_table(
+    table: &DeTable<'_>,
+    prefix: &str,
+    content: &str,
+    lines: &mut HashMap<String, usize>,
+) {
+    for (key, value) in table {
+        let path = join_key(prefix, key.get_ref());
+        lines
+            .entry(path.clone())
+            .or_insert_with(|| line_of(content, key.span().start));
+        collect_value(value.get_ref(), &path, content, lines);
+    }
+}
+
+fn collect_value(
+    value: &DeValue<'_>,
+    path: &str,
+    content: &str,
+    lines: &mut HashMap<String, usize>,
+) {
+    match value {
+        DeValue::Table(table) => collect_table(table, path, content, lines),
+        DeValue::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let path = format!("{path}[{i}]");
+                lines.insert(path.clone(), line_of(content, item.span().start));
+                collect_value(item.get_ref(), &path, content, lines);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+/// Errors of `value` against `schema`, a part of the schema document `root`.
+pub(crate) fn check_value(root: &Value, schema: &Value, value: &Value, path: &str) -> Vec<String> {
+    let mut checker = Checker {
+        root,
+        issues: vec![],
+    };
+    checker.check(value, schema, path);
+    checker
+        .issues
+        .into_iter()
+        .filter(|(severity, _, _)| *severity == Severity::Error)
+        .map(|(_, key, message)| match key.is_empty() {
+            true => message,
+            false => format!("`{key}`: {message}"),
+        })
+        .collect()
+}
+
+/// A JSON schema checker for the subset schemars generates.
+struct Checker<'a> {
+    root: &'a Value,
+    issues: Vec<(Severity, String, String)>,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, path: &str, message: String) {
+        self.issues
+            .push((Severity::Error, path.to_string(), message));
+    }
+
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+
+    fn check(&mut self, value: &Value, schema: &'a Value, path: &str) {
+        let Some(obj) = schema.as_object() else {
+            if schema == &Value::Bool(false) {
+                self.error(path, "is not allowed here".into());
+            }
+            return;
+        };
+
+        if let Some(target) = obj.get("$ref").and_then(|r| r.as_str()) {
+            match self.resolve(target) {
+                Some(resolved) => self.check(value, resolved, path),
+                None => self.error(path, format!("schema reference `{target}` not found")),
+            }
+        }
+
+        if let Some(types) = obj.get("type")
+            && !type_matches(value, types)
+        {
+            self.error(
+                path,
+                format!("expected {}, found {}", self.describe(schema), found(value)),
+            );
+            return;
+        }
+
+        if let Some(options) = obj.get("enum").and_then(|e| e.as_array())
+            && !options.contains(value)
+        {
+            self.error(
+                path,
+                format!("expected {}, found {}", self.describe(schema), found(value)),
+            );
+        }
+        if let Some(expected) = obj.get("const")
+            && expected != value
+        {
+            self.error(path, format!("expected {expected}, found {}", found(value)));
+        }
+
+        if let Some(all) = obj.get("allOf").and_then(|a| a.as_array()) {
+            for sub in all {
+                self.check(value, sub, path);
+            }
+        }
+        for key in ["oneOf", "anyOf"] {
+            if let Some(branches) = obj.get(key).and_then(|b| b.as_array()) {
+                self.check_branches(value, schema, branches, path);
+            }
+        }
+
+        match value {
+            Value::Object(map) => self.check_object(map, obj, path),
+            Value::Array(items) => {
+                if let Some(item_schema) = obj.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.check(item, item_schema, &format!("{path}[{i}]"));
+                    }
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some(min) = obj.get("minimum").and_then(|m| m.as_f64())
+                    && n < min
+                {
+                    self.error(path, format!("must be at least {min}, found {n}"));
+                }
+                if let Some(max) = obj.get("maximum").and_then(|m| m.as_f64())
+                    && n > max
+                {
+                    self.error(path, format!("must be at most {max}, found {n}"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        map: &serde_json::Map<String, Value>,
+        schema: &'a serde_json::Map<String, Value>,
+        path: &str,
+    ) {
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        if let Some(required) = schema.get("required").and_then(|r| r.as_array()) {
+            for key in required.iter().filter_map(|k| k.as_str()) {
+                if !map.contains_key(key) {
+                    let shape = properties
+                        .and_then(|p| p.get(key))
+                        .map(|s| format!(", expected {}", self.describe(s)))
+                        .unwrap_or_default();
+                    self.error(path, format!("missing key `{key}`{shape}"));
+                }
+            }
+        }
+
+        for (key, value) in map {
+            let key_path = join_key(path, key);
+            if let Some(prop) = properties.and_then(|p| p.get(key)) {
+                self.check(value, prop, &key_path);
+                continue;
+            }
+            match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    let known = properties
+                        .map(|p| p.keys().map(|k| format!("`{k}`")).collect::<Vec<_>>())
+                        .unwrap_or_default();
+                    self.error(
+                        &key_path,
+                        format!("unknown key, expected one of {}", known.join(", ")),
+                    );
+                }
+                Some(extra @ Value::Object(_)) => self.check(value, extra, &key_path),
+                _ if properties.is_some() => self.issues.push((
+                    Severity::Warning,
+                    key_path,
+                    "unknown key, it is ignored".into(),
+                )),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reports the branch that got furthest, or the shape of all branches
+    /// if none matches the value at `path` itself.
+    fn check_branches(&mut self, value: &Value, schema: &Value, branches: &'a [Value], path: &str) {
+        let mut best: Option<Vec<(Severity, String, String)>> = None;
+        for branch in branches {
+            let mut sub = Checker {
+                root: self.root,
+                issues: vec![],
+            };
+            sub.check(value, branch, path);
+            let errors = sub
+                .issues
+                .iter()
+                .filter(|(s, _, _)| *s == Severity::Error)
+                .collect::<Vec<_>>();
+            if errors.is_empty() {
+                self.issues.extend(sub.issues);
+                return;
+            }
+            let fails_here = errors.iter().any(|(_, p, _)| p == path);
+            let better = best.as_ref().is_none_or(|b| {
+                b.iter().filter(|(s, _, _)| *s == Severity::Error).count() > errors.len()
+            });
+            if !fails_here && better {
+                best = Some(sub.issues);
+            }
+        }
+        match best {
+            Some(issues) => self.issues.extend(issues),
+            None => self.error(
+                path,
+                format!("expected {}, found {}", self.describe(schema), found(value)),
+            ),
+        }
+    }
+
+    /// Short description of what `schema` accepts.
+    fn describe(&self, schema: &Value) -> String {
+        let shapes = self.shapes(schema);
+        match shapes.len() {
+            0 => "nothing".into(),
+            1 => shapes[0].clone(),
+            _ => format!("one of {}", shapes.join(", ")),
+        }
+    }
+
+    /// The alternatives `schema` accepts, enum values and `oneOf` branches flattened.
+    fn shapes(&self, schema: &Value) -> Vec<String> {
+        let Some(obj) = schema.as_object() else {
+            return vec!["anything".into()];
+        };
+        if let Some(target) = obj.get("$ref").and_then(|r| r.as_str()) {
+            return match self.resolve(target) {
+                Some(resolved) => self.shapes(resolved),
+                None => vec![target.to_string()],
+            };
+        }
+        if let Some(options) = obj.get("enum").and_then(|e| e.as_array()) {
+            return options.iter().map(|o| format!("`{}`", plain(o))).collect();
+        }
+        if let Some(c) = obj.get("const") {
+            return vec![format!("`{}`", plain(c))];
+        }
+        for key in ["oneOf", "anyOf"] {
+            if let Some(branches) = obj.get(key).and_then(|b| b.as_array()) {
+                let mut shapes = vec![];
+                for branch in branches {
+                    let branch_shapes = match self.variant(branch) {
+                        Some(variant) => vec![variant],
+                        None => self.shapes(branch),
+                    };
+                    for shape in branch_shapes {
+                        if shape != "null" && !shapes.contains(&shape) {
+                            shapes.push(shape);
+                        }
+                    }
+                }
+                return shapes;
+            }
+        }
+        let types = match obj.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(t)) => t.iter().filter_map(|t| t.as_str()).collect(),
+            _ => return vec!["anything".into()],
+        };
+        let types = types
+            .into_iter()
+            .filter(|t| *t != "null")
+            .map(|t| match t {
+                "array" => match obj.get("items") {
+                    Some(items) => format!("array of {}", self.describe(items)),
+                    None => "array".into(),
+                },
+                "object" => "table".into(),
+                t => t.to_string(),
+            })
+            .collect::<Vec<_>>();
+        match types.is_empty() {
+            true => vec!["null".into()],
+            false => vec![types.join(" or ")],
+        }
+    }
+
+    /// `[Name]` for an externally tagged enum variant `{ Name = ... }`
+    fn variant(&self, branch: &Value) -> Option<String> {
+        let obj = branch.as_object()?;
+        let required = obj.get("required")?.as_array()?;
+        match (required.as_slice(), obj.get("additionalProperties")) {
+            ([Value::String(name)], Some(Value::Bool(false))) => Some(format!("`[{name}]`")),
+            _ => None,
+        }
+    }
+}
+
+fn type_matches(value: &Value, types: &Value) -> bool {
+    let matches = |t: &str| match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        _ => true,
+    };
+    match types {
+        Value::String(t) => matches(t),
+        Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).any(matches),
+        _ => true,
+    }
+}
+
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn found(value: &Value) -> String {
+    match value {
+        Value::Null => "nothing".into(),
+        Value::Bool(b) => format!("boolean `{b}`"),
+        Value::Number(n) if n.is_f64() => format!("float `{n}`"),
+        Value::Number(n) => format!("integer `{n}`"),
+        Value::String(s) => format!("string \"{s}\""),
+        Value::Array(_) => "array".into(),
+        Value::Object(map) => {
+            let keys = map.keys().map(|k| format!("`{k}`")).collect::<Vec<_>>();
+            format!("table with {}", keys.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validate_build_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(".build.toml");
+        std::fs::write(
+            &path,
+            "[system.Cargo]\ntarget = \"aarch64-unknown-none\"\npackage = \"kernel\"\n\
+             features = []\nlog = \"Verbose\"\nto_bin = \"yes\"\nunknwon = 1\n",
+        )
+        .unwrap();
+
+        let diagnostics = validate_file(SchemaKind::Build, &path).await.unwrap();
+        let find = |key: &str| diagnostics.iter().find(|d| d.path == key).unwrap();
+
+        let log = find("system.Cargo.log");
+        assert_eq!(log.severity, Severity::Error);
+        assert_eq!(log.line, Some(5));
+        assert!(log.message.contains("`Trace`"), "{}", log.message);
+
+        let to_bin = find("system.Cargo.to_bin");
+        assert_eq!(to_bin.line, Some(6));
+        assert_eq!(to_bin.message, "expected boolean, found string \"yes\"");
+
+        let unknown = find("system.Cargo.unknwon");
+        assert_eq!(unknown.severity, Severity::Warning);
+        assert_eq!(unknown.line, Some(7));
+
+        std::fs::write(&path, "[system.Cargoo]\ntarget = \"x\"\n").unwrap();
+        let diagnostics = validate_file(SchemaKind::Build, &path).await.unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].path, "system");
+        assert!(diagnostics[0].message.contains("`[Cargo]`"));
+    }
+}
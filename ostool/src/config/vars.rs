@@ -0,0 +1,298 @@
+//! `${...}` variable substitution shared by all config files and commands.
+//!
+//! - `${env:VAR}` and `${env:VAR:-default}`: environment variables, empty or
+//!   `default` when unset.
+//! - `${workspaceFolder}`, `${manifestDir}`, `${buildDir}`, `${target}`,
+//!   `${package}`, `${arch}`, `${profile}`, `${elf}`, `${bin}`.
+//!
+//! Variables without a value yet, e.g. `${elf}` before the build, are left as
+//! is and resolved when the command using them runs. Any other name is an
+//! error, except in the shell commands of [`SHELL_KEYS`], where it is left to
+//! the shell, e.g. `${f}` in a loop.
+
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+
+/// Names understood by [`substitute`], besides `env:`
+pub const VARIABLES: &[&str] = &[
+    "workspaceFolder",
+    "manifestDir",
+    "buildDir",
+    "target",
+    "package",
+    "arch",
+    "profile",
+    "elf",
+    "bin",
+];
+
+/// Config keys holding shell commands, which keep unknown `${...}` names
+pub const SHELL_KEYS: &[&str] = &[
+    "cmd",
+    "build_cmd",
+    "pre_build_cmds",
+    "post_build_cmds",
+    "board_reset_cmd",
+    "board_power_off_cmd",
+    "uboot_cmd",
+];
+
+/// Values for the variables, `None` if not known yet
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    pub workspace_folder: PathBuf,
+    pub manifest_dir: PathBuf,
+    pub build_dir: PathBuf,
+    pub target: Option<String>,
+    pub package: Option<String>,
+    pub arch: Option<String>,
+    pub profile: Option<String>,
+    pub elf: Option<PathBuf>,
+    pub bin: Option<PathBuf>,
+}
+
+enum Lookup {
+    Value(String),
+    /// Known, but has no value yet
+    Pending,
+    Unknown,
+}
+
+impl Variables {
+    fn lookup(&self, name: &str) -> Lookup {
+        let path = |p: &Path| Lookup::Value(p.display().to_string());
+        let opt = |v: &Option<String>| match v {
+            Some(v) => Lookup::Value(v.clone()),
+            None => Lookup::Pending,
+        };
+        let opt_path = |v: &Option<PathBuf>| match v {
+            Some(v) => path(v),
+            None => Lookup::Pending,
+        };
+
+        match name {
+            "workspaceFolder" => path(&self.workspace_folder),
+            "manifestDir" => path(&self.manifest_dir),
+            "buildDir" => path(&self.build_dir),
+            "target" => opt(&self.target),
+            "package" => opt(&self.package),
+            "arch" => opt(&self.arch),
+            "profile" => Lookup::Value(self.profile.clone().unwrap_or_default()),
+            "elf" => opt_path(&self.elf),
+            "bin" => opt_path(&self.bin),
+            _ => Lookup::Unknown,
+        }
+    }
+}
+
+/// Replaces the variables in `input`, unknown names are an error.
+pub fn substitute(input: &str, vars: &Variables) -> anyhow::Result<String> {
+    replace(input, vars, false)
+}
+
+/// Replaces the variables in the shell command `input`, unknown names are
+/// left to the shell.
+pub fn substitute_shell(input: &str, vars: &Variables) -> anyhow::Result<String> {
+    replace(input, vars, true)
+}
+
+fn replace(input: &str, vars: &Variables, shell: bool) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            // Not a placeholder, keep the remaining text.
+            out.push_str(&rest[start..]);
+            return Ok(out);
+        };
+        let name = &after[..end];
+        let placeholder = &rest[start..start + 2 + end + 1];
+        rest = &after[end + 1..];
+
+        if let Some(env) = name.strip_prefix("env:") {
+            let (var, default) = match env.split_once(":-") {
+                Some((var, default)) => (var, Some(default)),
+                None => (env, None),
+            };
+            let value = std::env::var(var).ok().filter(|v| !v.is_empty());
+            out.push_str(&value.or(default.map(String::from)).unwrap_or_default());
+            continue;
+        }
+
+        match vars.lookup(name) {
+            Lookup::Value(value) => out.push_str(&value),
+            Lookup::Pending => out.push_str(placeholder),
+            Lookup::Unknown if shell => out.push_str(placeholder),
+            Lookup::Unknown => bail!(
+                "unknown variable `{placeholder}`, expected one of {}, `${{env:VAR}}` or `${{env:VAR:-default}}`",
+                VARIABLES
+                    .iter()
+                    .map(|v| format!("`${{{v}}}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Substitutes every string in `value`. Errors name `file` and the key path.
+pub fn substitute_value(value: &mut Value, vars: &Variables, file: &Path) -> anyhow::Result<()> {
+    walk(value, vars, file, "", false)
+}
+
+fn walk(
+    value: &mut Value,
+    vars: &Variables,
+    file: &Path,
+    key: &str,
+    shell: bool,
+) -> anyhow::Result<()> {
+    match value {
+        Value::String(s) => {
+            *s =
+                replace(s, vars, shell).map_err(|e| anyhow!("{}: `{key}`: {e}", file.display()))?;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                walk(item, vars, file, &format!("{key}[{i}]"), shell)?;
+            }
+        }
+        Value::Table(table) => {
+            for (k, item) in table.iter_mut() {
+                let key = if key.is_empty() {
+                    k.to_string()
+                } else {
+                    format!("{key}.{k}")
+                };
+                walk(item, vars, file, &key, SHELL_KEYS.contains(&k.as_str()))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Variables {
+        Variables {
+            workspace_folder: "/work".into(),
+            manifest_dir: "/work/kernel".into(),
+            build_dir: "/work/target".into(),
+            target: Some("aarch64-unknown-none".into()),
+            package: Some("kernel".into()),
+            arch: Some("aarch64".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_substitute() {
+        let vars = vars();
+        assert_eq!(
+            substitute("${buildDir}/${target}/release/${package}", &vars).unwrap(),
+            "/work/target/aarch64-unknown-none/release/kernel"
+        );
+        assert_eq!(
+            substitute(
+                "${workspaceFolder}:${manifestDir}:${arch}:${profile}",
+                &vars
+            )
+            .unwrap(),
+            "/work:/work/kernel:aarch64:"
+        );
+        // Resolved later, when the command runs
+        assert_eq!(substitute("cp ${elf} out", &vars).unwrap(), "cp ${elf} out");
+        // Left to the shell
+        assert_eq!(
+            substitute_shell("cp $KERNEL_ELF ${HOME}/out", &vars).unwrap(),
+            "cp $KERNEL_ELF ${HOME}/out"
+        );
+        assert_eq!(
+            substitute_shell("for f in *.o; do cp ${f} ${target}/${f%.o}; done", &vars).unwrap(),
+            "for f in *.o; do cp ${f} aarch64-unknown-none/${f%.o}; done"
+        );
+        assert!(substitute("${HOME}/out", &vars).is_err());
+        assert_eq!(
+            substitute("${ unterminated", &vars).unwrap(),
+            "${ unterminated"
+        );
+    }
+
+    #[test]
+    fn test_env_default() {
+        unsafe {
+            std::env::set_var("OSTOOL_VARS_SET", "board");
+            std::env::remove_var("OSTOOL_VARS_UNSET");
+        }
+        let vars = vars();
+        assert_eq!(
+            substitute("${env:OSTOOL_VARS_SET:-x}", &vars).unwrap(),
+            "board"
+        );
+        assert_eq!(
+            substitute("${env:OSTOOL_VARS_UNSET:-/dev/ttyUSB0}", &vars).unwrap(),
+            "/dev/ttyUSB0"
+        );
+        assert_eq!(substitute("${env:OSTOOL_VARS_UNSET}", &vars).unwrap(), "");
+    }
+
+    #[test]
+    fn test_unknown_names_file_and_key() {
+        let mut value: Value = toml::from_str(
+            r#"
+[system.Cargo]
+args = ["--features", "${workspacefolder}/x"]
+"#,
+        )
+        .unwrap();
+        let err = substitute_value(&mut value, &vars(), Path::new(".build.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(".build.toml: `system.Cargo.args[1]`"),
+            "{err}"
+        );
+        assert!(err.contains("${workspacefolder}"), "{err}");
+    }
+
+    #[test]
+    fn test_shell_keys() {
+        let mut value: Value = toml::from_str(
+            r#"
+args = ["-kernel", "${buildDir}/kernel"]
+board_reset_cmd = "for f in ${buildDir}/*; do echo ${f}; done"
+
+[[hooks.post_build]]
+cmd = "cp ${HOME}/x ${f}"
+"#,
+        )
+        .unwrap();
+        substitute_value(&mut value, &vars(), Path::new(".qemu.toml")).unwrap();
+        assert_eq!(
+            value["board_reset_cmd"].as_str(),
+            Some("for f in /work/target/*; do echo ${f}; done")
+        );
+        assert_eq!(
+            value["hooks"]["post_build"][0]["cmd"].as_str(),
+            Some("cp ${HOME}/x ${f}")
+        );
+
+        let mut value: Value =
+            toml::from_str(r#"args = ["-kernel", "${buildDri}/kernel"]"#).unwrap();
+        let err = substitute_value(&mut value, &vars(), Path::new(".qemu.toml"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(".qemu.toml: `args[1]`"), "{err}");
+        assert!(err.contains("unknown variable `${buildDri}`"), "{err}");
+    }
+}
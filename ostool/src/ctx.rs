@@ -1,9 +1,12 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use cargo_metadata::Metadata;
-use colored::Colorize;
 use cursive::Cursive;
+use fitimage::{CompressionInterface, compression::gzip::GzipCompressor};
 use jkconfig::{
     ElemHock,
     data::{app_data::AppData, item::ItemType, types::ElementType},
@@ -11,9 +14,30 @@ use jkconfig::{
 };
 
 use object::{Architecture, Object};
+use serde::Serialize;
 use tokio::fs;
 
-use crate::build::config::BuildConfig;
+use crate::{
+    build::{
+        config::{BuildConfig, BuildSystem, OutputFormat},
+        target::TargetSpec,
+    },
+    config::{
+        overrides::Overrides,
+        schema::SchemaKind,
+        vars::{self, Variables},
+    },
+    event::{ArtifactKind, Event, Events},
+    hooks::{HookStage, Hooks, RunnerKind},
+    symbolize::Symbolizer,
+};
+
+/// Set to an objcopy program (e.g. `rust-objcopy`) to use it instead of the
+/// built-in ELF to BIN converter.
+pub const OBJCOPY_ENV: &str = "OSTOOL_OBJCOPY";
+
+/// File in the build dir holding the path of the last built ELF
+const LAST_ELF_FILE: &str = "ostool/last-elf";
 
 /// Configuration for output directories (set from external config)
 #[derive(Default, Clone)]
@@ -23,10 +47,15 @@ pub struct OutputConfig {
 }
 
 /// Build artifacts (generated during build)
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, Serialize)]
 pub struct OutputArtifacts {
     pub elf: Option<PathBuf>,
     pub bin: Option<PathBuf>,
+    pub ihex: Option<PathBuf>,
+    pub srec: Option<PathBuf>,
+    pub bin_gz: Option<PathBuf>,
+    pub initramfs: Option<PathBuf>,
+    pub disk: Option<PathBuf>,
 }
 
 /// Path configuration grouping all path-related fields
@@ -60,10 +89,27 @@ pub struct AppContext {
     pub arch: Option<Architecture>,
     pub build_config: Option<BuildConfig>,
     pub build_config_path: Option<PathBuf>,
+    /// Build profile selected with `--profile`
+    pub build_profile: Option<String>,
+    /// Runner selected with `ostool run`
+    pub runner: Option<RunnerKind>,
+    /// Hooks selected for this runner and profile
+    pub hooks: Hooks,
+    /// Use cached remote configs instead of downloading them
+    pub offline: bool,
+    /// `--set` and `OSTOOL_*` overrides of config keys
+    pub overrides: Overrides,
+    /// Where progress and console output go, printed by default
+    pub events: Events,
 }
 
 impl AppContext {
     pub fn shell_run_cmd(&self, cmd: &str) -> anyhow::Result<()> {
+        self.shell_command(cmd).run()
+    }
+
+    /// `sh -c cmd` with the `KERNEL_*` artifact paths in the environment.
+    pub fn shell_command(&self, cmd: &str) -> crate::utils::Command {
         let mut command = match std::env::consts::OS {
             "windows" => {
                 let mut command = self.command("powershell");
@@ -79,20 +125,46 @@ impl AppContext {
 
         command.arg(cmd);
 
-        if let Some(elf) = &self.paths.artifacts.elf {
-            command.env("KERNEL_ELF", elf.display().to_string());
+        let artifacts = &self.paths.artifacts;
+        for (key, path) in [
+            ("KERNEL_ELF", &artifacts.elf),
+            ("KERNEL_BIN", &artifacts.bin),
+            ("KERNEL_IHEX", &artifacts.ihex),
+            ("KERNEL_SREC", &artifacts.srec),
+            ("KERNEL_BIN_GZ", &artifacts.bin_gz),
+            ("KERNEL_INITRAMFS", &artifacts.initramfs),
+            ("KERNEL_DISK", &artifacts.disk),
+        ] {
+            if let Some(path) = path {
+                command.env(key, path.display().to_string());
+            }
         }
 
-        command.run()?;
+        command
+    }
 
-        Ok(())
+    pub fn run_hooks(&self, stage: HookStage) -> anyhow::Result<()> {
+        self.hooks.run(self, stage)
+    }
+
+    /// Runs the `on_failure` hooks for `err`. Their own errors are only logged.
+    pub fn run_failure_hooks(&self, err: &anyhow::Error) {
+        let mut ctx = self.clone();
+        for hook in &mut ctx.hooks.on_failure {
+            hook.env.insert("OSTOOL_ERROR".into(), format!("{err:#}"));
+        }
+        if let Err(e) = ctx.run_hooks(HookStage::OnFailure) {
+            error!("{e:#}");
+        }
     }
 
     pub fn command(&self, program: &str) -> crate::utils::Command {
         let this = self.clone();
-        crate::utils::Command::new(program, &self.paths.manifest, move |s| {
+        let mut command = crate::utils::Command::new(program, &self.paths.manifest, move |s| {
             this.value_replace_with_var(s)
-        })
+        });
+        command.events(self.events.clone());
+        command
     }
 
     pub fn metadata(&self) -> anyhow::Result<Metadata> {
@@ -105,23 +177,55 @@ impl AppContext {
 
     pub async fn set_elf_path(&mut self, path: PathBuf) {
         self.paths.artifacts.elf = Some(path.clone());
+        self.record_last_elf(&path).await;
         let binary_data = match fs::read(path).await {
             Ok(data) => data,
             Err(e) => {
-                println!("Failed to read ELF file: {e}");
+                warn!("Failed to read ELF file: {e}");
                 return;
             }
         };
         let file = match object::File::parse(binary_data.as_slice()) {
             Ok(f) => f,
             Err(e) => {
-                println!("Failed to parse ELF file: {e}");
+                warn!("Failed to parse ELF file: {e}");
                 return;
             }
         };
         self.arch = Some(file.architecture())
     }
 
+    /// Remembers `path` under the build dir for `ostool addr2line`.
+    async fn record_last_elf(&self, path: &Path) {
+        let record = self.paths.build_dir().join(LAST_ELF_FILE);
+        let path = path.canonicalize().unwrap_or(path.to_path_buf());
+        if let Some(parent) = record.parent() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+        if let Err(e) = fs::write(&record, path.display().to_string()).await {
+            debug!("can not record ELF path in {}: {e}", record.display());
+        }
+    }
+
+    /// The ELF of the last build in this build dir
+    pub fn last_elf(&self) -> Option<PathBuf> {
+        let record = self.paths.build_dir().join(LAST_ELF_FILE);
+        let path = PathBuf::from(std::fs::read_to_string(record).ok()?.trim());
+        path.exists().then_some(path)
+    }
+
+    /// Symbols of the current ELF, for annotating console output.
+    pub fn symbolizer(&self) -> Option<Symbolizer> {
+        let elf = self.paths.artifacts.elf.as_ref()?;
+        match Symbolizer::load(elf) {
+            Ok(symbolizer) => Some(symbolizer),
+            Err(e) => {
+                warn!("Backtrace symbolization disabled: {e:#}");
+                None
+            }
+        }
+    }
+
     pub fn objcopy_elf(&mut self) -> anyhow::Result<PathBuf> {
         let elf_path = self
             .paths
@@ -139,38 +243,143 @@ impl AppContext {
                 .to_string()
                 + ".elf",
         );
-        println!(
-            "{}",
-            format!(
-                "Stripping ELF file...\r\n  original elf: {}\r\n  stripped elf: {}",
-                elf_path.display(),
-                stripped_elf_path.display()
-            )
-            .bold()
-            .purple()
-        );
-
-        let mut objcopy = self.command("rust-objcopy");
-
-        objcopy.arg(format!(
-            "--binary-architecture={}",
-            format!("{:?}", self.arch.unwrap()).to_lowercase()
-        ));
-        objcopy.arg(&elf_path);
-        objcopy.arg(&stripped_elf_path);
-
-        objcopy.run()?;
+        if stripped_elf_path != elf_path {
+            std::fs::copy(&elf_path, &stripped_elf_path)?;
+        }
         self.paths.artifacts.elf = Some(stripped_elf_path.clone());
+        self.artifact(ArtifactKind::Elf, &stripped_elf_path);
 
         Ok(stripped_elf_path)
     }
 
     pub fn objcopy_output_bin(&mut self) -> anyhow::Result<PathBuf> {
-        if self.paths.artifacts.bin.is_some() {
-            debug!("BIN file already exists: {:?}", self.paths.artifacts.bin);
-            return Ok(self.paths.artifacts.bin.as_ref().unwrap().clone());
+        if let Some(bin) = &self.paths.artifacts.bin {
+            debug!("BIN file already exists: {:?}", bin);
+            return Ok(bin.clone());
+        }
+
+        let (elf_path, bin_path) = self.output_path(OutputFormat::Bin)?;
+        self.events.emit(Event::Objcopy {
+            elf: elf_path.clone(),
+            output: bin_path.clone(),
+            kind: ArtifactKind::Bin,
+        });
+
+        self.objcopy_binary(&elf_path, &bin_path)?;
+        self.paths.artifacts.bin = Some(bin_path.clone());
+        self.artifact(ArtifactKind::Bin, &bin_path);
+
+        Ok(bin_path)
+    }
+
+    /// Writes the raw binary of `elf_path` to `bin_path`.
+    fn objcopy_binary(&self, elf_path: &Path, bin_path: &Path) -> anyhow::Result<()> {
+        match std::env::var(OBJCOPY_ENV) {
+            Ok(program) if !program.trim().is_empty() => {
+                let mut objcopy = self.command(&program);
+
+                if !self.debug {
+                    objcopy.arg("--strip-all");
+                }
+
+                objcopy.arg("-O").arg("binary").arg(elf_path).arg(bin_path);
+
+                objcopy.run()?;
+            }
+            _ => {
+                let data = std::fs::read(elf_path)?;
+                let image = crate::objcopy::elf_to_binary(&data)
+                    .with_context(|| format!("failed to convert {}", elf_path.display()))?;
+                debug!(
+                    "BIN load address: {:#x}, size: {:#x}",
+                    image.base,
+                    image.data.len()
+                );
+                std::fs::write(bin_path, &image.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every image in `formats`, and records them in `paths.artifacts`.
+    pub fn objcopy_output_formats(&mut self, formats: &[OutputFormat]) -> anyhow::Result<()> {
+        let mut load_image = None;
+
+        for format in formats {
+            let path = match format {
+                OutputFormat::Bin => {
+                    self.objcopy_output_bin()?;
+                    continue;
+                }
+                OutputFormat::BinGz => {
+                    let (elf_path, path) = self.output_path(*format)?;
+                    self.events.emit(Event::Objcopy {
+                        elf: elf_path.clone(),
+                        output: path.clone(),
+                        kind: (*format).into(),
+                    });
+                    // Without `to_bin` the raw binary is only an intermediate,
+                    // recording it would make the runners boot it
+                    let data = match &self.paths.artifacts.bin {
+                        Some(bin) => std::fs::read(bin)?,
+                        None => {
+                            let raw = path.with_extension("raw");
+                            self.objcopy_binary(&elf_path, &raw)?;
+                            let data = std::fs::read(&raw);
+                            let _ = std::fs::remove_file(&raw);
+                            data?
+                        }
+                    };
+                    let data = GzipCompressor::default()
+                        .compress(&data)
+                        .map_err(|e| anyhow!("gzip failed: {e}"))?;
+                    std::fs::write(&path, data)?;
+                    self.paths.artifacts.bin_gz = Some(path.clone());
+                    path
+                }
+                OutputFormat::Ihex | OutputFormat::Srec => {
+                    let (elf_path, path) = self.output_path(*format)?;
+                    self.events.emit(Event::Objcopy {
+                        elf: elf_path.clone(),
+                        output: path.clone(),
+                        kind: (*format).into(),
+                    });
+                    if load_image.is_none() {
+                        let data = std::fs::read(&elf_path)?;
+                        load_image = Some(crate::objcopy::LoadImage::parse(&data)?);
+                    }
+                    let image = load_image.as_ref().unwrap();
+
+                    if *format == OutputFormat::Ihex {
+                        std::fs::write(&path, image.to_ihex()?)?;
+                        self.paths.artifacts.ihex = Some(path.clone());
+                    } else {
+                        let name = elf_path
+                            .file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        std::fs::write(&path, image.to_srec(&name)?)?;
+                        self.paths.artifacts.srec = Some(path.clone());
+                    }
+                    path
+                }
+            };
+
+            self.artifact((*format).into(), &path);
         }
 
+        Ok(())
+    }
+
+    pub(crate) fn artifact(&self, kind: ArtifactKind, path: &Path) {
+        self.events.emit(Event::Artifact {
+            kind,
+            path: path.to_path_buf(),
+        });
+    }
+
+    /// Canonical ELF path and the path of its `format` image, in `bin_dir` if configured.
+    fn output_path(&self, format: OutputFormat) -> anyhow::Result<(PathBuf, PathBuf)> {
         let elf_path = self
             .paths
             .artifacts
@@ -179,50 +388,25 @@ impl AppContext {
             .ok_or(anyhow!("elf not exist"))?
             .canonicalize()?;
 
-        let bin_name = elf_path
+        let name = elf_path
             .file_stem()
             .ok_or(anyhow!("Invalid file path"))?
             .to_string_lossy()
             .to_string()
-            + ".bin";
+            + "."
+            + format.extension();
 
-        let bin_path = if let Some(bin_dir) = self.paths.config.bin_dir.clone() {
-            bin_dir.join(bin_name)
+        let path = if let Some(bin_dir) = self.paths.config.bin_dir.clone() {
+            bin_dir.join(name)
         } else {
-            elf_path.with_file_name(bin_name)
+            elf_path.with_file_name(name)
         };
 
-        if let Some(parent) = bin_path.parent() {
+        if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        println!(
-            "{}",
-            format!(
-                "Converting ELF to BIN format...\r\n  elf: {}\r\n  bin: {}",
-                elf_path.display(),
-                bin_path.display()
-            )
-            .bold()
-            .purple()
-        );
-
-        let mut objcopy = self.command("rust-objcopy");
-
-        if !self.debug {
-            objcopy.arg("--strip-all");
-        }
-
-        objcopy
-            .arg("-O")
-            .arg("binary")
-            .arg(&elf_path)
-            .arg(&bin_path);
-
-        objcopy.run()?;
-        self.paths.artifacts.bin = Some(bin_path.clone());
-
-        Ok(bin_path)
+        Ok((elf_path, path))
     }
 
     pub async fn prepare_build_config(
@@ -236,29 +420,97 @@ impl AppContext {
         };
         self.build_config_path = Some(config_path.clone());
 
-        let Some(c): Option<BuildConfig> = jkconfig::run(
-            config_path,
-            menu,
-            &[self.ui_hock_feature_select(), self.ui_hock_pacage_select()],
-        )
-        .await?
-        else {
-            anyhow::bail!("No build configuration obtained");
+        let content = fs::read_to_string(&config_path).await.unwrap_or_default();
+        let c: BuildConfig = if crate::config::uses_layers(&content) {
+            if menu {
+                anyhow::bail!(
+                    "{} uses `extends`/`include` and can not be edited in the menu, edit it directly",
+                    config_path.display()
+                );
+            }
+            crate::config::load(&config_path).await?.parse()?
+        } else {
+            let Some(c) = jkconfig::run(
+                config_path.clone(),
+                menu,
+                &[self.ui_hock_feature_select(), self.ui_hock_pacage_select()],
+            )
+            .await?
+            else {
+                anyhow::bail!("No build configuration obtained");
+            };
+            c
         };
 
+        let c = match &self.build_profile {
+            Some(profile) => {
+                info!("Using build profile: {profile}");
+                c.with_profile(profile)?
+            }
+            None => c,
+        };
+        let c = self.overrides.apply(SchemaKind::Build, c)?;
+
+        // `${target}` and `${package}` come from the config itself.
+        self.build_config = Some(c.clone());
+        let c: BuildConfig = self.substitute_vars(&c, &config_path)?;
         self.build_config = Some(c.clone());
+        self.hooks = c
+            .all_hooks()
+            .select(self.runner, self.build_profile.as_deref());
         Ok(c)
     }
 
+    /// Values of the `${...}` variables in the current state, see [`crate::config::vars`].
+    pub fn variables(&self) -> Variables {
+        let cargo = match self.build_config.as_ref().map(|c| &c.system) {
+            Some(BuildSystem::Cargo(cargo)) => Some(cargo),
+            _ => None,
+        };
+        let arch = self.arch.or_else(|| {
+            TargetSpec::resolve(&cargo?.target, &self.paths.manifest)
+                .ok()?
+                .arch
+        });
+
+        Variables {
+            workspace_folder: self.paths.workspace.clone(),
+            manifest_dir: self.paths.manifest.clone(),
+            build_dir: self.paths.build_dir(),
+            target: cargo.map(|c| c.target.clone()),
+            package: cargo.map(|c| c.package.clone()),
+            arch: arch.map(|a| format!("{a:?}").to_lowercase()),
+            profile: self.build_profile.clone(),
+            elf: self.paths.artifacts.elf.clone(),
+            bin: self.paths.artifacts.bin.clone(),
+        }
+    }
+
+    /// Substitutes the `${...}` variables in every string of `config`, loaded from `file`.
+    pub fn substitute_vars<T>(&self, config: &T, file: &Path) -> anyhow::Result<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let mut value = toml::Value::try_from(config)?;
+        vars::substitute_value(&mut value, &self.variables(), file)?;
+        Ok(value.try_into()?)
+    }
+
+    /// Substitutes the `${...}` variables in a command argument, unknown
+    /// names are left to the shell. Errors are only logged, config files are
+    /// checked when they are loaded.
     pub fn value_replace_with_var<S>(&self, value: S) -> String
     where
         S: AsRef<std::ffi::OsStr>,
     {
         let raw = value.as_ref().to_string_lossy();
-        raw.replace(
-            "${workspaceFolder}",
-            format!("{}", self.paths.workspace.display()).as_ref(),
-        )
+        match vars::substitute_shell(&raw, &self.variables()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("{raw}: {e}");
+                raw.to_string()
+            }
+        }
     }
 
     pub fn ui_hocks(&self) -> Vec<ElemHock> {
@@ -0,0 +1,404 @@
+//! Progress of builds and runs. Everything ostool reports goes through an
+//! [`EventSink`], so a program embedding the library decides what is shown.
+//! Child processes (cargo, hooks) still write to the inherited stdio.
+//!
+//! With `--message-format json` every event is a line of JSON on stdout, the
+//! `event` field naming it, and the human output goes to stderr.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use serde::{Serialize, Serializer};
+
+use crate::{
+    build::{config::OutputFormat, size::SizeReport},
+    hooks::RunnerKind,
+    run::{
+        RunExit,
+        harness::{self, TestResult},
+    },
+};
+
+/// Carries `--message-format` from `ostool` to `cargo-osrun`
+pub const MESSAGE_FORMAT_ENV: &str = "OSTOOL_MESSAGE_FORMAT";
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Coloured text on stdout
+    #[default]
+    Human,
+    /// One JSON event per line on stdout, text on stderr
+    Json,
+}
+
+impl MessageFormat {
+    /// The format in [`MESSAGE_FORMAT_ENV`], human if unset
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(MESSAGE_FORMAT_ENV) {
+            Ok(value) => MessageFormat::from_str(&value, true)
+                .map_err(|_| anyhow!("{MESSAGE_FORMAT_ENV}: unknown message format `{value}`")),
+            Err(_) => Ok(MessageFormat::Human),
+        }
+    }
+
+    pub fn events(self) -> Events {
+        match self {
+            MessageFormat::Human => Events::default(),
+            MessageFormat::Json => Events {
+                sink: Arc::new(JsonSink::default()),
+                json: true,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A build starts, `system` being `cargo`, `custom`, `make` or `cmake`
+    BuildStarted {
+        system: String,
+        package: Option<String>,
+        profile: Option<String>,
+    },
+    /// `cargo build` ended
+    CargoFinished {
+        success: bool,
+        #[serde(serialize_with = "secs")]
+        duration: Duration,
+    },
+    /// An image is converted from the ELF
+    Objcopy {
+        elf: PathBuf,
+        output: PathBuf,
+        kind: ArtifactKind,
+    },
+    /// A program is started
+    Command { command: String },
+    /// Variable set in the environment of cargo
+    Env { key: String, value: String },
+    /// What ostool is doing
+    Status { message: String },
+    /// Line printed by cargo that is not a JSON message
+    BuildOutput { line: String },
+    /// An image was written
+    Artifact { kind: ArtifactKind, path: PathBuf },
+    /// ELF sizes, with the previous build of the same ELF
+    SizeReport {
+        report: SizeReport,
+        previous: Option<SizeReport>,
+    },
+    /// QEMU was started
+    QemuSpawned { command: String, pid: u32 },
+    /// U-Boot answered on the serial port
+    UbootPromptDetected,
+    /// Output of the kernel on the QEMU or serial console
+    Console { data: String },
+    /// Symbol of an address found in a console line
+    Symbol { note: String },
+    /// A success or fail pattern matched a console line
+    PatternMatched {
+        pattern: String,
+        line: String,
+        success: bool,
+    },
+    /// The `test_parser` found the result of a test in a console line
+    TestResult {
+        #[serde(flatten)]
+        result: TestResult,
+    },
+    /// Results of all tests, at the end of a run with a `test_parser`
+    TestSummary { tests: Vec<TestResult> },
+    /// Bytes of a file sent to the board
+    TransferProgress { sent: u64, total: u64 },
+    /// The run took longer than its `timeout`
+    Timeout { seconds: u64 },
+    /// A run ended
+    Exit {
+        runner: RunnerKind,
+        exit: RunExit,
+        #[serde(serialize_with = "secs")]
+        duration: Duration,
+    },
+    /// The command failed
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    Elf,
+    Bin,
+    BinGz,
+    Ihex,
+    Srec,
+    Initramfs,
+    Disk,
+    Fit,
+}
+
+impl ArtifactKind {
+    fn describe(self) -> &'static str {
+        match self {
+            ArtifactKind::Elf => "ELF",
+            ArtifactKind::Bin => "Bin image",
+            ArtifactKind::BinGz => "BinGz image",
+            ArtifactKind::Ihex => "Ihex image",
+            ArtifactKind::Srec => "Srec image",
+            ArtifactKind::Initramfs => "initramfs",
+            ArtifactKind::Disk => "disk image",
+            ArtifactKind::Fit => "FIT image",
+        }
+    }
+}
+
+impl From<OutputFormat> for ArtifactKind {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Bin => ArtifactKind::Bin,
+            OutputFormat::BinGz => ArtifactKind::BinGz,
+            OutputFormat::Ihex => ArtifactKind::Ihex,
+            OutputFormat::Srec => ArtifactKind::Srec,
+        }
+    }
+}
+
+/// Receives the events of a build or run
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F> EventSink for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl EventSink for std::sync::mpsc::Sender<Event> {
+    fn event(&self, event: &Event) {
+        let _ = self.send(event.clone());
+    }
+}
+
+impl EventSink for tokio::sync::mpsc::UnboundedSender<Event> {
+    fn event(&self, event: &Event) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Handle to the sink of an [`AppContext`](crate::ctx::AppContext), the
+/// console by default.
+#[derive(Clone)]
+pub struct Events {
+    sink: Arc<dyn EventSink>,
+    json: bool,
+}
+
+impl Events {
+    pub fn new(sink: impl EventSink + 'static) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            json: false,
+        }
+    }
+
+    /// Events are JSON lines on stdout, child programs must write to stderr
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.sink.event(&event);
+    }
+
+    pub fn status(&self, message: impl Into<String>) {
+        self.emit(Event::Status {
+            message: message.into(),
+        });
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new(ConsoleSink::default())
+    }
+}
+
+/// Prints events the way the `ostool` command does
+#[derive(Default)]
+pub struct ConsoleSink {
+    stderr: bool,
+    upload: Mutex<Option<ProgressBar>>,
+}
+
+impl EventSink for ConsoleSink {
+    fn event(&self, event: &Event) {
+        let mut out: Box<dyn Write> = match self.stderr {
+            true => Box::new(std::io::stderr().lock()),
+            false => Box::new(std::io::stdout().lock()),
+        };
+        let _ = match event {
+            Event::Command { command } => writeln!(out, "{}", command.purple().bold()),
+            Event::Env { key, value } => writeln!(out, "{}", format!("{key}={value}").cyan()),
+            Event::Status { message } => writeln!(out, "{message}"),
+            Event::BuildOutput { line } => writeln!(out, "{line}"),
+            Event::Artifact { kind, path } => writeln!(
+                out,
+                "{}",
+                format!("Generated {}: {}", kind.describe(), path.display())
+                    .bold()
+                    .purple()
+            ),
+            Event::SizeReport { report, previous } => {
+                write!(out, "{}", report.render(previous.as_ref()))
+            }
+            Event::Console { data } => {
+                // the serial terminal puts the tty in raw mode
+                if crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+                    out.write_all(data.replace('\n', "\r\n").as_bytes())
+                } else {
+                    out.write_all(data.as_bytes())
+                }
+            }
+            Event::Symbol { note } => write!(out, "{}\r\n", format!("  -> {note}").cyan()),
+            Event::PatternMatched {
+                pattern, success, ..
+            } => {
+                if *success {
+                    write!(
+                        out,
+                        "{}\r\n",
+                        format!("\r\n=== SUCCESS PATTERN MATCHED: '{pattern}' ===").green()
+                    )
+                } else {
+                    write!(
+                        out,
+                        "{}\r\n",
+                        format!("\r\n=== FAIL PATTERN MATCHED: '{pattern}' ===").red()
+                    )
+                }
+            }
+            Event::TestSummary { tests } => write!(out, "{}", harness::render_summary(tests)),
+            Event::Timeout { seconds } => write!(
+                out,
+                "{}\r\n",
+                format!("\r\n=== TIMEOUT AFTER {seconds}s ===").red()
+            ),
+            Event::TransferProgress { sent, total } => {
+                self.upload(*sent, *total);
+                Ok(())
+            }
+            Event::BuildStarted { .. }
+            | Event::CargoFinished { .. }
+            | Event::Objcopy { .. }
+            | Event::QemuSpawned { .. }
+            | Event::UbootPromptDetected
+            | Event::TestResult { .. }
+            | Event::Exit { .. }
+            | Event::Error { .. } => Ok(()),
+        };
+        let _ = out.flush();
+    }
+}
+
+impl ConsoleSink {
+    /// Prints to stderr instead of stdout
+    pub fn stderr() -> Self {
+        Self {
+            stderr: true,
+            ..Default::default()
+        }
+    }
+
+    fn upload(&self, sent: u64, total: u64) {
+        let mut upload = self.upload.lock().unwrap();
+        let pb = upload.get_or_insert_with(|| {
+            let pb = ProgressBar::new(total);
+            pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .with_key("eta", |state: &ProgressState, w: &mut dyn core::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+                .progress_chars("#>-"));
+            pb
+        });
+        pb.set_length(total);
+        pb.set_position(sent);
+        if sent >= total {
+            pb.finish_with_message("upload done");
+            *upload = None;
+        }
+    }
+}
+
+/// `--message-format json`: JSON lines on stdout, the console output on stderr
+pub struct JsonSink {
+    human: ConsoleSink,
+}
+
+impl Default for JsonSink {
+    fn default() -> Self {
+        Self {
+            human: ConsoleSink::stderr(),
+        }
+    }
+}
+
+impl EventSink for JsonSink {
+    fn event(&self, event: &Event) {
+        self.human.event(event);
+        // the console is only text, the pattern and exit events carry the result
+        if matches!(event, Event::Console { .. }) {
+            return;
+        }
+        if let Ok(line) = serde_json::to_string(event) {
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{line}");
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// Serializes a duration as seconds
+pub(crate) fn secs<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_events() {
+        let event = Event::Artifact {
+            kind: ArtifactKind::BinGz,
+            path: "kernel.bin.gz".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"artifact","kind":"bin_gz","path":"kernel.bin.gz"}"#
+        );
+
+        let event = Event::Exit {
+            runner: RunnerKind::Qemu,
+            exit: RunExit::Timeout { seconds: 30 },
+            duration: Duration::from_millis(30_500),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"exit","runner":"qemu","exit":{"reason":"timeout","seconds":30},"duration":30.5}"#
+        );
+        assert_eq!(
+            serde_json::to_value(Event::UbootPromptDetected).unwrap()["event"],
+            "uboot-prompt-detected"
+        );
+    }
+}
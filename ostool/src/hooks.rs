@@ -0,0 +1,374 @@
+//! Lifecycle hooks: shell commands run around the build and run stages.
+//!
+//! ```toml
+//! [[hooks.post_build]]
+//! cmd = "./scripts/pack.sh $KERNEL_BIN"
+//! cwd = "${workspaceFolder}"
+//! env = { BOARD = "rk3588" }
+//! timeout = 60
+//! allow_failure = true
+//! when = { runner = ["uboot"], profile = ["rk3588"] }
+//! ```
+//!
+//! With `ostool run` on a Cargo project the runner stages happen in
+//! `cargo-osrun`, the selected hooks are handed over in [`HOOKS_ENV`].
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::ctx::AppContext;
+
+/// Environment variable carrying the hooks from `ostool` to `cargo-osrun`
+pub const HOOKS_ENV: &str = "OSTOOL_HOOKS";
+
+/// Lines of output kept for the error message of a failed hook
+const ERROR_TAIL_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreBuild,
+    PostBuild,
+    PreRun,
+    PostRun,
+    OnFailure,
+}
+
+impl HookStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookStage::PreBuild => "pre_build",
+            HookStage::PostBuild => "post_build",
+            HookStage::PreRun => "pre_run",
+            HookStage::PostRun => "post_run",
+            HookStage::OnFailure => "on_failure",
+        }
+    }
+}
+
+/// Runner selected by `ostool run`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunnerKind {
+    Qemu,
+    Uboot,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Hooks {
+    /// before the kernel is built
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_build: Vec<Hook>,
+    /// after the kernel and its images are built
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_build: Vec<Hook>,
+    /// before QEMU or U-Boot is started
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_run: Vec<Hook>,
+    /// after QEMU or U-Boot finished successfully
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_run: Vec<Hook>,
+    /// when any stage failed, `OSTOOL_ERROR` holds the error
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_failure: Vec<Hook>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct Hook {
+    /// shell command, `KERNEL_ELF`, `KERNEL_BIN`, ... and `OSTOOL_HOOK_STAGE` are set
+    pub cmd: String,
+    /// working directory, relative to the manifest directory
+    pub cwd: Option<String>,
+    /// extra environment variables
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// seconds before the command is killed
+    pub timeout: Option<u64>,
+    /// only warn if the command fails
+    #[serde(default)]
+    pub allow_failure: bool,
+    /// run the hook only in some cases
+    #[serde(default)]
+    pub when: HookCondition,
+}
+
+/// All set fields must match. Unset fields match everything.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct HookCondition {
+    /// runners the hook applies to, never matches a plain `ostool build`
+    pub runner: Option<Vec<RunnerKind>>,
+    /// build profiles selected with `--profile`
+    pub profile: Option<Vec<String>>,
+}
+
+impl HookCondition {
+    pub fn matches(&self, runner: Option<RunnerKind>, profile: Option<&str>) -> bool {
+        let runner_ok = match &self.runner {
+            Some(runners) => runner.is_some_and(|r| runners.contains(&r)),
+            None => true,
+        };
+        let profile_ok = match &self.profile {
+            Some(profiles) => profile.is_some_and(|p| profiles.iter().any(|x| x == p)),
+            None => true,
+        };
+        runner_ok && profile_ok
+    }
+}
+
+impl Hooks {
+    pub fn stage(&self, stage: HookStage) -> &[Hook] {
+        match stage {
+            HookStage::PreBuild => &self.pre_build,
+            HookStage::PostBuild => &self.post_build,
+            HookStage::PreRun => &self.pre_run,
+            HookStage::PostRun => &self.post_run,
+            HookStage::OnFailure => &self.on_failure,
+        }
+    }
+
+    fn stage_mut(&mut self, stage: HookStage) -> &mut Vec<Hook> {
+        match stage {
+            HookStage::PreBuild => &mut self.pre_build,
+            HookStage::PostBuild => &mut self.post_build,
+            HookStage::PreRun => &mut self.pre_run,
+            HookStage::PostRun => &mut self.post_run,
+            HookStage::OnFailure => &mut self.on_failure,
+        }
+    }
+
+    /// The hooks whose `when` matches, with the condition cleared.
+    pub fn select(&self, runner: Option<RunnerKind>, profile: Option<&str>) -> Hooks {
+        let mut selected = Hooks::default();
+        for stage in ALL_STAGES {
+            *selected.stage_mut(stage) = self
+                .stage(stage)
+                .iter()
+                .filter(|h| h.when.matches(runner, profile))
+                .map(|h| Hook {
+                    when: HookCondition::default(),
+                    ..h.clone()
+                })
+                .collect();
+        }
+        selected
+    }
+
+    /// Hooks handed over by `ostool` in [`HOOKS_ENV`].
+    pub fn from_env() -> anyhow::Result<Hooks> {
+        match std::env::var(HOOKS_ENV) {
+            Ok(json) => {
+                serde_json::from_str(&json).map_err(|e| anyhow!("invalid `{HOOKS_ENV}`: {e}"))
+            }
+            Err(_) => Ok(Hooks::default()),
+        }
+    }
+
+    /// Runs the hooks of `stage` in order, stopping at the first failure
+    /// that is not allowed.
+    pub fn run(&self, ctx: &AppContext, stage: HookStage) -> anyhow::Result<()> {
+        for hook in self.stage(stage) {
+            match hook.run(ctx, stage) {
+                Ok(()) => {}
+                Err(e) if hook.allow_failure => {
+                    warn!("{} hook failed, ignored: {e:#}", stage.name())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+const ALL_STAGES: [HookStage; 5] = [
+    HookStage::PreBuild,
+    HookStage::PostBuild,
+    HookStage::PreRun,
+    HookStage::PostRun,
+    HookStage::OnFailure,
+];
+
+impl Hook {
+    /// A plain command from the old `pre_build_cmds`/`post_build_cmds` lists
+    pub fn shell(cmd: impl Into<String>) -> Self {
+        Self {
+            cmd: cmd.into(),
+            cwd: None,
+            env: HashMap::new(),
+            timeout: None,
+            allow_failure: false,
+            when: HookCondition::default(),
+        }
+    }
+
+    fn run(&self, ctx: &AppContext, stage: HookStage) -> anyhow::Result<()> {
+        let mut command = ctx.shell_command(&self.cmd);
+        if let Some(cwd) = &self.cwd {
+            let cwd = ctx.value_replace_with_var(cwd);
+            command.current_dir(ctx.paths.manifest.join(Path::new(&cwd)));
+        }
+        command.env("OSTOOL_HOOK_STAGE", stage.name());
+        for (k, v) in &self.env {
+            command.env(k, v);
+        }
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+        command.print_cmd();
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!("{} hook `{}`: {e}", stage.name(), self.cmd))?;
+
+        let output = Arc::new(Mutex::new(Vec::<String>::new()));
+        let readers = [
+            child
+                .stdout
+                .take()
+                .map(|s| capture(s, stage, output.clone())),
+            child
+                .stderr
+                .take()
+                .map(|s| capture(s, stage, output.clone())),
+        ];
+
+        let deadline = self
+            .timeout
+            .map(|t| Instant::now() + Duration::from_secs(t));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                let _ = child.kill();
+                let _ = child.wait();
+                // Children of the shell may still hold the pipes, do not wait for the readers.
+                bail!(
+                    "{} hook `{}` timed out after {}s",
+                    stage.name(),
+                    self.cmd,
+                    self.timeout.unwrap_or_default()
+                );
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        for reader in readers.into_iter().flatten() {
+            let _ = reader.join();
+        }
+
+        if !status.success() {
+            let output = output.lock().unwrap();
+            let tail = &output[output.len().saturating_sub(ERROR_TAIL_LINES)..];
+            bail!(
+                "{} hook `{}` failed with {status}{}",
+                stage.name(),
+                self.cmd,
+                if tail.is_empty() {
+                    String::new()
+                } else {
+                    format!(":\n  {}", tail.join("\n  "))
+                }
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Logs every line of `stream` and keeps it for error messages.
+fn capture(
+    stream: impl Read + Send + 'static,
+    stage: HookStage,
+    output: Arc<Mutex<Vec<String>>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else { break };
+            info!("[{}] {line}", stage.name());
+            output.lock().unwrap().push(line);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_by_condition() {
+        let hooks: Hooks = toml::from_str(
+            r#"
+[[pre_build]]
+cmd = "always"
+
+[[pre_build]]
+cmd = "qemu only"
+when = { runner = ["qemu"] }
+
+[[post_run]]
+cmd = "rk3588 on board"
+when = { runner = ["uboot"], profile = ["rk3588"] }
+"#,
+        )
+        .unwrap();
+
+        let cmds = |h: &Hooks, stage| {
+            h.stage(stage)
+                .iter()
+                .map(|h| h.cmd.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let build = hooks.select(None, Some("rk3588"));
+        assert_eq!(cmds(&build, HookStage::PreBuild), "always");
+        assert!(build.post_run.is_empty());
+
+        let qemu = hooks.select(Some(RunnerKind::Qemu), None);
+        assert_eq!(cmds(&qemu, HookStage::PreBuild), "always,qemu only");
+
+        let board = hooks.select(Some(RunnerKind::Uboot), Some("rk3588"));
+        assert_eq!(cmds(&board, HookStage::PostRun), "rk3588 on board");
+        assert_eq!(board.post_run[0].when, HookCondition::default());
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let ctx = AppContext {
+            paths: crate::ctx::PathConfig {
+                workspace: std::env::temp_dir(),
+                manifest: std::env::temp_dir(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut hook = Hook::shell("echo $OSTOOL_HOOK_STAGE $NAME; exit 3");
+        hook.env.insert("NAME".into(), "hook".into());
+        let err = hook.run(&ctx, HookStage::PreRun).unwrap_err().to_string();
+        assert!(err.starts_with("pre_run hook `echo"), "{err}");
+        assert!(err.ends_with("\n  pre_run hook"), "{err}");
+
+        let hooks = Hooks {
+            post_build: vec![Hook {
+                allow_failure: true,
+                ..Hook::shell("exit 1")
+            }],
+            ..Default::default()
+        };
+        hooks.run(&ctx, HookStage::PostBuild).unwrap();
+
+        let slow = Hook {
+            timeout: Some(0),
+            ..Hook::shell("sleep 5")
+        };
+        let err = slow.run(&ctx, HookStage::PreBuild).unwrap_err().to_string();
+        assert!(err.contains("timed out"), "{err}");
+    }
+}
@@ -0,0 +1,268 @@
+//! `ostool init`: writes `.build.toml`, `.qemu.toml` and `.uboot.toml` for a
+//! kernel package, with their JSON schemas.
+
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use cargo_metadata::{Package, TargetKind};
+use colored::Colorize;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{
+    build::{
+        config::{BuildConfig, BuildSystem, Cargo},
+        target::TargetSpec,
+    },
+    config::schema::{self, SchemaKind},
+    ctx::AppContext,
+    run::{qemu::QemuConfig, uboot::UbootConfig},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct InitArgs {
+    /// Kernel package, asked for when the workspace has several bin packages
+    pub package: Option<String>,
+    /// Overrides the target found in `.cargo/config.toml` or `rust-toolchain.toml`
+    pub target: Option<String>,
+    /// Overwrite existing config files
+    pub force: bool,
+}
+
+pub async fn init(ctx: &AppContext, args: InitArgs) -> anyhow::Result<()> {
+    let meta = ctx
+        .metadata()
+        .context("`ostool init` must be run in a cargo project")?;
+    let workspace = &ctx.paths.workspace;
+
+    let candidates = meta
+        .workspace_packages()
+        .into_iter()
+        .filter(|p| p.targets.iter().any(|t| t.is_kind(TargetKind::Bin)))
+        .collect::<Vec<_>>();
+    let package = select_package(ctx, &candidates, args.package.as_deref())?;
+
+    let target = match args.target {
+        Some(target) => target,
+        None => detect_target(workspace).ok_or_else(|| {
+            anyhow!("no target in `.cargo/config.toml` or `rust-toolchain.toml`, pass `--target`")
+        })?,
+    };
+    let arch = TargetSpec::resolve(&target, workspace)?.arch;
+    info!("Package `{}`, target `{target}` ({arch:?})", package.name);
+
+    let qemu = QemuConfig::template(arch);
+    let bins = package
+        .targets
+        .iter()
+        .filter(|t| t.is_kind(TargetKind::Bin))
+        .collect::<Vec<_>>();
+    let bin = match bins.as_slice() {
+        [_] => None,
+        bins => bins
+            .iter()
+            .find(|t| t.name == *package.name)
+            .or(bins.first())
+            .map(|t| t.name.clone()),
+    };
+    let build = BuildConfig {
+        system: BuildSystem::Cargo(Cargo {
+            target,
+            package: package.name.to_string(),
+            bin,
+            to_bin: qemu.to_bin,
+            ..Default::default()
+        }),
+        profiles: Default::default(),
+        hooks: Default::default(),
+        userspace: vec![],
+        initramfs: None,
+        disk: None,
+    };
+
+    write_config(ctx, SchemaKind::Build, &build, args.force).await?;
+    write_config(ctx, SchemaKind::Qemu, &qemu, args.force).await?;
+    let uboot = UbootConfig::template();
+    write_config(ctx, SchemaKind::Uboot, &uboot, args.force).await?;
+    Ok(())
+}
+
+fn select_package<'a>(
+    ctx: &AppContext,
+    packages: &[&'a Package],
+    name: Option<&str>,
+) -> anyhow::Result<&'a Package> {
+    let names = || {
+        packages
+            .iter()
+            .map(|p| p.name.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if let Some(name) = name {
+        return packages
+            .iter()
+            .find(|p| *p.name == name)
+            .copied()
+            .ok_or_else(|| anyhow!("no bin package `{name}`, available: [{}]", names()));
+    }
+
+    match packages {
+        [] => bail!("no package with a bin target in this workspace"),
+        [only] => Ok(only),
+        _ if !std::io::stdin().is_terminal() || ctx.events.is_json() => {
+            warn!("Several bin packages [{}], using the first", names());
+            Ok(packages[0])
+        }
+        _ => {
+            ctx.events.status("Bin packages:");
+            for (i, p) in packages.iter().enumerate() {
+                ctx.events.status(format!("  {}) {}", i + 1, p.name));
+            }
+            loop {
+                print!("Kernel package [1]: ");
+                std::io::stdout().flush()?;
+                let mut line = String::new();
+                std::io::stdin().lock().read_line(&mut line)?;
+                let line = line.trim();
+                if line.is_empty() {
+                    return Ok(packages[0]);
+                }
+                match line.parse::<usize>() {
+                    Ok(i) if (1..=packages.len()).contains(&i) => return Ok(packages[i - 1]),
+                    _ => {
+                        if let Some(p) = packages.iter().find(|p| *p.name == line) {
+                            return Ok(p);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `build.target` of the cargo config files cargo would read in `dir`, then
+/// the first of `toolchain.targets` in the rust toolchain file.
+pub fn detect_target(dir: &Path) -> Option<String> {
+    for ancestor in dir.ancestors() {
+        for name in [".cargo/config.toml", ".cargo/config"] {
+            let path = ancestor.join(name);
+            let Some(table) = read_table(&path) else {
+                continue;
+            };
+            let target = match table.get("build").and_then(|b| b.get("target")) {
+                Some(toml::Value::String(t)) => Some(t.clone()),
+                Some(toml::Value::Array(a)) => a.first().and_then(|t| t.as_str()).map(String::from),
+                _ => None,
+            };
+            if let Some(target) = target {
+                return Some(relative_spec(&target, ancestor, dir));
+            }
+        }
+    }
+
+    for name in ["rust-toolchain.toml", "rust-toolchain"] {
+        let Some(table) = read_table(&dir.join(name)) else {
+            continue;
+        };
+        let target = table
+            .get("toolchain")
+            .and_then(|t| t.get("targets"))
+            .and_then(|t| t.as_array())
+            .and_then(|t| t.first())
+            .and_then(|t| t.as_str());
+        if let Some(target) = target {
+            return Some(target.to_string());
+        }
+    }
+    None
+}
+
+/// Cargo resolves a spec path against the parent of `.cargo`, the build config against `dir`.
+fn relative_spec(target: &str, base: &Path, dir: &Path) -> String {
+    if !target.ends_with(".json") || Path::new(target).is_absolute() {
+        return target.to_string();
+    }
+    let path = base.join(target);
+    path.strip_prefix(dir)
+        .map(Path::to_path_buf)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// `None` if missing or not TOML, a legacy `rust-toolchain` may hold a bare channel name.
+fn read_table(path: &Path) -> Option<toml::Table> {
+    std::fs::read_to_string(path).ok()?.parse().ok()
+}
+
+async fn write_config<C: Serialize>(
+    ctx: &AppContext,
+    kind: SchemaKind,
+    config: &C,
+    force: bool,
+) -> anyhow::Result<()> {
+    let path = &ctx.paths.workspace.join(kind.config_file());
+    let schema_path = schema::write_schema(kind, path).await?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if path.exists() && !force {
+        ctx.events.status(format!(
+            "{} {name} exists, pass --force to overwrite",
+            "Skipped".yellow()
+        ));
+        return Ok(());
+    }
+
+    let content = toml::to_string_pretty(config)?;
+    fs::write(
+        path,
+        schema::with_schema_header(&content, &schema::schema_reference(path)),
+    )
+    .await
+    .with_context(|| format!("can not write {}", path.display()))?;
+    ctx.events.status(format!(
+        "{} {name} ({})",
+        "Created".green(),
+        schema_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_target() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let project = dir.join("kernel");
+        std::fs::create_dir_all(project.join(".cargo")).unwrap();
+
+        std::fs::write(
+            project.join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly\"\ntargets = [\"riscv64gc-unknown-none-elf\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect_target(&project).as_deref(),
+            Some("riscv64gc-unknown-none-elf")
+        );
+
+        std::fs::write(
+            project.join(".cargo/config.toml"),
+            "[build]\ntarget = [\"aarch64-unknown-none\", \"x86_64-unknown-none\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect_target(&project).as_deref(),
+            Some("aarch64-unknown-none")
+        );
+    }
+}
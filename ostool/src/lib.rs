@@ -1,10 +1,17 @@
 #![cfg(not(target_os = "none"))]
 
 pub mod build;
+pub mod config;
 pub mod ctx;
+pub mod event;
+pub mod hooks;
+pub mod init;
 pub mod menuconfig;
+pub mod objcopy;
 pub mod run;
+pub mod session;
 pub mod sterm;
+pub mod symbolize;
 pub mod utils;
 
 #[macro_use]
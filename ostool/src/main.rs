@@ -2,11 +2,20 @@ use std::{env::current_dir, path::PathBuf};
 
 use anyhow::Result;
 use clap::*;
+use colored::Colorize;
 
 use log::info;
 use ostool::{
     build::{self, CargoRunnerKind},
+    config::{
+        overrides::Overrides,
+        schema::{self, SchemaKind},
+        validate::{self, Severity},
+    },
     ctx::AppContext,
+    event::{Event, MessageFormat},
+    hooks::RunnerKind,
+    init::InitArgs,
     menuconfig::{MenuConfigHandler, MenuConfigMode},
     run::{qemu::RunQemuArgs, uboot::RunUbootArgs},
 };
@@ -16,6 +25,9 @@ use ostool::{
 struct Cli {
     #[arg(short, long)]
     workdir: Option<PathBuf>,
+    /// Use cached remote configs instead of downloading them
+    #[arg(long, global = true)]
+    offline: bool,
     #[command(subcommand)]
     command: SubCommands,
 }
@@ -26,20 +38,103 @@ enum SubCommands {
         /// Path to the build configuration file
         #[arg(short, long)]
         config: Option<PathBuf>,
+        /// Build profile defined in the `profiles` table
+        #[arg(long)]
+        profile: Option<String>,
+        /// Override a config key, e.g. `qemu.uefi=true` or `build.system.Cargo.features+=foo`
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        set: Vec<String>,
+        /// `json` prints one event per line on stdout and the usual output on stderr
+        #[arg(long, value_enum, default_value = "human")]
+        message_format: MessageFormat,
     },
     Run(RunArgs),
     Menuconfig {
         /// Menu configuration mode (qemu or uboot)
         #[arg(value_enum)]
         mode: Option<MenuConfigMode>,
+        /// Build profile defined in the `profiles` table
+        #[arg(long)]
+        profile: Option<String>,
+    },
+    /// Resolve kernel addresses to `function at file:line`
+    Addr2line {
+        /// ELF file, default to the last built one
+        #[arg(short, long)]
+        elf: Option<PathBuf>,
+        /// Hex addresses; console lines are read from stdin if none are given
+        addresses: Vec<String>,
+    },
+    /// Write `.build.toml`, `.qemu.toml` and `.uboot.toml` for this project
+    Init {
+        /// Kernel package, asked for when there are several bin packages
+        #[arg(short, long)]
+        package: Option<String>,
+        /// Target triple or spec, detected from `.cargo/config.toml` or `rust-toolchain.toml`
+        #[arg(short, long)]
+        target: Option<String>,
+        /// Overwrite existing config files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write the JSON schemas of the config files
+    Schema {
+        /// Config type
+        #[arg(value_enum, default_value = "all")]
+        kind: SchemaKind,
+        /// Output directory, default to the workspace
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// Add a `#:schema` comment pointing to the schema to existing config files
+        #[arg(long)]
+        annotate: bool,
+    },
+    /// Check and manage config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage the cache of downloaded configs
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Check config files against their schemas, without opening the UI
+    Validate {
+        /// Config files, default to the `.build.toml`, `.qemu.toml` and `.uboot.toml` found
+        files: Vec<PathBuf>,
+        /// Config type, guessed from the file name by default
+        #[arg(long, value_enum)]
+        kind: Option<SchemaKind>,
     },
 }
 
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove all cached downloads
+    Clean,
+    /// Print the cache directory
+    Dir,
+}
+
 #[derive(Args, Debug)]
 struct RunArgs {
     /// Path to the build configuration file
     #[arg(short, long)]
     config: Option<PathBuf>,
+    /// Build profile defined in the `profiles` table
+    #[arg(long)]
+    profile: Option<String>,
+    /// Override a config key, e.g. `qemu.uefi=true` or `uboot.baud_rate=1500000`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    set: Vec<String>,
+    /// `json` prints one event per line on stdout and the usual output on stderr
+    #[arg(long, value_enum, default_value = "human")]
+    message_format: MessageFormat,
     #[command(subcommand)]
     command: RunSubCommands,
 }
@@ -94,73 +189,226 @@ async fn main() -> Result<()> {
             manifest: workspace_folder.clone(),
             ..Default::default()
         },
+        offline: cli.offline,
         ..Default::default()
     };
 
     match cli.command {
-        SubCommands::Build { config } => {
-            ctx.build(config).await?;
+        SubCommands::Build {
+            config,
+            profile,
+            set,
+            message_format,
+        } => {
+            ctx.events = message_format.events();
+            ctx.build_profile = profile;
+            let res = match overrides(&set) {
+                Ok(overrides) => {
+                    ctx.overrides = overrides;
+                    ctx.build(config).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &res {
+                ctx.run_failure_hooks(e);
+                report_error(&ctx, e);
+            }
+            res?;
         }
         SubCommands::Run(args) => {
-            let config = ctx.prepare_build_config(args.config, false).await?;
-            match config.system {
-                build::config::BuildSystem::Cargo(config) => {
-                    let kind = match args.command {
-                        RunSubCommands::Qemu(qemu_args) => CargoRunnerKind::Qemu {
+            ctx.events = args.message_format.events();
+            ctx.build_profile = args.profile;
+            ctx.runner = Some(match &args.command {
+                RunSubCommands::Qemu(_) => RunnerKind::Qemu,
+                RunSubCommands::Uboot(_) => RunnerKind::Uboot,
+            });
+            let res = match overrides(&args.set) {
+                Ok(overrides) => {
+                    ctx.overrides = overrides;
+                    run(&mut ctx, args.config, args.command).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &res {
+                ctx.run_failure_hooks(e);
+                report_error(&ctx, e);
+            }
+            res?;
+        }
+        SubCommands::Menuconfig { mode, profile } => {
+            ctx.build_profile = profile;
+            MenuConfigHandler::handle_menuconfig(&mut ctx, mode).await?;
+        }
+        SubCommands::Addr2line { elf, addresses } => {
+            let elf = match elf {
+                Some(elf) => elf,
+                None => ctx.last_elf().ok_or_else(|| {
+                    anyhow::anyhow!("No ELF has been built yet, run `ostool build` or pass --elf")
+                })?,
+            };
+            ostool::symbolize::addr2line(&elf, &addresses)?;
+        }
+        SubCommands::Init {
+            package,
+            target,
+            force,
+        } => {
+            ostool::init::init(
+                &ctx,
+                InitArgs {
+                    package,
+                    target,
+                    force,
+                },
+            )
+            .await?;
+        }
+        SubCommands::Schema {
+            kind,
+            out,
+            annotate,
+        } => {
+            let out = out.unwrap_or_else(|| ctx.paths.workspace.clone());
+            tokio::fs::create_dir_all(&out).await?;
+            for kind in kind.expand() {
+                let schema = out.join(kind.schema_file());
+                schema::write_schema_to(kind, &schema).await?;
+                println!("Wrote {}", schema.display());
+                let config = ctx.paths.workspace.join(kind.config_file());
+                if annotate && config.exists() && schema::annotate(&config, &schema).await? {
+                    println!("Annotated {}", config.display());
+                }
+            }
+        }
+        SubCommands::Config { command } => match command {
+            ConfigCommand::Validate { files, kind } => validate(&ctx, files, kind).await?,
+        },
+        SubCommands::Cache { command } => match command {
+            CacheCommand::Clean => match ostool::config::remote::clean_cache()? {
+                Some(dir) => println!("Removed {}", dir.display()),
+                None => println!("Cache is empty"),
+            },
+            CacheCommand::Dir => println!("{}", ostool::config::remote::cache_dir().display()),
+        },
+    }
+
+    Ok(())
+}
+
+/// The error as an event too, so a JSON reader sees why the command failed
+fn report_error(ctx: &AppContext, err: &anyhow::Error) {
+    if ctx.events.is_json() {
+        ctx.events.emit(Event::Error {
+            message: format!("{err:#}"),
+        });
+    }
+}
+
+/// `OSTOOL_*` variables, then `--set`
+fn overrides(set: &[String]) -> Result<Overrides> {
+    let mut overrides = Overrides::from_env()?;
+    overrides.extend(Overrides::parse(set)?);
+    Ok(overrides)
+}
+
+async fn validate(ctx: &AppContext, files: Vec<PathBuf>, kind: Option<SchemaKind>) -> Result<()> {
+    let files = match files.is_empty() {
+        true => SchemaKind::All
+            .expand()
+            .into_iter()
+            .map(|k| ctx.paths.workspace.join(k.config_file()))
+            .filter(|f| f.exists())
+            .collect(),
+        false => files,
+    };
+    if files.is_empty() {
+        anyhow::bail!("no config files found, run `ostool init` to create them");
+    }
+
+    let (mut errors, mut warnings) = (0, 0);
+    for file in files {
+        let kind = match kind.filter(|k| *k != SchemaKind::All) {
+            Some(kind) => kind,
+            None => SchemaKind::from_file_name(&file).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "can not tell the config type of {}, pass --kind",
+                    file.display()
+                )
+            })?,
+        };
+        let diagnostics = validate::validate_file(kind, &file).await?;
+        if diagnostics.is_empty() {
+            println!("{} {}", "ok".green().bold(), file.display());
+        }
+        for diagnostic in diagnostics {
+            match diagnostic.severity {
+                Severity::Error => errors += 1,
+                Severity::Warning => warnings += 1,
+            }
+            println!("{diagnostic}");
+        }
+    }
+
+    if errors > 0 {
+        anyhow::bail!("{errors} error(s), {warnings} warning(s) in config files");
+    }
+    if warnings > 0 {
+        println!("{warnings} warning(s)");
+    }
+    Ok(())
+}
+
+async fn run(ctx: &mut AppContext, config: Option<PathBuf>, command: RunSubCommands) -> Result<()> {
+    let config = ctx.prepare_build_config(config, false).await?;
+    match &config.system {
+        build::config::BuildSystem::Cargo(cargo) => {
+            let kind = match command {
+                RunSubCommands::Qemu(qemu_args) => CargoRunnerKind::Qemu {
+                    qemu_config: qemu_args.qemu_config,
+                    debug: qemu_args.debug,
+                    dtb_dump: qemu_args.dtb_dump,
+                },
+                RunSubCommands::Uboot(uboot_args) => CargoRunnerKind::Uboot {
+                    uboot_config: uboot_args.uboot_config,
+                },
+            };
+            ctx.cargo_run(cargo, &kind).await?;
+        }
+        _ => {
+            ctx.build_with_config(&config).await?;
+            info!(
+                "ELF {:?}: {}",
+                ctx.arch,
+                ctx.paths.artifacts.elf.as_ref().unwrap().display()
+            );
+
+            match command {
+                RunSubCommands::Qemu(qemu_args) => {
+                    ostool::run::qemu::run_qemu(
+                        ctx.clone(),
+                        RunQemuArgs {
                             qemu_config: qemu_args.qemu_config,
-                            debug: qemu_args.debug,
                             dtb_dump: qemu_args.dtb_dump,
+                            show_output: true,
+                            cmdline_args: Vec::new(),
                         },
-                        RunSubCommands::Uboot(uboot_args) => CargoRunnerKind::Uboot {
-                            uboot_config: uboot_args.uboot_config,
-                        },
-                    };
-                    ctx.cargo_run(&config, &kind).await?;
+                    )
+                    .await?;
                 }
-                build::config::BuildSystem::Custom(custom_cfg) => {
-                    ctx.shell_run_cmd(&custom_cfg.build_cmd)?;
-                    ctx.set_elf_path(custom_cfg.elf_path.clone().into()).await;
-                    info!(
-                        "ELF {:?}: {}",
-                        ctx.arch,
-                        ctx.paths.artifacts.elf.as_ref().unwrap().display()
-                    );
-
-                    if custom_cfg.to_bin {
-                        ctx.objcopy_output_bin()?;
-                    }
-
-                    match args.command {
-                        RunSubCommands::Qemu(qemu_args) => {
-                            ostool::run::qemu::run_qemu(
-                                ctx,
-                                RunQemuArgs {
-                                    qemu_config: qemu_args.qemu_config,
-                                    dtb_dump: qemu_args.dtb_dump,
-                                    show_output: true,
-                                },
-                            )
-                            .await?;
-                        }
-                        RunSubCommands::Uboot(uboot_args) => {
-                            ostool::run::uboot::run_uboot(
-                                ctx,
-                                RunUbootArgs {
-                                    config: uboot_args.uboot_config,
-                                    show_output: true,
-                                },
-                            )
-                            .await?;
-                        }
-                    }
+                RunSubCommands::Uboot(uboot_args) => {
+                    ostool::run::uboot::run_uboot(
+                        ctx.clone(),
+                        RunUbootArgs {
+                            config: uboot_args.uboot_config,
+                            show_output: true,
+                            cmdline_args: Vec::new(),
+                        },
+                    )
+                    .await?;
                 }
             }
         }
-        SubCommands::Menuconfig { mode } => {
-            MenuConfigHandler::handle_menuconfig(&mut ctx, mode).await?;
-        }
     }
-
     Ok(())
 }
 
@@ -170,6 +418,7 @@ impl From<QemuArgs> for RunQemuArgs {
             qemu_config: value.qemu_config,
             dtb_dump: value.dtb_dump,
             show_output: true,
+            cmdline_args: Vec::new(),
         }
     }
 }
@@ -179,6 +428,7 @@ impl From<UbootArgs> for RunUbootArgs {
         RunUbootArgs {
             config: value.uboot_config,
             show_output: true,
+            cmdline_args: Vec::new(),
         }
     }
 }
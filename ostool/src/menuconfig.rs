@@ -1,8 +1,11 @@
+use std::path::Path;
+
 use anyhow::Result;
 use clap::ValueEnum;
 use log::info;
 use tokio::fs;
 
+use crate::config::schema::keep_schema_header;
 use crate::ctx::AppContext;
 use crate::run::qemu::QemuConfig;
 use crate::run::uboot::UbootConfig;
@@ -45,23 +48,24 @@ impl MenuConfigHandler {
         info!("配置 QEMU 运行参数");
         let config_path = ctx.paths.workspace.join(".qemu.toml");
         if config_path.exists() {
-            println!("\n当前 U-Boot 配置文件: {}", config_path.display());
+            ctx.events
+                .status(format!("当前 U-Boot 配置文件: {}", config_path.display()));
             // 这里可以读取并显示当前的 U-Boot 配置
         } else {
-            println!("\n未找到 U-Boot 配置文件，将使用默认配置");
+            ctx.events.status("未找到 U-Boot 配置文件，将使用默认配置");
         }
 
-        let config = jkconfig::run::<QemuConfig>(config_path, true, &[]).await?;
+        ensure_not_layered(&config_path).await?;
+        let config = jkconfig::run::<QemuConfig>(&config_path, true, &[]).await?;
 
         if let Some(c) = config {
-            fs::write(
-                ctx.value_replace_with_var(ctx.paths.workspace.join(".qemu.toml")),
-                toml::to_string_pretty(&c)?,
-            )
-            .await?;
-            println!("\nQEMU 配置已保存到 .qemu.toml");
+            let path = ctx.value_replace_with_var(ctx.paths.workspace.join(".qemu.toml"));
+            let previous = fs::read_to_string(&path).await.unwrap_or_default();
+            let content = keep_schema_header(&previous, toml::to_string_pretty(&c)?);
+            fs::write(path, content).await?;
+            ctx.events.status("QEMU 配置已保存到 .qemu.toml");
         } else {
-            println!("\n未更改 QEMU 配置");
+            ctx.events.status("未更改 QEMU 配置");
         }
 
         Ok(())
@@ -70,28 +74,43 @@ impl MenuConfigHandler {
     async fn handle_uboot_config(ctx: &mut AppContext) -> Result<()> {
         info!("配置 U-Boot 运行参数");
 
-        println!("=== U-Boot 配置模式 ===");
+        ctx.events.status("=== U-Boot 配置模式 ===");
 
         // 检查是否存在 U-Boot 配置文件
         let uboot_config_path = ctx.paths.workspace.join(".uboot.toml");
         if uboot_config_path.exists() {
-            println!("\n当前 U-Boot 配置文件: {}", uboot_config_path.display());
+            ctx.events.status(format!(
+                "当前 U-Boot 配置文件: {}",
+                uboot_config_path.display()
+            ));
             // 这里可以读取并显示当前的 U-Boot 配置
         } else {
-            println!("\n未找到 U-Boot 配置文件，将使用默认配置");
+            ctx.events.status("未找到 U-Boot 配置文件，将使用默认配置");
         }
+        ensure_not_layered(&uboot_config_path).await?;
         let config = jkconfig::run::<UbootConfig>(uboot_config_path, true, &[]).await?;
         if let Some(c) = config {
-            fs::write(
-                ctx.value_replace_with_var(ctx.paths.workspace.join(".uboot.toml")),
-                toml::to_string_pretty(&c)?,
-            )
-            .await?;
-            println!("\nU-Boot 配置已保存到 .uboot.toml");
+            let path = ctx.value_replace_with_var(ctx.paths.workspace.join(".uboot.toml"));
+            let previous = fs::read_to_string(&path).await.unwrap_or_default();
+            let content = keep_schema_header(&previous, toml::to_string_pretty(&c)?);
+            fs::write(path, content).await?;
+            ctx.events.status("U-Boot 配置已保存到 .uboot.toml");
         } else {
-            println!("\n未更改 U-Boot 配置");
+            ctx.events.status("未更改 U-Boot 配置");
         }
 
         Ok(())
     }
 }
+
+/// The menu rewrites the whole file, which would flatten `extends`/`include`.
+async fn ensure_not_layered(config_path: &Path) -> Result<()> {
+    let content = fs::read_to_string(config_path).await.unwrap_or_default();
+    if crate::config::uses_layers(&content) {
+        anyhow::bail!(
+            "{} uses `extends`/`include` and can not be edited in the menu, edit it directly",
+            config_path.display()
+        );
+    }
+    Ok(())
+}
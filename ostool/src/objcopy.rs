@@ -0,0 +1,514 @@
+//! In-process replacement for `rust-objcopy -O binary`.
+//!
+//! The output matches `rust-objcopy --strip-all -O binary`: every allocated
+//! section with file contents is written at its load address (LMA), gaps are
+//! filled with zeros, and symbols, debug info and other non-allocated sections
+//! are left out.
+
+use std::ops::Range;
+
+use byte_unit::Byte;
+use object::{
+    Endianness, FileKind, elf,
+    read::elf::{ElfFile, FileHeader, ProgramHeader, SectionHeader},
+};
+
+/// Gaps larger than this are reported as a warning.
+pub const WARN_GAP: u64 = 16 * 1024 * 1024;
+/// Images larger than this are refused, they are almost always a linker
+/// script placing data in a distant memory region.
+pub const MAX_IMAGE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Loadable contents of an ELF file, grouped by load address
+#[derive(Debug, Clone)]
+pub struct LoadImage {
+    /// Entry point from the ELF header
+    pub entry: u64,
+    /// Allocated sections with file contents, sorted by LMA
+    pub regions: Vec<Region>,
+}
+
+/// One allocated section placed at its load address
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    pub lma: u64,
+    pub data: Vec<u8>,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.lma + self.data.len() as u64
+    }
+}
+
+/// Raw binary image built from an ELF file
+#[derive(Debug, Clone)]
+pub struct BinaryImage {
+    /// Load address of the first byte
+    pub base: u64,
+    pub data: Vec<u8>,
+}
+
+/// A piece of the ELF file placed at `lma`
+#[derive(Debug, Clone)]
+struct Chunk {
+    name: String,
+    lma: u64,
+    file_range: Range<usize>,
+}
+
+/// Converts the ELF file in `data` to a flat binary image.
+pub fn elf_to_binary(data: &[u8]) -> anyhow::Result<BinaryImage> {
+    LoadImage::parse(data)?.to_binary()
+}
+
+impl LoadImage {
+    /// Collects the loadable contents of the ELF file in `data`.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let (entry, chunks) = match FileKind::parse(data)? {
+            FileKind::Elf32 => load_chunks::<elf::FileHeader32<Endianness>>(data)?,
+            FileKind::Elf64 => load_chunks::<elf::FileHeader64<Endianness>>(data)?,
+            kind => bail!("not an ELF file: {kind:?}"),
+        };
+
+        if chunks.is_empty() {
+            bail!("ELF file has no loadable data");
+        }
+
+        let regions = chunks
+            .into_iter()
+            .map(|c| Region {
+                name: c.name,
+                lma: c.lma,
+                data: data[c.file_range].to_vec(),
+            })
+            .collect();
+
+        Ok(Self { entry, regions })
+    }
+
+    /// Lowest and highest load address
+    fn bounds(&self) -> (u64, u64) {
+        let base = self.regions.iter().map(|r| r.lma).min().unwrap_or(0);
+        let end = self.regions.iter().map(|r| r.end()).max().unwrap_or(base);
+        (base, end)
+    }
+
+    /// Flat image from the lowest load address, gaps filled with zeros.
+    pub fn to_binary(&self) -> anyhow::Result<BinaryImage> {
+        let (base, end) = self.bounds();
+        check_gaps(&self.regions, base, end)?;
+
+        let mut image = vec![0u8; (end - base) as usize];
+        for region in &self.regions {
+            let start = (region.lma - base) as usize;
+            image[start..start + region.data.len()].copy_from_slice(&region.data);
+        }
+
+        Ok(BinaryImage { base, data: image })
+    }
+
+    /// Intel HEX with extended linear address records.
+    pub fn to_ihex(&self) -> anyhow::Result<String> {
+        let (_, end) = self.bounds();
+        if end > 1 << 32 {
+            bail!(
+                "Intel HEX can not address {:#x}, the limit is 4 GiB",
+                end - 1
+            );
+        }
+
+        // Same record layout as `llvm-objcopy -O ihex`: segment records (02)
+        // below 1 MiB, linear records (04) above, CRLF line endings.
+        let mut out = String::new();
+        let mut segment = 0u64;
+        let mut base = 0u64;
+        for region in &self.regions {
+            let mut addr = region.lma;
+            let mut data = region.data.as_slice();
+            while !data.is_empty() {
+                if addr > base + segment + 0xffff {
+                    if addr > 0xf_ffff {
+                        if segment != 0 {
+                            segment = 0;
+                            push_ihex(&mut out, 0, 0x02, &[0, 0]);
+                        }
+                        base = addr & 0xffff_0000;
+                        push_ihex(&mut out, 0, 0x04, &((base >> 16) as u16).to_be_bytes());
+                    } else {
+                        segment = addr & 0xf_0000;
+                        push_ihex(&mut out, 0, 0x02, &((segment >> 4) as u16).to_be_bytes());
+                    }
+                }
+
+                let offset = addr - base - segment;
+                // A record must not cross a 64 KiB boundary.
+                let len = data.len().min(RECORD_LEN).min((0x1_0000 - offset) as usize);
+                push_ihex(&mut out, offset as u16, 0x00, &data[..len]);
+                addr += len as u64;
+                data = &data[len..];
+            }
+        }
+
+        if self.entry <= 0xf_ffff {
+            let start = ((self.entry & 0xf_0000) << 12) | (self.entry & 0xffff);
+            push_ihex(&mut out, 0, 0x03, &(start as u32).to_be_bytes());
+        } else if self.entry <= u32::MAX as u64 {
+            push_ihex(&mut out, 0, 0x05, &(self.entry as u32).to_be_bytes());
+        }
+        push_ihex(&mut out, 0, 0x01, &[]);
+        Ok(out)
+    }
+
+    /// Motorola S-record. Uses the shortest address width that fits.
+    pub fn to_srec(&self, header: &str) -> anyhow::Result<String> {
+        let (_, end) = self.bounds();
+        let max = end.saturating_sub(1).max(self.entry);
+        let (data_type, term_type, addr_len) = match max {
+            0..=0xffff => (1, 9, 2),
+            0x1_0000..=0xff_ffff => (2, 8, 3),
+            0x100_0000..=0xffff_ffff => (3, 7, 4),
+            _ => bail!("S-record can not address {max:#x}, the limit is 4 GiB"),
+        };
+
+        let mut out = String::new();
+        push_srec(&mut out, 0, 0, 2, header.as_bytes());
+
+        let mut count = 0u32;
+        for region in &self.regions {
+            for (i, line) in region.data.chunks(RECORD_LEN).enumerate() {
+                let addr = region.lma + (i * RECORD_LEN) as u64;
+                push_srec(&mut out, data_type, addr, addr_len, line);
+                count += 1;
+            }
+        }
+
+        if count <= 0xffff {
+            push_srec(&mut out, 5, count as u64, 2, &[]);
+        } else if count <= 0xff_ffff {
+            push_srec(&mut out, 6, count as u64, 3, &[]);
+        }
+        push_srec(&mut out, term_type, self.entry, addr_len, &[]);
+        Ok(out)
+    }
+}
+
+/// Data bytes per HEX/S-record line
+const RECORD_LEN: usize = 16;
+
+fn push_ihex(out: &mut String, addr: u16, ty: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(ty);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+
+    out.push(':');
+    for b in record {
+        out.push_str(&format!("{b:02X}"));
+    }
+    out.push_str("\r\n");
+}
+
+fn push_srec(out: &mut String, ty: u8, addr: u64, addr_len: usize, data: &[u8]) {
+    let addr = addr.to_be_bytes();
+    let mut record = vec![(addr_len + data.len() + 1) as u8];
+    record.extend_from_slice(&addr[8 - addr_len..]);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    record.push(!sum);
+
+    out.push('S');
+    out.push(char::from(b'0' + ty));
+    for b in record {
+        out.push_str(&format!("{b:02X}"));
+    }
+    out.push('\n');
+}
+
+fn load_chunks<Elf: FileHeader<Endian = Endianness>>(
+    data: &[u8],
+) -> anyhow::Result<(u64, Vec<Chunk>)> {
+    let file = ElfFile::<Elf>::parse(data)?;
+    let endian = file.endian();
+    let entry = file.elf_header().e_entry(endian).into();
+
+    let segments = file
+        .elf_program_headers()
+        .iter()
+        .filter(|p| p.p_type(endian) == elf::PT_LOAD && p.p_filesz(endian).into() > 0)
+        .collect::<Vec<_>>();
+
+    let mut chunks = Vec::new();
+    let sections = file.elf_section_table();
+    for section in sections.iter() {
+        if section.sh_flags(endian).into() & u64::from(elf::SHF_ALLOC) == 0 {
+            continue;
+        }
+        // `None` for SHT_NOBITS, e.g. `.bss`
+        let Some((offset, size)) = section.file_range(endian) else {
+            continue;
+        };
+        if size == 0 {
+            continue;
+        }
+
+        // A section inside a segment is loaded at the segment's physical address.
+        let lma = segments
+            .iter()
+            .find(|p| {
+                let p_offset = p.p_offset(endian).into();
+                offset >= p_offset && offset < p_offset + p.p_filesz(endian).into()
+            })
+            .map(|p| p.p_paddr(endian).into() + offset - p.p_offset(endian).into())
+            .unwrap_or_else(|| section.sh_addr(endian).into());
+
+        let name = sections
+            .section_name(endian, section)
+            .map(|n| String::from_utf8_lossy(n).to_string())
+            .unwrap_or_default();
+
+        chunks.push(Chunk {
+            name,
+            lma,
+            file_range: offset as usize..(offset + size) as usize,
+        });
+    }
+
+    // Section headers were stripped, fall back to the segments.
+    if chunks.is_empty() {
+        for (i, p) in segments.iter().enumerate() {
+            let offset: u64 = p.p_offset(endian).into();
+            let size: u64 = p.p_filesz(endian).into();
+            chunks.push(Chunk {
+                name: format!("segment {i}"),
+                lma: p.p_paddr(endian).into(),
+                file_range: offset as usize..(offset + size) as usize,
+            });
+        }
+    }
+
+    if chunks.iter().any(|c| c.file_range.end > data.len()) {
+        bail!("ELF section data is out of bounds");
+    }
+
+    chunks.sort_by_key(|c| c.lma);
+    Ok((entry, chunks))
+}
+
+fn check_gaps(regions: &[Region], base: u64, end: u64) -> anyhow::Result<()> {
+    let mut largest: Option<(u64, &Region, &Region)> = None;
+    for pair in regions.windows(2) {
+        let prev_end = pair[0].end();
+        let gap = pair[1].lma.saturating_sub(prev_end);
+        if largest.is_none_or(|(g, _, _)| gap > g) {
+            largest = Some((gap, &pair[0], &pair[1]));
+        }
+    }
+
+    let size = end - base;
+    if size > MAX_IMAGE_SIZE {
+        let detail = largest
+            .map(|(gap, a, b)| {
+                format!(
+                    ", the largest gap is {:.2} between `{}` ({:#x}) and `{}` ({:#x})",
+                    Byte::from(gap),
+                    a.name,
+                    a.lma,
+                    b.name,
+                    b.lma
+                )
+            })
+            .unwrap_or_default();
+        bail!(
+            "refusing to write a {:.2} binary{detail}. Check the load addresses in the linker script",
+            Byte::from(size),
+        );
+    }
+
+    if let Some((gap, a, b)) = largest
+        && gap > WARN_GAP
+    {
+        warn!(
+            "binary contains a {:.2} gap between `{}` ({:#x}) and `{}` ({:#x})",
+            Byte::from(gap),
+            a.name,
+            a.lma,
+            b.name,
+            b.lma
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_executable() {
+        // Any ELF will do, the test binary itself is the easiest to find.
+        let exe = std::env::current_exe().unwrap();
+        let data = std::fs::read(exe).unwrap();
+        if FileKind::parse(data.as_slice()).is_err() {
+            return;
+        }
+        let image = elf_to_binary(&data).unwrap();
+        assert!(!image.data.is_empty());
+    }
+
+    /// ELF64 with `.text` and `.data` in two PT_LOAD segments linked high
+    /// (VMA) but loaded at 0x8000_0000 and 0x8000_0020 (LMA).
+    fn two_segment_elf() -> Vec<u8> {
+        const VMA: u64 = 0xffff_ffc0_0000_0000;
+        let shstrtab = b"\0.text\0.data\0.shstrtab\0";
+        let mut out = Vec::new();
+        let u16 = |out: &mut Vec<u8>, v: u16| out.extend_from_slice(&v.to_le_bytes());
+        let u32 = |out: &mut Vec<u8>, v: u32| out.extend_from_slice(&v.to_le_bytes());
+        let u64 = |out: &mut Vec<u8>, v: u64| out.extend_from_slice(&v.to_le_bytes());
+
+        // ELF header, program headers at 0x40, contents at 0x100,
+        // section headers at 0x128
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        out.resize(16, 0);
+        u16(&mut out, elf::ET_EXEC);
+        u16(&mut out, elf::EM_RISCV);
+        u32(&mut out, 1);
+        u64(&mut out, 0x8000_0000);
+        u64(&mut out, 0x40);
+        u64(&mut out, 0x128);
+        u32(&mut out, 0);
+        for v in [64, 56, 2, 64, 4, 3] {
+            u16(&mut out, v);
+        }
+        for (offset, lma, size) in [(0x100, 0x8000_0000, 8), (0x108, 0x8000_0020, 4)] {
+            u32(&mut out, elf::PT_LOAD);
+            u32(&mut out, elf::PF_R);
+            u64(&mut out, offset);
+            u64(&mut out, VMA + lma);
+            u64(&mut out, lma);
+            u64(&mut out, size);
+            u64(&mut out, size);
+            u64(&mut out, 4);
+        }
+        out.resize(0x100, 0);
+        out.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0xa, 0xb, 0xc, 0xd]);
+        out.extend_from_slice(shstrtab);
+        out.resize(0x128, 0);
+        let sections = [
+            (0, elf::SHT_NULL, 0, 0, 0, 0),
+            (
+                1,
+                elf::SHT_PROGBITS,
+                elf::SHF_ALLOC,
+                VMA + 0x8000_0000,
+                0x100,
+                8,
+            ),
+            (
+                7,
+                elf::SHT_PROGBITS,
+                elf::SHF_ALLOC,
+                VMA + 0x8000_0020,
+                0x108,
+                4,
+            ),
+            (13, elf::SHT_STRTAB, 0, 0, 0x10c, shstrtab.len() as u64),
+        ];
+        for (name, ty, flags, addr, offset, size) in sections {
+            u32(&mut out, name);
+            u32(&mut out, ty);
+            u64(&mut out, flags.into());
+            u64(&mut out, addr);
+            u64(&mut out, offset);
+            u64(&mut out, size);
+            u32(&mut out, 0);
+            u32(&mut out, 0);
+            u64(&mut out, 1);
+            u64(&mut out, 0);
+        }
+        out
+    }
+
+    #[test]
+    fn test_segments_at_lma() {
+        let image = LoadImage::parse(&two_segment_elf()).unwrap();
+        let regions = image
+            .regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.lma))
+            .collect::<Vec<_>>();
+        assert_eq!(regions, [(".text", 0x8000_0000), (".data", 0x8000_0020)]);
+
+        let bin = image.to_binary().unwrap();
+        assert_eq!(bin.base, 0x8000_0000);
+        assert_eq!(bin.data.len(), 0x24);
+        assert_eq!(bin.data[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(bin.data[8..0x20].iter().all(|b| *b == 0));
+        assert_eq!(bin.data[0x20..], [0xa, 0xb, 0xc, 0xd]);
+
+        let ihex = image.to_ihex().unwrap();
+        let lines = ihex.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], ":0200000480007A");
+        assert!(lines[1].starts_with(":08000000"), "{}", lines[1]);
+        assert!(lines[2].starts_with(":04002000"), "{}", lines[2]);
+
+        let srec = image.to_srec("kernel").unwrap();
+        let lines = srec.lines().collect::<Vec<_>>();
+        assert!(lines[1].starts_with("S30D80000000"), "{}", lines[1]);
+        assert!(lines[2].starts_with("S30980000020"), "{}", lines[2]);
+        assert_eq!(lines[4], "S705800000007A");
+    }
+
+    #[test]
+    fn test_gap_limit() {
+        let regions = vec![
+            Region {
+                name: ".text".into(),
+                lma: 0x8000_0000,
+                data: vec![0; 0x100],
+            },
+            Region {
+                name: ".data".into(),
+                lma: 0xffff_0000_0000,
+                data: vec![0; 0x100],
+            },
+        ];
+        let err = check_gaps(&regions, 0x8000_0000, 0xffff_0000_0100)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(".data"), "{err}");
+    }
+
+    #[test]
+    fn test_ihex_and_srec() {
+        let image = LoadImage {
+            entry: 0x1_0000,
+            regions: vec![Region {
+                name: ".text".into(),
+                lma: 0xfff8,
+                data: (0u8..0x10).collect(),
+            }],
+        };
+
+        let ihex = image.to_ihex().unwrap();
+        assert_eq!(
+            ihex,
+            ":08FFF8000001020304050607E5\r\n\
+             :020000021000EC\r\n\
+             :0800000008090A0B0C0D0E0F9C\r\n\
+             :0400000310000000E9\r\n\
+             :00000001FF\r\n"
+        );
+
+        let srec = image.to_srec("kernel").unwrap();
+        let lines = srec.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "S00900006B65726E656C75");
+        assert_eq!(lines[1], "S21400FFF8000102030405060708090A0B0C0D0E0F7C");
+        assert_eq!(lines[2], "S5030001FB");
+        assert_eq!(lines[3], "S804010000FA");
+    }
+}
@@ -0,0 +1,297 @@
+//! Sets `/chosen/bootargs` in a flattened device tree, for kernels that are
+//! started without a command line of their own.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+const HEADER_SIZE: usize = 40;
+
+/// `dtb` with `/chosen/bootargs` set to `bootargs`, creating `/chosen` if needed
+pub fn set_bootargs(dtb: &[u8], bootargs: &str) -> anyhow::Result<Vec<u8>> {
+    let word = |off: usize| -> anyhow::Result<u32> {
+        dtb.get(off..off + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| anyhow!("truncated DTB"))
+    };
+    if dtb.len() < HEADER_SIZE || word(0)? != FDT_MAGIC {
+        bail!("not a DTB");
+    }
+    if word(20)? < 17 {
+        bail!("DTB version {} is older than 17", word(20)?);
+    }
+    let off_struct = word(8)? as usize;
+    let off_strings = word(12)? as usize;
+    let off_rsvmap = word(16)? as usize;
+    let size_strings = word(32)? as usize;
+    let size_struct = word(36)? as usize;
+    let strings = dtb
+        .get(off_strings..off_strings + size_strings)
+        .ok_or_else(|| anyhow!("truncated DTB strings"))?;
+    let structure = dtb
+        .get(off_struct..off_struct + size_struct)
+        .ok_or_else(|| anyhow!("truncated DTB structure"))?;
+
+    let mut strings = strings.to_vec();
+    let name_off = match find_string(&strings, "bootargs") {
+        Some(off) => off,
+        None => {
+            let off = strings.len();
+            strings.extend_from_slice(b"bootargs\0");
+            off
+        }
+    };
+    let mut prop = Vec::new();
+    push_word(&mut prop, FDT_PROP);
+    push_word(&mut prop, bootargs.len() as u32 + 1);
+    push_word(&mut prop, name_off as u32);
+    prop.extend_from_slice(bootargs.as_bytes());
+    prop.push(0);
+    pad(&mut prop);
+
+    // byte range of `structure` replaced by `insert`
+    let (range, insert) = locate(structure, &strings, &prop)?;
+    let mut new_struct = structure[..range.start].to_vec();
+    new_struct.extend_from_slice(&insert);
+    new_struct.extend_from_slice(&structure[range.end..]);
+
+    let mut rsvmap = Vec::new();
+    for entry in dtb[off_rsvmap..].chunks(16) {
+        if entry.len() < 16 {
+            bail!("truncated DTB memory reservations");
+        }
+        rsvmap.extend_from_slice(entry);
+        if entry.iter().all(|b| *b == 0) {
+            break;
+        }
+    }
+
+    let off_rsvmap = HEADER_SIZE;
+    let off_struct = off_rsvmap + rsvmap.len();
+    let off_strings = off_struct + new_struct.len();
+    let total = off_strings + strings.len();
+    let mut out = Vec::with_capacity(total);
+    for value in [
+        FDT_MAGIC,
+        total as u32,
+        off_struct as u32,
+        off_strings as u32,
+        off_rsvmap as u32,
+        word(20)?,
+        word(24)?,
+        word(28)?,
+        strings.len() as u32,
+        new_struct.len() as u32,
+    ] {
+        push_word(&mut out, value);
+    }
+    out.extend_from_slice(&rsvmap);
+    out.extend_from_slice(&new_struct);
+    out.extend_from_slice(&strings);
+    Ok(out)
+}
+
+/// Where `prop` goes: over the old `bootargs`, at the start of `/chosen`, or
+/// in a new `/chosen` at the end of the root node.
+fn locate(
+    structure: &[u8],
+    strings: &[u8],
+    prop: &[u8],
+) -> anyhow::Result<(std::ops::Range<usize>, Vec<u8>)> {
+    let word = |off: usize| -> anyhow::Result<u32> {
+        structure
+            .get(off..off + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| anyhow!("truncated DTB structure"))
+    };
+    let mut off = 0;
+    let mut depth = 0usize;
+    // offset of the first property of `/chosen`
+    let mut chosen: Option<usize> = None;
+    loop {
+        let token = word(off)?;
+        let start = off;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let len = structure[off..]
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or_else(|| anyhow!("unterminated DTB node name"))?;
+                let name = &structure[off..off + len];
+                off = align(off + len + 1);
+                depth += 1;
+                if depth == 2 && (name == b"chosen" || name.starts_with(b"chosen@")) {
+                    chosen = Some(off);
+                }
+            }
+            FDT_END_NODE => {
+                if let Some(at) = chosen
+                    && depth == 2
+                {
+                    return Ok((at..at, prop.to_vec()));
+                }
+                if depth == 1 {
+                    let mut node = Vec::new();
+                    push_word(&mut node, FDT_BEGIN_NODE);
+                    node.extend_from_slice(b"chosen\0");
+                    pad(&mut node);
+                    node.extend_from_slice(prop);
+                    push_word(&mut node, FDT_END_NODE);
+                    return Ok((start..start, node));
+                }
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| anyhow!("unbalanced DTB nodes"))?;
+            }
+            FDT_PROP => {
+                let len = word(off)? as usize;
+                let name_off = word(off + 4)? as usize;
+                off = align(off + 8 + len);
+                if chosen.is_some() && depth == 2 && string_at(strings, name_off) == b"bootargs" {
+                    return Ok((start..off, prop.to_vec()));
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => bail!("DTB has no root node"),
+            token => bail!("invalid DTB token {token:#x}"),
+        }
+        // a node nested in `/chosen` ends the search for its properties
+        if depth > 2
+            && let Some(at) = chosen.take()
+        {
+            return Ok((at..at, prop.to_vec()));
+        }
+    }
+}
+
+fn find_string(strings: &[u8], name: &str) -> Option<usize> {
+    let mut off = 0;
+    for s in strings.split(|b| *b == 0) {
+        if s == name.as_bytes() {
+            return Some(off);
+        }
+        off += s.len() + 1;
+    }
+    None
+}
+
+fn string_at(strings: &[u8], off: usize) -> &[u8] {
+    let s = strings.get(off..).unwrap_or_default();
+    &s[..s.iter().position(|b| *b == 0).unwrap_or(s.len())]
+}
+
+fn align(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(align(buf.len()), 0);
+}
+
+fn push_word(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DTB of `nodes` under the root, each a name and its string properties
+    fn dtb(nodes: &[(&str, &[(&str, &str)])]) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut structure = Vec::new();
+        let node = |structure: &mut Vec<u8>, name: &str| {
+            push_word(structure, FDT_BEGIN_NODE);
+            structure.extend_from_slice(name.as_bytes());
+            structure.push(0);
+            pad(structure);
+        };
+        node(&mut structure, "");
+        for (name, props) in nodes {
+            node(&mut structure, name);
+            for (key, value) in *props {
+                let off = find_string(&strings, key).unwrap_or_else(|| {
+                    strings.extend_from_slice(key.as_bytes());
+                    strings.push(0);
+                    strings.len() - key.len() - 1
+                });
+                push_word(&mut structure, FDT_PROP);
+                push_word(&mut structure, value.len() as u32 + 1);
+                push_word(&mut structure, off as u32);
+                structure.extend_from_slice(value.as_bytes());
+                structure.push(0);
+                pad(&mut structure);
+            }
+            push_word(&mut structure, FDT_END_NODE);
+        }
+        push_word(&mut structure, FDT_END_NODE);
+        push_word(&mut structure, FDT_END);
+
+        let off_struct = HEADER_SIZE + 16;
+        let off_strings = off_struct + structure.len();
+        let mut out = Vec::new();
+        for value in [
+            FDT_MAGIC,
+            (off_strings + strings.len()) as u32,
+            off_struct as u32,
+            off_strings as u32,
+            HEADER_SIZE as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_word(&mut out, value);
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&structure);
+        out.extend_from_slice(&strings);
+        out
+    }
+
+    #[test]
+    fn test_set_bootargs() {
+        let expected = dtb(&[
+            ("memory", &[("device_type", "memory")]),
+            (
+                "chosen",
+                &[("bootargs", "--filter foo"), ("stdout-path", "/uart")],
+            ),
+        ]);
+
+        let replaced = dtb(&[
+            ("memory", &[("device_type", "memory")]),
+            (
+                "chosen",
+                &[("bootargs", "console=ttyS0"), ("stdout-path", "/uart")],
+            ),
+        ]);
+        assert_eq!(set_bootargs(&replaced, "--filter foo").unwrap(), expected);
+
+        let inserted = set_bootargs(
+            &dtb(&[
+                ("memory", &[("device_type", "memory")]),
+                ("chosen", &[("stdout-path", "/uart")]),
+            ]),
+            "--filter foo",
+        )
+        .unwrap();
+        assert_eq!(set_bootargs(&inserted, "--filter foo").unwrap(), inserted);
+        assert_eq!(
+            find_string(&inserted[inserted.len() - 9..], "bootargs"),
+            Some(0)
+        );
+
+        let created = set_bootargs(&dtb(&[("memory", &[])]), "quiet").unwrap();
+        assert_eq!(
+            set_bootargs(&created, "quiet").unwrap(),
+            created,
+            "the created `/chosen` is found again"
+        );
+        assert!(set_bootargs(b"not a dtb at all, not at all......!!!!!", "quiet").is_err());
+    }
+}
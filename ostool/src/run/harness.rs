@@ -0,0 +1,255 @@
+//! Parsers for the results an in-kernel test harness prints on the console.
+//!
+//! The runner configs select one with `test_parser`:
+//!
+//! ```toml
+//! test_parser = "libtest"  # `test mm::alloc ... ok`
+//! # test_parser = "tap"    # `ok 1 - alloc`, with KTAP subtests
+//! # test_parser = { regex = '^\[(?<result>PASS|FAIL)\] (?<name>\S+)' }
+//! ```
+
+use std::fmt::Write;
+
+use colored::Colorize;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestParser {
+    /// `test <name> ... ok|FAILED|ignored` lines of the Rust test harness
+    Libtest,
+    /// `ok`/`not ok` lines of TAP and KTAP, indented KTAP subtests are
+    /// named `<suite>::<test>`
+    Tap,
+    /// Regex with the named groups `name` and `result`. A result of `ok`,
+    /// `pass` or `passed` passes, `ignored`, `skip` or `skipped` is
+    /// ignored, anything else fails (case-insensitive)
+    Regex(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// Result of one test of the harness
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+}
+
+/// A [`TestParser`] with its state, fed one console line at a time
+pub(crate) enum Harness {
+    Libtest(Regex),
+    Tap {
+        line: Regex,
+        subtest: Regex,
+        /// `# Subtest:` names of the enclosing KTAP suites
+        suites: Vec<String>,
+    },
+    Regex(Regex),
+}
+
+impl Harness {
+    pub fn new(parser: &TestParser) -> anyhow::Result<Self> {
+        Ok(match parser {
+            TestParser::Libtest => {
+                Harness::Libtest(Regex::new(r"^test (\S+) \.\.\. (ok|FAILED|ignored)\b").unwrap())
+            }
+            TestParser::Tap => Harness::Tap {
+                line: Regex::new(
+                    r"^(\s*)(ok|not ok)\b(?:\s+(\d+))?(?:\s+-)?\s*([^#]*?)\s*(?:#\s*(\w+).*)?$",
+                )
+                .unwrap(),
+                subtest: Regex::new(r"^(\s*)# Subtest: (.+?)\s*$").unwrap(),
+                suites: Vec::new(),
+            },
+            TestParser::Regex(pattern) => {
+                let regex =
+                    Regex::new(pattern).map_err(|e| anyhow!("test_parser regex error: {e}"))?;
+                for group in ["name", "result"] {
+                    if !regex.capture_names().flatten().any(|n| n == group) {
+                        bail!("test_parser regex `{pattern}` has no `{group}` group");
+                    }
+                }
+                Harness::Regex(regex)
+            }
+        })
+    }
+
+    /// The result `line` reports, if any
+    pub fn parse(&mut self, line: &str) -> Option<TestResult> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        match self {
+            Harness::Libtest(regex) => {
+                let caps = regex.captures(line)?;
+                Some(TestResult {
+                    name: caps[1].to_string(),
+                    outcome: outcome(&caps[2]),
+                })
+            }
+            Harness::Tap {
+                line: regex,
+                subtest,
+                suites,
+            } => {
+                if let Some(caps) = subtest.captures(line) {
+                    let depth = depth(&caps[1]);
+                    suites.truncate(depth.saturating_sub(1));
+                    suites.push(caps[2].to_string());
+                    return None;
+                }
+                let caps = regex.captures(line)?;
+                let depth = depth(&caps[1]);
+                let name = match caps[4].is_empty() {
+                    true => format!("test {}", caps.get(3).map_or("", |m| m.as_str())),
+                    false => caps[4].to_string(),
+                };
+                // the result of a whole suite, its tests were reported
+                if suites.get(depth) == Some(&name) {
+                    suites.truncate(depth);
+                    return None;
+                }
+                suites.truncate(depth);
+                let directive = caps.get(5).map(|m| m.as_str().to_ascii_lowercase());
+                let outcome = match (&caps[2], directive.as_deref()) {
+                    (_, Some("skip" | "todo")) => TestOutcome::Ignored,
+                    ("ok", _) => TestOutcome::Passed,
+                    _ => TestOutcome::Failed,
+                };
+                let mut path = suites.clone();
+                path.push(name);
+                Some(TestResult {
+                    name: path.join("::"),
+                    outcome,
+                })
+            }
+            Harness::Regex(regex) => {
+                let caps = regex.captures(line)?;
+                Some(TestResult {
+                    name: caps["name"].to_string(),
+                    outcome: outcome(&caps["result"]),
+                })
+            }
+        }
+    }
+}
+
+fn outcome(result: &str) -> TestOutcome {
+    match result.to_ascii_lowercase().as_str() {
+        "ok" | "pass" | "passed" => TestOutcome::Passed,
+        "ignored" | "skip" | "skipped" => TestOutcome::Ignored,
+        _ => TestOutcome::Failed,
+    }
+}
+
+/// KTAP indents subtests by four spaces
+fn depth(indent: &str) -> usize {
+    indent
+        .chars()
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum::<usize>()
+        / 4
+}
+
+/// Table of `tests` with the totals
+pub fn render_summary(tests: &[TestResult]) -> String {
+    let mut out = String::new();
+    let name_width = tests.iter().map(|t| t.name.len()).max().unwrap_or(0).max(4);
+    let count = |outcome| tests.iter().filter(|t| t.outcome == outcome).count();
+
+    let _ = writeln!(out, "{}", "Test results:".bold().purple());
+    let _ = writeln!(out, "  {:<name_width$}  result", "test");
+    for test in tests {
+        let result = match test.outcome {
+            TestOutcome::Passed => "ok".green(),
+            TestOutcome::Failed => "FAILED".red(),
+            TestOutcome::Ignored => "ignored".yellow(),
+        };
+        let _ = writeln!(out, "  {:<name_width$}  {result}", test.name);
+    }
+    let _ = writeln!(
+        out,
+        "  {} passed, {} failed, {} ignored",
+        count(TestOutcome::Passed),
+        count(TestOutcome::Failed),
+        count(TestOutcome::Ignored)
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parser: TestParser, console: &str) -> Vec<TestResult> {
+        let mut harness = Harness::new(&parser).unwrap();
+        console.lines().filter_map(|l| harness.parse(l)).collect()
+    }
+
+    fn result(name: &str, outcome: TestOutcome) -> TestResult {
+        TestResult {
+            name: name.into(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_libtest() {
+        let console = "running 3 tests\r\n\
+            test mm::alloc ... ok\r\n\
+            test mm::free ... FAILED\r\n\
+            test fs::big ... ignored, slow\r\n\
+            test result: FAILED. 1 passed; 1 failed; 1 ignored\r\n";
+        assert_eq!(
+            parse(TestParser::Libtest, console),
+            [
+                result("mm::alloc", TestOutcome::Passed),
+                result("mm::free", TestOutcome::Failed),
+                result("fs::big", TestOutcome::Ignored),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tap() {
+        let console = "KTAP version 1\n\
+            1..3\n\
+            \x20   KTAP version 1\n\
+            \x20   # Subtest: mm\n\
+            \x20   1..2\n\
+            \x20   ok 1 alloc\n\
+            \x20   not ok 2 free\n\
+            not ok 1 mm\n\
+            ok 2 - boot # SKIP no disk\n\
+            not ok 3\n";
+        assert_eq!(
+            parse(TestParser::Tap, console),
+            [
+                result("mm::alloc", TestOutcome::Passed),
+                result("mm::free", TestOutcome::Failed),
+                result("boot", TestOutcome::Ignored),
+                result("test 3", TestOutcome::Failed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        let parser = TestParser::Regex(r"^\[(?<result>PASS|FAIL)\] (?<name>\S+)".into());
+        assert_eq!(
+            parse(parser, "[PASS] alloc\n[FAIL] free\nboot\n"),
+            [
+                result("alloc", TestOutcome::Passed),
+                result("free", TestOutcome::Failed),
+            ]
+        );
+        assert!(Harness::new(&TestParser::Regex("(?<name>.*)".into())).is_err());
+    }
+}
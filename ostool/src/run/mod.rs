@@ -1,5 +1,353 @@
+use std::{fmt, time::Duration};
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    ctx::AppContext,
+    event::{Event, Events},
+    hooks::RunnerKind,
+    run::harness::{Harness, TestOutcome, TestParser, TestResult},
+    symbolize::Symbolizer,
+};
+
+pub mod harness;
 pub mod qemu;
+pub mod report;
 pub mod tftp;
 pub mod uboot;
 
+mod fdt;
 mod ovmf_prebuilt;
+
+/// Why a run ended
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum RunExit {
+    /// A `success_regex` matched `line`
+    Success { pattern: String, line: String },
+    /// A `fail_regex` matched `line`
+    Failure { pattern: String, line: String },
+    /// QEMU exited before any pattern matched, `None` if it was killed by a signal
+    Exited { code: Option<i32> },
+    /// No pattern matched within the `timeout` of the runner config
+    Timeout { seconds: u64 },
+    /// The serial terminal was closed, and there was no `success_regex` to wait for
+    Stopped,
+    /// The serial terminal was closed before a `success_regex` matched
+    Interrupted,
+}
+
+/// Result of [`qemu::run_qemu`] and [`uboot::run_uboot`]
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub runner: RunnerKind,
+    pub exit: RunExit,
+    /// Everything the kernel printed, also when the output is not shown
+    pub console: String,
+    /// Results found by the `test_parser` of the runner config
+    pub tests: Vec<TestResult>,
+    #[serde(serialize_with = "crate::event::secs")]
+    pub duration: Duration,
+}
+
+impl RunReport {
+    /// A success pattern matched, or the runner ended cleanly, and no test failed
+    pub fn success(&self) -> bool {
+        matches!(
+            self.exit,
+            RunExit::Success { .. } | RunExit::Exited { code: Some(0) } | RunExit::Stopped
+        ) && self.failed_tests().next().is_none()
+    }
+
+    pub fn failed_tests(&self) -> impl Iterator<Item = &TestResult> {
+        self.tests
+            .iter()
+            .filter(|t| t.outcome == TestOutcome::Failed)
+    }
+
+    /// The pattern that ended the run
+    pub fn matched(&self) -> Option<&str> {
+        match &self.exit {
+            RunExit::Success { pattern, .. } | RunExit::Failure { pattern, .. } => Some(pattern),
+            _ => None,
+        }
+    }
+
+    /// `Err(RunFailed)` if the run did not succeed
+    pub fn check(self) -> anyhow::Result<Self> {
+        if self.success() {
+            Ok(self)
+        } else {
+            Err(RunFailed(Box::new(self)).into())
+        }
+    }
+}
+
+/// Error of a failed run, get the report with `err.downcast_ref::<RunFailed>()`
+#[derive(Debug)]
+pub struct RunFailed(pub Box<RunReport>);
+
+impl fmt::Display for RunFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let runner = match self.0.runner {
+            RunnerKind::Qemu => "QEMU",
+            RunnerKind::Uboot => "U-Boot",
+        };
+        match &self.0.exit {
+            RunExit::Failure { pattern, line } => write!(
+                f,
+                "Detected failure pattern '{pattern}' in {runner} output: {}",
+                line.trim_end()
+            ),
+            RunExit::Exited { code: Some(code) } => write!(f, "{runner} exited with code {code}"),
+            RunExit::Exited { code: None } => write!(f, "{runner} was killed by a signal"),
+            RunExit::Timeout { seconds } => write!(f, "{runner} run timed out after {seconds}s"),
+            RunExit::Interrupted => write!(
+                f,
+                "{runner} terminal was closed before a success pattern matched"
+            ),
+            _ if self.0.failed_tests().next().is_some() => {
+                let failed: Vec<_> = self.0.failed_tests().map(|t| t.name.as_str()).collect();
+                write!(
+                    f,
+                    "{} of {} tests failed: {}",
+                    failed.len(),
+                    self.0.tests.len(),
+                    failed.join(", ")
+                )
+            }
+            _ => write!(f, "{runner} run failed"),
+        }
+    }
+}
+
+impl std::error::Error for RunFailed {}
+
+/// The kernel command line: the `cmdline` of the runner config, then `args`,
+/// quoted where they hold spaces.
+pub(crate) fn kernel_cmdline(cmdline: Option<&str>, args: &[String]) -> Option<String> {
+    let parts: Vec<String> = cmdline
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(str::to_string)
+        .into_iter()
+        .chain(
+            args.iter()
+                .map(|arg| match arg.contains(char::is_whitespace) {
+                    true => format!("\"{arg}\""),
+                    false => arg.clone(),
+                }),
+        )
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+/// Splits console output into lines and matches them against the success and
+/// fail patterns.
+pub(crate) struct ConsoleMonitor {
+    events: Events,
+    show_output: bool,
+    success: Vec<Regex>,
+    fail: Vec<Regex>,
+    symbolizer: Option<Symbolizer>,
+    harness: Option<Harness>,
+    tests: Vec<TestResult>,
+    log: String,
+    line: String,
+    /// Incomplete UTF-8 sequence at the end of the last read
+    pending: Vec<u8>,
+}
+
+impl ConsoleMonitor {
+    pub fn new(
+        ctx: &AppContext,
+        success: &[String],
+        fail: &[String],
+        parser: Option<&TestParser>,
+        show_output: bool,
+    ) -> anyhow::Result<Self> {
+        let compile = |patterns: &[String], what: &str| {
+            patterns
+                .iter()
+                .map(|p| Regex::new(p).map_err(|e| anyhow!("{what} regex error: {e}")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            events: ctx.events.clone(),
+            show_output,
+            success: compile(success, "success")?,
+            fail: compile(fail, "fail")?,
+            symbolizer: ctx.symbolizer(),
+            harness: parser.map(Harness::new).transpose()?,
+            tests: Vec::new(),
+            log: String::new(),
+            line: String::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Handles a chunk of output, returns the exit once a pattern matches.
+    pub fn feed(&mut self, data: &[u8]) -> Option<RunExit> {
+        self.pending.extend_from_slice(data);
+        let text = take_utf8(&mut self.pending);
+        self.log.push_str(&text);
+
+        let mut rest = text.as_str();
+        while let Some(pos) = rest.find('\n') {
+            let (head, tail) = rest.split_at(pos + 1);
+            rest = tail;
+            self.show(head);
+            self.line.push_str(head);
+            let line = std::mem::take(&mut self.line);
+            if let Some(exit) = self.check_line(&line) {
+                return Some(exit);
+            }
+        }
+        self.show(rest);
+        self.line.push_str(rest);
+        None
+    }
+
+    /// The console output and the test results, whose summary is emitted
+    pub fn finish(self) -> (String, Vec<TestResult>) {
+        if self.harness.is_some() {
+            self.events.emit(Event::TestSummary {
+                tests: self.tests.clone(),
+            });
+        }
+        (self.log, self.tests)
+    }
+
+    fn show(&self, data: &str) {
+        if self.show_output && !data.is_empty() {
+            self.events.emit(Event::Console {
+                data: data.to_string(),
+            });
+        }
+    }
+
+    fn check_line(&mut self, line: &str) -> Option<RunExit> {
+        if self.show_output
+            && let Some(symbolizer) = &self.symbolizer
+        {
+            for note in symbolizer.annotate(line) {
+                self.events.emit(Event::Symbol { note });
+            }
+        }
+
+        if let Some(result) = self.harness.as_mut().and_then(|h| h.parse(line)) {
+            self.events.emit(Event::TestResult {
+                result: result.clone(),
+            });
+            self.tests.push(result);
+        }
+
+        let matched = |regexes: &[Regex]| {
+            regexes
+                .iter()
+                .find(|r| r.is_match(line))
+                .map(|r| r.as_str().to_string())
+        };
+        let (pattern, success) = match matched(&self.fail) {
+            Some(pattern) => (pattern, false),
+            None => (matched(&self.success)?, true),
+        };
+        self.events.emit(Event::PatternMatched {
+            pattern: pattern.clone(),
+            line: line.to_string(),
+            success,
+        });
+        let line = line.to_string();
+        Some(match success {
+            true => RunExit::Success { pattern, line },
+            false => RunExit::Failure { pattern, line },
+        })
+    }
+}
+
+/// The valid UTF-8 prefix of `buf`, keeping a trailing incomplete sequence.
+fn take_utf8(buf: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(buf) {
+        Ok(_) => buf.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => buf.len(),
+    };
+    let text = String::from_utf8_lossy(&buf[..valid]).to_string();
+    buf.drain(..valid);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_cmdline() {
+        assert_eq!(kernel_cmdline(None, &[]), None);
+        assert_eq!(kernel_cmdline(Some(" "), &[]), None);
+        assert_eq!(
+            kernel_cmdline(
+                Some("console=ttyS0"),
+                &["--filter".into(), "mm alloc".into()]
+            )
+            .as_deref(),
+            Some("console=ttyS0 --filter \"mm alloc\"")
+        );
+    }
+
+    #[test]
+    fn test_stopped_success() {
+        let report = |exit| RunReport {
+            runner: RunnerKind::Uboot,
+            exit,
+            console: String::new(),
+            tests: Vec::new(),
+            duration: Duration::ZERO,
+        };
+        assert!(report(RunExit::Stopped).success());
+        let err = report(RunExit::Interrupted).check().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "U-Boot terminal was closed before a success pattern matched"
+        );
+    }
+
+    #[test]
+    fn test_console_monitor() {
+        let ctx = AppContext {
+            events: Events::new(|_: &Event| {}),
+            ..Default::default()
+        };
+        let mut monitor = ConsoleMonitor::new(
+            &ctx,
+            &["All tests passed".into()],
+            &["panicked".into()],
+            None,
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(monitor.feed("booting \u{e9}".as_bytes()), None);
+        // a multi-byte character split between two reads
+        let bytes = "\u{e9}t\u{e9}\n".as_bytes();
+        assert_eq!(monitor.feed(&bytes[..1]), None);
+        assert_eq!(monitor.feed(&bytes[1..]), None);
+        assert_eq!(
+            monitor.feed(b"All tests "),
+            None,
+            "a pattern only matches complete lines"
+        );
+        assert_eq!(
+            monitor.feed(b"passed\r\nrest"),
+            Some(RunExit::Success {
+                pattern: "All tests passed".into(),
+                line: "All tests passed\r\n".into(),
+            })
+        );
+        assert_eq!(
+            monitor.finish().0,
+            "booting \u{e9}\u{e9}t\u{e9}\nAll tests passed\r\nrest"
+        );
+    }
+}
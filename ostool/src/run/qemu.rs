@@ -1,22 +1,31 @@
 use std::{
-    ffi::OsString,
-    io::{BufReader, Read},
+    io::Read,
     path::PathBuf,
     process::{Child, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
-use colored::Colorize;
+use anyhow::{Context, anyhow};
 use crossterm::terminal::disable_raw_mode;
 use object::Architecture;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::io::{self, Write};
 use tokio::fs;
 
 use crate::{
+    config::schema::SchemaKind,
     ctx::AppContext,
-    run::ovmf_prebuilt::{Arch, FileType, Prebuilt, Source},
+    event::Event,
+    hooks::{HookStage, RunnerKind},
+    run::{
+        ConsoleMonitor, RunExit, RunReport, fdt,
+        harness::TestParser,
+        kernel_cmdline,
+        ovmf_prebuilt::{Arch, FileType, Prebuilt, Source},
+    },
+    utils::Command,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Default)]
@@ -27,16 +36,65 @@ pub struct QemuConfig {
     pub to_bin: bool,
     pub success_regex: Vec<String>,
     pub fail_regex: Vec<String>,
+    /// Seconds before a run no pattern matched in is stopped as failed
+    pub timeout: Option<u64>,
+    /// Parser of the test results printed on the console, a failed test
+    /// fails the run
+    pub test_parser: Option<TestParser>,
+    /// how the `disk` image of the build is attached, `virtio` by default
+    pub disk: Option<DiskInterface>,
+    /// Kernel command line, passed with `-append`, or in `/chosen/bootargs`
+    /// of the DTB when the firmware boots the kernel from the disk
+    pub cmdline: Option<String>,
+}
+
+impl QemuConfig {
+    /// Config written when none exists, with a machine QEMU can boot for `arch`
+    pub fn template(arch: Option<Architecture>) -> Self {
+        let mut config = QemuConfig {
+            to_bin: true,
+            ..Default::default()
+        };
+        config.args.push("-nographic".to_string());
+        let extra: &[&str] = match arch {
+            Some(Architecture::Aarch64) => &["-cpu", "cortex-a53"],
+            Some(Architecture::Riscv64) => &["-cpu", "rv64"],
+            Some(Architecture::LoongArch64) => &["-cpu", "la464"],
+            // there is no `virt` machine on x86, and `-kernel` wants a multiboot ELF
+            Some(Architecture::X86_64) => {
+                config.to_bin = false;
+                &["-machine", "q35"]
+            }
+            _ => &[],
+        };
+        config.args.extend(extra.iter().map(|s| s.to_string()));
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskInterface {
+    #[default]
+    Virtio,
+    Nvme,
+    /// do not attach the image
+    None,
 }
 
 #[derive(Debug, Clone)]
 pub struct RunQemuArgs {
     pub qemu_config: Option<PathBuf>,
     pub dtb_dump: bool,
+    /// Send the console output to the event sink, it is captured in the report either way
     pub show_output: bool,
+    /// Appended to the `cmdline` of the config, e.g. the args of `cargo test -- <args>`
+    pub cmdline_args: Vec<String>,
 }
 
-pub async fn run_qemu(ctx: AppContext, args: RunQemuArgs) -> anyhow::Result<()> {
+/// Runs the kernel in QEMU. A failed run is an error holding a
+/// [`RunFailed`](crate::run::RunFailed) with the report.
+pub async fn run_qemu(ctx: AppContext, args: RunQemuArgs) -> anyhow::Result<RunReport> {
     // Build logic will be implemented here
     let config_path = match args.qemu_config.clone() {
         Some(path) => path,
@@ -46,58 +104,55 @@ pub async fn run_qemu(ctx: AppContext, args: RunQemuArgs) -> anyhow::Result<()>
     info!("Using QEMU config file: {}", config_path.display());
 
     let config = if config_path.exists() {
-        let config_content = fs::read_to_string(&config_path)
-            .await
-            .map_err(|_| anyhow!("can not open config file: {}", config_path.display()))?;
-        let config: QemuConfig = toml::from_str(&config_content)?;
-        config
+        let mut loaded = crate::config::load(&config_path).await?;
+        loaded.substitute(&ctx.variables())?;
+        loaded.parse::<QemuConfig>()?
     } else {
-        let mut config = QemuConfig {
-            to_bin: true,
-            ..Default::default()
-        };
-        config.args.push("-nographic".to_string());
-        if let Some(arch) = ctx.arch {
-            match arch {
-                Architecture::Aarch64 => {
-                    config.args.push("-cpu".to_string());
-                    config.args.push("cortex-a53".to_string());
-                }
-                Architecture::Riscv64 => {
-                    config.args.push("-cpu".to_string());
-                    config.args.push("rv64".to_string());
-                }
-                _ => {}
-            }
-        }
+        let config = QemuConfig::template(ctx.arch);
         fs::write(&config_path, toml::to_string_pretty(&config)?).await?;
         config
     };
+    let config = ctx.overrides.apply(SchemaKind::Qemu, config)?;
 
+    let cmdline = kernel_cmdline(config.cmdline.as_deref(), &args.cmdline_args);
     let mut runner = QemuRunner {
         ctx,
         config,
+        cmdline,
         args: vec![],
         dtbdump: args.dtb_dump,
-        success_regex: vec![],
-        fail_regex: vec![],
+        show_output: args.show_output,
     };
-    runner.run().await?;
-    Ok(())
+    runner.ctx.run_hooks(HookStage::PreRun)?;
+    let report = runner.run().await?;
+    runner.ctx.events.emit(Event::Exit {
+        runner: report.runner,
+        exit: report.exit.clone(),
+        duration: report.duration,
+    });
+    let report = report.check()?;
+    runner.ctx.run_hooks(HookStage::PostRun)?;
+    Ok(report)
 }
 
 struct QemuRunner {
     ctx: AppContext,
     config: QemuConfig,
+    cmdline: Option<String>,
     args: Vec<String>,
     dtbdump: bool,
-    success_regex: Vec<regex::Regex>,
-    fail_regex: Vec<regex::Regex>,
+    show_output: bool,
 }
 
 impl QemuRunner {
-    async fn run(&mut self) -> anyhow::Result<()> {
-        self.preper_regex()?;
+    async fn run(&mut self) -> anyhow::Result<RunReport> {
+        let mut monitor = ConsoleMonitor::new(
+            &self.ctx,
+            &self.config.success_regex,
+            &self.config.fail_regex,
+            self.config.test_parser.as_ref(),
+            self.show_output,
+        )?;
 
         if self.config.to_bin {
             self.ctx.objcopy_output_bin()?;
@@ -122,21 +177,32 @@ impl QemuRunner {
 
         #[cfg(windows)]
         {
-            println!("{}", "Checking for QEMU executable on Windows...".blue());
+            debug!("Checking for QEMU executable on Windows...");
             // Windows 特殊处理
             let msys2 =
                 PathBuf::from("C:\\msys64\\ucrt64\\bin").join(format!("{qemu_executable}.exe"));
 
             if msys2.exists() {
-                println!("Using QEMU executable from MSYS2: {}", msys2.display());
+                info!("Using QEMU executable from MSYS2: {}", msys2.display());
                 qemu_executable = msys2.to_string_lossy().to_string();
             }
         }
 
         let mut cmd = self.ctx.command(&qemu_executable);
 
-        for arg in &self.config.args {
+        let mut cmdline = self.cmdline.clone();
+        let mut args = self.config.args.iter();
+        while let Some(arg) = args.next() {
             cmd.arg(arg);
+            // an `-append` in the args comes first on the command line
+            if arg == "-append"
+                && let Some(value) = args.next()
+            {
+                cmd.arg(match cmdline.take() {
+                    Some(cmdline) => format!("{value} {cmdline}"),
+                    None => value.clone(),
+                });
+            }
         }
 
         if self.dtbdump {
@@ -157,53 +223,140 @@ impl QemuRunner {
             cmd.arg("-bios").arg(bios);
         }
 
-        if let Some(bin_path) = &self.ctx.paths.artifacts.bin {
-            cmd.arg("-kernel").arg(bin_path);
-        } else if let Some(elf_path) = &self.ctx.paths.artifacts.elf {
-            cmd.arg("-kernel").arg(elf_path);
+        let disk = match self.config.disk.unwrap_or_default() {
+            DiskInterface::None => None,
+            interface => self
+                .ctx
+                .paths
+                .artifacts
+                .disk
+                .clone()
+                .map(|d| (interface, d)),
+        };
+        if let Some((interface, disk)) = &disk {
+            cmd.arg("-drive").arg(format!(
+                "file={},format=raw,if=none,id=ostool-disk",
+                disk.display()
+            ));
+            cmd.arg("-device").arg(match interface {
+                DiskInterface::Nvme => "nvme,serial=ostool,drive=ostool-disk",
+                _ => "virtio-blk-pci,drive=ostool-disk",
+            });
+        }
+
+        // With `-kernel` the firmware would start the kernel directly instead
+        // of booting from the ESP
+        if !(self.config.uefi && disk.is_some()) {
+            if let Some(bin_path) = &self.ctx.paths.artifacts.bin {
+                cmd.arg("-kernel").arg(bin_path);
+            } else if let Some(elf_path) = &self.ctx.paths.artifacts.elf {
+                cmd.arg("-kernel").arg(elf_path);
+            }
+            if let Some(initramfs) = &self.ctx.paths.artifacts.initramfs
+                && !self.config.args.iter().any(|a| a == "-initrd")
+            {
+                cmd.arg("-initrd").arg(initramfs);
+            }
+            if let Some(cmdline) = cmdline.take() {
+                cmd.arg("-append").arg(cmdline);
+            }
+        }
+        // `-append` needs `-kernel`, the firmware finds the command line in the DTB
+        if let Some(cmdline) = cmdline {
+            let dtb = self.bootargs_dtb(&cmd, &qemu_executable, &cmdline)?;
+            cmd.arg("-dtb").arg(dtb);
         }
         cmd.stdout(Stdio::piped());
         cmd.print_cmd();
+        let start = Instant::now();
         let mut child = cmd.spawn()?;
-
-        let mut qemu_result: Option<anyhow::Result<()>> = None;
-
-        let stdout = BufReader::new(child.stdout.take().unwrap());
-        let mut line_buf = Vec::new();
-
-        for byte in stdout.bytes() {
-            let byte = match byte {
-                Ok(b) => b,
-                Err(e) => {
-                    println!("stdout: {:?}", e);
-                    continue;
+        self.ctx.events.emit(Event::QemuSpawned {
+            command: cmd.cmd_line(),
+            pid: child.id(),
+        });
+
+        // read on a thread so the timeout is checked while QEMU is silent
+        let mut stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        warn!("QEMU stdout: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        let deadline = self.config.timeout.map(|t| start + Duration::from_secs(t));
+        let mut matched = None;
+        loop {
+            let data = match deadline {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
                 }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
             };
-            let _ = std::io::stdout().write_all(&[byte]);
-            let _ = std::io::stdout().flush();
-
-            line_buf.push(byte);
-            if byte != b'\n' {
-                continue;
+            match data {
+                Ok(data) => {
+                    if let Some(exit) = monitor.feed(&data) {
+                        matched = Some(exit);
+                        self.kill_qemu(&mut child)?;
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let seconds = self.config.timeout.unwrap_or_default();
+                    self.ctx.events.emit(Event::Timeout { seconds });
+                    matched = Some(RunExit::Timeout { seconds });
+                    self.kill_qemu(&mut child)?;
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
             }
-
-            let line = String::from_utf8_lossy(&line_buf).to_string();
-
-            self.check_output(&line, &mut child, &mut qemu_result)?;
         }
 
-        let out = child.wait_with_output()?;
-        if let Some(res) = qemu_result {
-            res?;
-        } else if !out.status.success() {
-            unsafe {
-                return Err(anyhow::anyhow!(
-                    "{}",
-                    OsString::from_encoded_bytes_unchecked(out.stderr).to_string_lossy()
-                ));
-            }
-        }
-        Ok(())
+        let status = child.wait()?;
+        let exit = matched.unwrap_or(RunExit::Exited {
+            code: status.code(),
+        });
+        let (console, tests) = monitor.finish();
+        Ok(RunReport {
+            runner: RunnerKind::Qemu,
+            exit,
+            console,
+            tests,
+            duration: start.elapsed(),
+        })
+    }
+
+    /// The DTB QEMU generates for `cmd`, with `cmdline` in `/chosen/bootargs`
+    fn bootargs_dtb(&self, cmd: &Command, qemu: &str, cmdline: &str) -> anyhow::Result<PathBuf> {
+        let dir = self.ctx.paths.build_dir().join("ostool");
+        std::fs::create_dir_all(&dir)?;
+        let dumped = dir.join("qemu-dump.dtb");
+        let patched = dir.join("qemu-bootargs.dtb");
+
+        let mut dump = self.ctx.command(qemu);
+        dump.args(cmd.get_args())
+            .arg("-machine")
+            .arg(format!("dumpdtb={}", dumped.display()));
+        dump.run()
+            .context("the kernel command line needs `-kernel` or a machine with a device tree")?;
+        let dtb = std::fs::read(&dumped).with_context(|| {
+            format!("can not read the DTB dumped by QEMU: {}", dumped.display())
+        })?;
+        std::fs::write(&patched, fdt::set_bootargs(&dtb, cmdline)?)?;
+        Ok(patched)
     }
 
     fn detect_arch(&self) -> anyhow::Result<String> {
@@ -233,7 +386,9 @@ impl QemuRunner {
         let bios_dir = tmp.join("ostool").join("ovmf");
         fs::create_dir_all(&bios_dir).await?;
 
-        println!("Preparing OVMF firmware for architecture: {:?}", arch);
+        self.ctx.events.status(format!(
+            "Preparing OVMF firmware for architecture: {arch:?}"
+        ));
         let prebuilt = Prebuilt::fetch(Source::LATEST, &bios_dir)?;
         let arch = match arch {
             Architecture::X86_64 => Arch::X64,
@@ -249,46 +404,6 @@ impl QemuRunner {
         Ok(bios_path)
     }
 
-    fn check_output(
-        &self,
-        out: &str,
-        child: &mut Child,
-        res: &mut Option<anyhow::Result<()>>,
-    ) -> anyhow::Result<()> {
-        // // Process QEMU output line here
-        // println!("{}", line);
-
-        for regex in &self.fail_regex {
-            if regex.is_match(out) {
-                *res = Some(Err(anyhow!(
-                    "Detected failure pattern '{}' in QEMU output.",
-                    regex.as_str()
-                )));
-
-                self.kill_qemu(child)?;
-                return Ok(());
-            }
-        }
-
-        for regex in &self.success_regex {
-            if regex.is_match(out) {
-                *res = Some(Ok(()));
-                println!(
-                    "{}",
-                    format!(
-                        "Detected success pattern '{}' in QEMU output, terminating QEMU.",
-                        regex.as_str()
-                    )
-                    .green()
-                );
-                self.kill_qemu(child)?;
-                return Ok(());
-            }
-        }
-
-        Ok(())
-    }
-
     fn kill_qemu(&self, child: &mut Child) -> anyhow::Result<()> {
         child.kill()?;
 
@@ -301,30 +416,6 @@ impl QemuRunner {
             .arg("icanon")
             .status();
 
-        // 刷新输出
-        let _ = io::stdout().flush();
-        println!();
-
-        Ok(())
-    }
-
-    fn preper_regex(&mut self) -> anyhow::Result<()> {
-        // Prepare regex patterns if needed
-        // Compile success regex patterns
-        for pattern in self.config.success_regex.iter() {
-            // Compile and store the regex
-            let regex =
-                regex::Regex::new(pattern).map_err(|e| anyhow!("success regex error: {e}"))?;
-            self.success_regex.push(regex);
-        }
-
-        // Compile fail regex patterns
-        for pattern in self.config.fail_regex.iter() {
-            // Compile and store the regex
-            let regex = regex::Regex::new(pattern).map_err(|e| anyhow!("fail regex error: {e}"))?;
-            self.fail_regex.push(regex);
-        }
-
         Ok(())
     }
 }
@@ -0,0 +1,285 @@
+//! Test reports of `cargo-osrun`. Each run of a test binary adds a record to
+//! `<path>.json`, from which `<path>.xml` is rewritten in JUnit format, so the
+//! reports cover every binary of a `cargo test`.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hooks::RunnerKind,
+    run::{RunFailed, RunReport, harness::TestResult},
+};
+
+/// Report path used when `cargo-osrun` gets no `--report`
+pub const REPORT_ENV: &str = "OSTOOL_REPORT";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// The binary could not be run
+    Error,
+}
+
+/// Run of one test binary
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TestRecord {
+    pub name: String,
+    /// Test binary, without the hash cargo appends
+    pub binary: String,
+    pub runner: RunnerKind,
+    pub outcome: Outcome,
+    /// Seconds
+    pub duration: f64,
+    /// The success or fail regex that ended the run
+    pub pattern: Option<String>,
+    pub message: Option<String>,
+    pub console: String,
+    /// Results of the `test_parser` of the runner config
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestResult>,
+}
+
+impl TestRecord {
+    /// Record of the result of [`run_qemu`](super::qemu::run_qemu) or
+    /// [`run_uboot`](super::uboot::run_uboot)
+    pub fn new(
+        name: String,
+        binary: String,
+        runner: RunnerKind,
+        result: &anyhow::Result<RunReport>,
+    ) -> Self {
+        let (outcome, report, message) = match result {
+            Ok(report) => (Outcome::Passed, Some(report), None),
+            Err(e) => match e.downcast_ref::<RunFailed>() {
+                Some(failed) => (
+                    Outcome::Failed,
+                    Some(failed.0.as_ref()),
+                    Some(failed.to_string()),
+                ),
+                None => (Outcome::Error, None, Some(format!("{e:#}"))),
+            },
+        };
+        Self {
+            name,
+            binary,
+            runner,
+            outcome,
+            duration: report.map_or(0.0, |r| r.duration.as_secs_f64()),
+            pattern: report.and_then(|r| r.matched()).map(str::to_string),
+            message,
+            console: report.map(|r| r.console.clone()).unwrap_or_default(),
+            tests: report.map(|r| r.tests.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TestReport {
+    pub tests: Vec<TestRecord>,
+}
+
+impl TestReport {
+    /// Adds `record` to the report at `path`, then writes its JSON and JUnit files
+    pub fn append(path: &Path, record: TestRecord) -> anyhow::Result<()> {
+        let (json, xml) = report_paths(path);
+        let mut report = match std::fs::read(&json) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("invalid test report: {}", json.display()))?,
+            Err(_) => TestReport::default(),
+        };
+        report.tests.push(record);
+
+        if let Some(parent) = json.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&json, serde_json::to_vec_pretty(&report)?)
+            .with_context(|| format!("can not write test report: {}", json.display()))?;
+        std::fs::write(&xml, report.junit())
+            .with_context(|| format!("can not write test report: {}", xml.display()))?;
+        Ok(())
+    }
+
+    /// The report as JUnit XML, a test suite per binary
+    pub fn junit(&self) -> String {
+        let mut binaries: Vec<&str> = Vec::new();
+        for test in &self.tests {
+            if !binaries.contains(&test.binary.as_str()) {
+                binaries.push(&test.binary);
+            }
+        }
+
+        let count =
+            |tests: &[&TestRecord], outcome| tests.iter().filter(|t| t.outcome == outcome).count();
+        let all: Vec<_> = self.tests.iter().collect();
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            out,
+            "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            all.len(),
+            count(&all, Outcome::Failed),
+            count(&all, Outcome::Error),
+            all.iter().map(|t| t.duration).sum::<f64>()
+        );
+        for binary in binaries {
+            let tests: Vec<_> = self.tests.iter().filter(|t| t.binary == binary).collect();
+            let _ = writeln!(
+                out,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+                escape(binary),
+                tests.len(),
+                count(&tests, Outcome::Failed),
+                count(&tests, Outcome::Error),
+                tests.iter().map(|t| t.duration).sum::<f64>()
+            );
+            for test in tests {
+                let _ = writeln!(
+                    out,
+                    "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+                    escape(&test.name),
+                    escape(binary),
+                    test.duration
+                );
+                if let Some(pattern) = &test.pattern {
+                    let _ = writeln!(out, "      <properties>");
+                    let _ = writeln!(
+                        out,
+                        "        <property name=\"pattern\" value=\"{}\"/>",
+                        escape(pattern)
+                    );
+                    let _ = writeln!(out, "      </properties>");
+                }
+                let message = escape(test.message.as_deref().unwrap_or_default());
+                match test.outcome {
+                    Outcome::Passed => {}
+                    Outcome::Failed => {
+                        let _ = writeln!(out, "      <failure message=\"{message}\"/>");
+                    }
+                    Outcome::Error => {
+                        let _ = writeln!(out, "      <error message=\"{message}\"/>");
+                    }
+                }
+                if !test.console.is_empty() {
+                    let _ = writeln!(
+                        out,
+                        "      <system-out>{}</system-out>",
+                        escape(&test.console)
+                    );
+                }
+                let _ = writeln!(out, "    </testcase>");
+            }
+            let _ = writeln!(out, "  </testsuite>");
+        }
+        out.push_str("</testsuites>\n");
+        out
+    }
+}
+
+/// The JSON and the JUnit file of the report at `path`
+pub fn report_paths(path: &Path) -> (PathBuf, PathBuf) {
+    (path.with_extension("json"), path.with_extension("xml"))
+}
+
+/// Name of a test binary, without the `-<hash>` cargo appends
+pub fn binary_name(elf: &Path) -> String {
+    let stem = elf
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match stem.rsplit_once('-') {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            name.to_string()
+        }
+        _ => stem,
+    }
+}
+
+/// Escapes text for XML, dropping the control characters XML can not hold
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::run::RunExit;
+
+    #[test]
+    fn test_binary_name() {
+        assert_eq!(
+            binary_name(Path::new("target/debug/deps/smoke-1a2b3c4d5e6f7a8b")),
+            "smoke"
+        );
+        assert_eq!(
+            binary_name(Path::new("target/release/my-kernel")),
+            "my-kernel"
+        );
+    }
+
+    #[test]
+    fn test_junit() {
+        let report = |exit| {
+            RunReport {
+                runner: RunnerKind::Qemu,
+                exit,
+                console: "boot\x1b[0m <ok>\n".into(),
+                tests: Vec::new(),
+                duration: Duration::from_millis(1500),
+            }
+            .check()
+        };
+        let passed = TestRecord::new(
+            "smoke".into(),
+            "smoke".into(),
+            RunnerKind::Qemu,
+            &report(RunExit::Success {
+                pattern: "ok".into(),
+                line: "<ok>".into(),
+            }),
+        );
+        let failed = TestRecord::new(
+            "mm".into(),
+            "kernel".into(),
+            RunnerKind::Qemu,
+            &report(RunExit::Failure {
+                pattern: "panic".into(),
+                line: "panic \"here\"".into(),
+            }),
+        );
+        assert_eq!(passed.outcome, Outcome::Passed);
+        assert_eq!(failed.outcome, Outcome::Failed);
+        assert_eq!(failed.pattern.as_deref(), Some("panic"));
+
+        let xml = TestReport {
+            tests: vec![passed, failed],
+        }
+        .junit();
+        assert!(xml.contains(r#"<testsuites tests="2" failures="1" errors="0" time="3.000">"#));
+        assert!(xml.contains(r#"<testcase name="mm" classname="kernel" time="1.500">"#));
+        assert!(xml.contains(
+            r#"<failure message="Detected failure pattern &apos;panic&apos; in QEMU output: panic &quot;here&quot;"/>"#
+        ));
+        assert!(xml.contains("<system-out>boot[0m &lt;ok&gt;\n</system-out>"));
+    }
+}
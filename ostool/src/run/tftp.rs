@@ -1,6 +1,5 @@
 use std::net::{IpAddr, Ipv4Addr};
 
-use colored::Colorize as _;
 use tftpd::{Config, Server};
 
 use crate::ctx::AppContext;
@@ -29,8 +28,7 @@ pub fn run_tftp_server(app: &AppContext) -> anyhow::Result<()> {
     std::thread::spawn(move || {
         let mut server = Server::new(&config)
                 .inspect_err(|e| {
-                    println!("{}", e);
-                    println!("{}","TFTP server 启动失败：{e:?}。若权限不足，尝试执行 `sudo setcap cap_net_bind_service=+eip $(which cargo-osrun)&&sudo setcap cap_net_bind_service=+eip $(which ostool)` 并重启终端".red());
+                    error!("TFTP server 启动失败：{e:?}。若权限不足，尝试执行 `sudo setcap cap_net_bind_service=+eip $(which cargo-osrun)&&sudo setcap cap_net_bind_service=+eip $(which ostool)` 并重启终端");
                     std::process::exit(1);
                 }).unwrap();
         server.listen();
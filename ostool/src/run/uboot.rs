@@ -2,15 +2,12 @@ use std::{
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use byte_unit::Byte;
-use colored::Colorize;
-use fitimage::{ComponentConfig, FitImageBuilder, FitImageConfig};
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use jkconfig::data::app_data::default_schema_by_init;
+use fitimage::{ComponentConfig, FitImageBuilder, FitImageConfig, LegacyImageBuilder};
 use log::{info, warn};
 use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
 use schemars::JsonSchema;
@@ -18,7 +15,14 @@ use serde::{Deserialize, Serialize};
 use tokio::fs;
 use uboot_shell::UbootShell;
 
-use crate::{ctx::AppContext, run::tftp, sterm::SerialTerm, utils::replace_env_placeholders};
+use crate::{
+    config::schema::SchemaKind,
+    ctx::AppContext,
+    event::Event,
+    hooks::{HookStage, RunnerKind},
+    run::{ConsoleMonitor, RunExit, RunReport, harness::TestParser, kernel_cmdline, tftp},
+    sterm::SerialTerm,
+};
 
 /// FIT image 生成相关的错误消息常量
 mod errors {
@@ -26,6 +30,8 @@ mod errors {
     pub const DTB_READ_ERROR: &str = "读取 DTB 文件失败";
     pub const FIT_BUILD_ERROR: &str = "构建 FIT image 失败";
     pub const FIT_SAVE_ERROR: &str = "保存 FIT image 失败";
+    pub const LEGACY_BUILD_ERROR: &str = "构建 legacy uImage 失败";
+    pub const LEGACY_SAVE_ERROR: &str = "保存 legacy uImage 失败";
     pub const DIR_ERROR: &str = "无法获取 kernel 文件目录";
 }
 
@@ -42,6 +48,9 @@ pub struct UbootConfig {
     /// Fit Image load address
     /// if not specified, use automatically calculated address
     pub fit_load_addr: Option<String>,
+    /// Boot image format, `fit` by default
+    /// `legacy` builds a uImage and loads the DTB separately, for U-Boot without FIT support
+    pub image_format: Option<ImageFormat>,
     /// TFTP boot configuration
     pub net: Option<Net>,
     /// Board reset command
@@ -52,10 +61,26 @@ pub struct UbootConfig {
     pub board_power_off_cmd: Option<String>,
     pub success_regex: Vec<String>,
     pub fail_regex: Vec<String>,
+    /// Seconds before a run no pattern matched in is stopped as failed
+    pub timeout: Option<u64>,
+    /// Kernel command line, set in the `bootargs` env before booting
+    pub cmdline: Option<String>,
+    /// Parser of the test results printed on the console, a failed test
+    /// fails the run
+    pub test_parser: Option<TestParser>,
     pub uboot_cmd: Option<Vec<String>>,
 }
 
 impl UbootConfig {
+    /// Config written when none exists
+    pub fn template() -> Self {
+        UbootConfig {
+            serial: "/dev/ttyUSB0".to_string(),
+            baud_rate: "115200".into(),
+            ..Default::default()
+        }
+    }
+
     pub fn kernel_load_addr_int(&self) -> Option<u64> {
         self.addr_int(self.kernel_load_addr.as_ref())
     }
@@ -75,6 +100,17 @@ impl UbootConfig {
     }
 }
 
+#[derive(Default, Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// FIT image with kernel and DTB
+    #[default]
+    #[serde(rename = "fit")]
+    Fit,
+    /// Legacy uImage (`mkimage -T kernel`)
+    #[serde(rename = "legacy")]
+    Legacy,
+}
+
 #[derive(Default, Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Net {
     pub interface: String,
@@ -88,45 +124,35 @@ pub struct Net {
 pub struct RunUbootArgs {
     pub config: Option<PathBuf>,
     pub show_output: bool,
+    /// Appended to the `cmdline` of the config, e.g. the args of `cargo test -- <args>`
+    pub cmdline_args: Vec<String>,
 }
 
-pub async fn run_uboot(ctx: AppContext, args: RunUbootArgs) -> anyhow::Result<()> {
+/// Boots the kernel on a board through U-Boot. A failed run is an error
+/// holding a [`RunFailed`](crate::run::RunFailed) with the report.
+pub async fn run_uboot(ctx: AppContext, args: RunUbootArgs) -> anyhow::Result<RunReport> {
     // Build logic will be implemented here
     let config_path = match args.config.clone() {
         Some(path) => path,
         None => ctx.paths.workspace.join(".uboot.toml"),
     };
 
-    let schema_path = default_schema_by_init(&config_path);
-
-    let schema = schemars::schema_for!(UbootConfig);
-    let schema_json = serde_json::to_value(&schema)?;
-    let schema_content = serde_json::to_string_pretty(&schema_json)?;
-    fs::write(&schema_path, schema_content).await?;
+    crate::config::schema::write_schema(SchemaKind::Uboot, &config_path).await?;
 
     // 初始化AppData
     // let app_data = AppData::new(Some(&config_path), Some(schema_path))?;
 
     let config = if config_path.exists() {
-        println!("Using U-Boot config: {}", config_path.display());
-        let mut config_content = fs::read_to_string(&config_path)
-            .await
-            .map_err(|_| anyhow!("can not open config file: {}", config_path.display()))?;
-
-        config_content = replace_env_placeholders(&config_content)?;
-
-        let config: UbootConfig = toml::from_str(&config_content)?;
-        config
+        info!("Using U-Boot config: {}", config_path.display());
+        let mut loaded = crate::config::load(&config_path).await?;
+        loaded.substitute(&ctx.variables())?;
+        loaded.parse::<UbootConfig>()?
     } else {
-        let config = UbootConfig {
-            serial: "/dev/ttyUSB0".to_string(),
-            baud_rate: "115200".into(),
-            ..Default::default()
-        };
-
+        let config = UbootConfig::template();
         fs::write(&config_path, toml::to_string_pretty(&config)?).await?;
         config
     };
+    let config = ctx.overrides.apply(SchemaKind::Uboot, config)?;
 
     let baud_rate = config
         .baud_rate
@@ -135,21 +161,29 @@ pub async fn run_uboot(ctx: AppContext, args: RunUbootArgs) -> anyhow::Result<()
 
     let mut runner = Runner {
         ctx,
+        cmdline: kernel_cmdline(config.cmdline.as_deref(), &args.cmdline_args),
         config,
         baud_rate,
-        success_regex: vec![],
-        fail_regex: vec![],
+        show_output: args.show_output,
     };
-    runner.run().await?;
-    Ok(())
+    runner.ctx.run_hooks(HookStage::PreRun)?;
+    let report = runner.run().await?;
+    runner.ctx.events.emit(Event::Exit {
+        runner: report.runner,
+        exit: report.exit.clone(),
+        duration: report.duration,
+    });
+    let report = report.check()?;
+    runner.ctx.run_hooks(HookStage::PostRun)?;
+    Ok(report)
 }
 
 struct Runner {
     ctx: AppContext,
     config: UbootConfig,
-    success_regex: Vec<regex::Regex>,
-    fail_regex: Vec<regex::Regex>,
+    cmdline: Option<String>,
     baud_rate: u32,
+    show_output: bool,
 }
 
 impl Runner {
@@ -169,7 +203,7 @@ impl Runner {
         kernel_load_addr: u64,
         kernel_entry_addr: u64,
         fdt_load_addr: Option<u64>,
-        _ramfs_load_addr: Option<u64>,
+        ramfs_load_addr: Option<u64>,
     ) -> anyhow::Result<PathBuf> {
         info!("Making FIT image...");
         // 生成压缩的 FIT image
@@ -194,12 +228,7 @@ impl Runner {
             Byte::from(kernel_data.len())
         );
 
-        let arch = match self.ctx.arch.as_ref().unwrap() {
-            object::Architecture::Aarch64 => "arm64",
-            object::Architecture::Arm => "arm",
-            object::Architecture::LoongArch64 => "loongarch64",
-            _ => todo!(),
-        };
+        let arch = self.image_arch()?;
 
         // 创建配置，与 test.its 文件中的参数一致
         let mut config = FitImageConfig::new("Various kernels, ramdisks and FDT blobs")
@@ -251,6 +280,30 @@ impl Runner {
             warn!("未指定 DTB 文件，将生成仅包含 kernel 的 FIT image");
         }
 
+        let mut ramdisk_name = None;
+        if let Some(initramfs) = &self.ctx.paths.artifacts.initramfs {
+            let data = fs::read(initramfs)
+                .await
+                .map_err(|e| anyhow!("can not read initramfs {}: {e}", initramfs.display()))?;
+            info!(
+                "initramfs: {} (size: {:.2})",
+                initramfs.display(),
+                Byte::from(data.len())
+            );
+            ramdisk_name = Some("ramdisk");
+
+            let mut ramdisk_config = ComponentConfig::new("ramdisk", data)
+                .with_description("This ramdisk")
+                .with_type("ramdisk")
+                .with_arch(arch)
+                .with_os("linux");
+            match ramfs_load_addr {
+                Some(addr) => ramdisk_config = ramdisk_config.with_load_address(addr),
+                None => warn!("No $ramdisk_addr_r, U-Boot will use the ramdisk in place"),
+            }
+            config = config.with_ramdisk(ramdisk_config);
+        }
+
         config = config
             .with_default_config("config-ostool")
             .with_configuration(
@@ -258,7 +311,7 @@ impl Runner {
                 "ostool configuration",
                 Some("kernel"),
                 fdt_name,
-                None::<String>,
+                ramdisk_name,
             );
 
         // 使用新的 mkimage API 构建 FIT image
@@ -282,7 +335,88 @@ impl Runner {
         Ok(output_path)
     }
 
-    async fn run(&mut self) -> anyhow::Result<()> {
+    /// 生成 legacy uImage，DTB 复制到 uImage 所在目录以便单独加载
+    ///
+    /// # 返回值
+    /// 返回 uImage 路径和复制后的 DTB 路径
+    async fn generate_legacy_image(
+        &self,
+        kernel_path: &Path,
+        dtb_path: Option<&Path>,
+        kernel_load_addr: u64,
+        kernel_entry_addr: u64,
+    ) -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
+        info!("Making legacy uImage...");
+        if self.ctx.paths.artifacts.initramfs.is_some() {
+            warn!("The initramfs is not loaded with a legacy uImage, use image_format = \"fit\"");
+        }
+        let output_dir = kernel_path.parent().ok_or(anyhow!(errors::DIR_ERROR))?;
+
+        let kernel_data = fs::read(kernel_path).await.map_err(|e| {
+            anyhow!(
+                "{} {}: {}",
+                errors::KERNEL_READ_ERROR,
+                kernel_path.display(),
+                e
+            )
+        })?;
+
+        info!(
+            "kernel: {} (size: {:.2})",
+            kernel_path.display(),
+            Byte::from(kernel_data.len())
+        );
+
+        let image = LegacyImageBuilder::kernel("ostool")
+            .with_arch(self.image_arch()?)
+            .with_os("linux")
+            .with_compression(true)
+            .with_load_address(kernel_load_addr)
+            .with_entry_point(kernel_entry_addr)
+            .build(&kernel_data)
+            .map_err(|e| anyhow!("{}: {}", errors::LEGACY_BUILD_ERROR, e))?;
+
+        let output_path = output_dir.join("uImage");
+        fs::write(&output_path, image).await.map_err(|e| {
+            anyhow!(
+                "{} {}: {}",
+                errors::LEGACY_SAVE_ERROR,
+                output_path.display(),
+                e
+            )
+        })?;
+        info!("uImage ok: {}", output_path.display());
+
+        let Some(dtb_path) = dtb_path else {
+            warn!("未指定 DTB 文件，uImage 将不带 DTB 启动");
+            return Ok((output_path, None));
+        };
+
+        let dtb_name = dtb_path
+            .file_name()
+            .ok_or(anyhow!("Invalid DTB filename: {}", dtb_path.display()))?;
+        let dtb_out = output_dir.join(dtb_name);
+        if dtb_path.canonicalize().ok() != dtb_out.canonicalize().ok() {
+            fs::copy(dtb_path, &dtb_out)
+                .await
+                .map_err(|e| anyhow!("{} {}: {}", errors::DTB_READ_ERROR, dtb_path.display(), e))?;
+        }
+
+        Ok((output_path, Some(dtb_out)))
+    }
+
+    /// Architecture name used in FIT and legacy image headers
+    fn image_arch(&self) -> anyhow::Result<&'static str> {
+        let arch = match self.ctx.arch.as_ref() {
+            Some(object::Architecture::Aarch64) => "arm64",
+            Some(object::Architecture::Arm) => "arm",
+            Some(object::Architecture::LoongArch64) => "loongarch64",
+            arch => bail!("Unsupported architecture for U-Boot image: {arch:?}"),
+        };
+        Ok(arch)
+    }
+
+    async fn run(&mut self) -> anyhow::Result<RunReport> {
         let res = self._run().await;
         if let Some(ref cmd) = self.config.board_power_off_cmd
             && !cmd.trim().is_empty()
@@ -293,8 +427,14 @@ impl Runner {
         res
     }
 
-    async fn _run(&mut self) -> anyhow::Result<()> {
-        self.preper_regex()?;
+    async fn _run(&mut self) -> anyhow::Result<RunReport> {
+        let monitor = ConsoleMonitor::new(
+            &self.ctx,
+            &self.config.success_regex,
+            &self.config.fail_regex,
+            self.config.test_parser.as_ref(),
+            self.show_output,
+        )?;
         self.ctx.objcopy_output_bin()?;
 
         let kernel = self
@@ -336,7 +476,10 @@ impl Runner {
             .try_clone()
             .map_err(|e| anyhow!("Failed to clone serial port: {e}"))?;
 
-        println!("Waiting for board on power or reset...");
+        self.ctx
+            .events
+            .status("Waiting for board on power or reset...");
+        let start = Instant::now();
         let handle: thread::JoinHandle<anyhow::Result<UbootShell>> = thread::spawn(move || {
             let uboot = UbootShell::new(tx, rx)?;
             Ok(uboot)
@@ -351,6 +494,7 @@ impl Runner {
         let mut net_ok = false;
 
         let mut uboot = handle.join().unwrap()?;
+        self.ctx.events.emit(Event::UbootPromptDetected);
         uboot.set_env("autoload", "yes")?;
 
         if let Some(ref cmds) = self.config.uboot_cmd {
@@ -435,52 +579,98 @@ impl Runner {
         }
 
         let dtb_path = dtb.as_ref().map(Path::new);
-        let fitimage = self
-            .generate_fit_image(
-                kernel,
-                dtb_path,
-                kernel_entry,
-                kernel_entry,
-                fdt_load_addr,
-                ramfs_load_addr,
-            )
-            .await?;
-
-        let fitname = if is_tftp {
-            let tftp_dir = self
-                .config
-                .net
-                .as_ref()
-                .and_then(|net| net.tftp_dir.as_ref())
-                .unwrap();
+        let image_format = self.config.image_format.unwrap_or_default();
+        let (image, dtb_image) = match image_format {
+            ImageFormat::Fit => {
+                let fitimage = self
+                    .generate_fit_image(
+                        kernel,
+                        dtb_path,
+                        kernel_entry,
+                        kernel_entry,
+                        fdt_load_addr,
+                        ramfs_load_addr,
+                    )
+                    .await?;
+                (fitimage, None)
+            }
+            ImageFormat::Legacy => {
+                self.generate_legacy_image(kernel, dtb_path, kernel_entry, kernel_entry)
+                    .await?
+            }
+        };
 
-            let fitimage = fitimage.file_name().unwrap();
-            let tftp_path = PathBuf::from(tftp_dir).join(fitimage);
+        // Legacy images carry no DTB, it is loaded to $fdt_addr_r on its own.
+        let dtb_image = match dtb_image {
+            Some(dtb) => {
+                let addr = fdt_load_addr
+                    .ok_or(anyhow!("legacy uImage needs $fdt_addr_r to load the DTB"))?;
+                Some((dtb, addr))
+            }
+            None => None,
+        };
 
-            info!("Setting TFTP file path: {}", tftp_path.display());
-            tftp_path.display().to_string()
-        } else {
-            let name = fitimage
+        let remote_name = |path: &Path| -> anyhow::Result<String> {
+            let name = path
                 .file_name()
                 .and_then(|n| n.to_str())
-                .ok_or(anyhow!("Invalid fitimage filename"))?;
+                .ok_or(anyhow!("Invalid image filename: {}", path.display()))?;
+
+            if is_tftp {
+                let tftp_dir = self
+                    .config
+                    .net
+                    .as_ref()
+                    .and_then(|net| net.tftp_dir.as_ref())
+                    .unwrap();
+                let tftp_path = PathBuf::from(tftp_dir).join(name);
+
+                info!("Setting TFTP file path: {}", tftp_path.display());
+                Ok(tftp_path.display().to_string())
+            } else {
+                info!("Using image filename: {}", name);
+                Ok(name.to_string())
+            }
+        };
 
-            info!("Using fitimage filename: {}", name);
-            name.to_string()
+        let fitname = remote_name(&image)?;
+        let bootm = match &dtb_image {
+            Some((_, addr)) => format!("bootm {fit_loadaddr:#x} - {addr:#x}"),
+            None => "bootm".to_string(),
         };
 
         let bootcmd =
             if let Some(ref board_ip) = self.config.net.as_ref().and_then(|e| e.board_ip.clone()) {
                 uboot.set_env("ipaddr", board_ip)?;
-                format!("tftp {fitname} && bootm",)
+                let mut cmd = format!("tftp {fitname}");
+                if let Some((dtb, addr)) = &dtb_image {
+                    cmd += &format!(" && tftp {addr:#x} {}", remote_name(dtb)?);
+                }
+                format!("{cmd} && {bootm}")
             } else if net_ok {
-                format!("dhcp {fitname} && bootm",)
+                let mut cmd = format!("dhcp {fitname}");
+                if let Some((dtb, addr)) = &dtb_image {
+                    cmd += &format!(" && tftp {addr:#x} {}", remote_name(dtb)?);
+                }
+                format!("{cmd} && {bootm}")
             } else {
-                info!("No TFTP config, using loady to upload FIT image...");
-                Self::uboot_loady(&mut uboot, fit_loadaddr as usize, fitimage);
-                "bootm".to_string()
+                info!("No TFTP config, using loady to upload image...");
+                if let Some((dtb, addr)) = &dtb_image {
+                    self.uboot_loady(&mut uboot, *addr as usize, dtb);
+                }
+                self.uboot_loady(&mut uboot, fit_loadaddr as usize, image);
+                bootm
             };
 
+        if let Some(cmdline) = &self.cmdline {
+            // quoted for the U-Boot shell
+            let value = match cmdline.contains('\'') {
+                true => format!("\"{cmdline}\""),
+                false => format!("'{cmdline}'"),
+            };
+            uboot.set_env("bootargs", value)?;
+        }
+
         info!("Booting kernel with command: {}", bootcmd);
         uboot.cmd_without_reply(&bootcmd)?;
         // if self.config.net.is_some() {
@@ -501,62 +691,52 @@ impl Runner {
 
         drop(uboot);
 
-        println!("{}", "Interacting with U-Boot shell...".green());
-
-        let success_regex = self.success_regex.clone();
-        let fail_regex = self.fail_regex.clone();
-
-        let res = Arc::new(Mutex::<Option<anyhow::Result<()>>>::new(None));
-        let res_clone = res.clone();
-        let mut shell = SerialTerm::new(tx, rx, move |h, line| {
-            for regex in success_regex.iter() {
-                if regex.is_match(line) {
-                    println!("{}", "\r\n=== SUCCESS PATTERN MATCHED ===".green());
-                    h.stop();
-                    let mut res_lock = res_clone.lock().unwrap();
-                    *res_lock = Some(Ok(()));
-                    return;
-                }
-            }
-
-            for regex in fail_regex.iter() {
-                if regex.is_match(line) {
-                    println!("{}", "\r\n=== FAIL PATTERN MATCHED ===".red());
+        self.ctx.events.status("Interacting with U-Boot shell...");
+
+        let monitor = Arc::new(Mutex::new(Some(monitor)));
+        let exit = Arc::new(Mutex::new(None));
+        let timeout = self.config.timeout;
+        let mut shell = SerialTerm::new(tx, rx, {
+            let monitor = monitor.clone();
+            let exit = exit.clone();
+            move |h, data| {
+                if let Some(monitor) = monitor.lock().unwrap().as_mut()
+                    && let Some(res) = monitor.feed(data)
+                {
                     h.stop();
-                    let mut res_lock = res_clone.lock().unwrap();
-                    *res_lock = Some(Err(anyhow!("Fail pattern matched: {}", line)));
-                    return;
+                    *exit.lock().unwrap() = Some(res);
                 }
             }
-        });
+        })
+        .timeout(timeout.map(Duration::from_secs));
         shell.run().await?;
-        {
-            let mut res_lock = res.lock().unwrap();
-            if let Some(result) = res_lock.take() {
-                result?;
-            }
-        }
-        Ok(())
-    }
-
-    fn preper_regex(&mut self) -> anyhow::Result<()> {
-        // Prepare regex patterns if needed
-        // Compile success regex patterns
-        for pattern in self.config.success_regex.iter() {
-            // Compile and store the regex
-            let regex =
-                regex::Regex::new(pattern).map_err(|e| anyhow!("success regex error: {e}"))?;
-            self.success_regex.push(regex);
-        }
-
-        // Compile fail regex patterns
-        for pattern in self.config.fail_regex.iter() {
-            // Compile and store the regex
-            let regex = regex::Regex::new(pattern).map_err(|e| anyhow!("fail regex error: {e}"))?;
-            self.fail_regex.push(regex);
+        if shell.timed_out() {
+            let seconds = timeout.unwrap_or_default();
+            self.ctx.events.emit(Event::Timeout { seconds });
+            *exit.lock().unwrap() = Some(RunExit::Timeout { seconds });
         }
 
-        Ok(())
+        let (console, tests) = monitor
+            .lock()
+            .unwrap()
+            .take()
+            .map(ConsoleMonitor::finish)
+            .unwrap_or_default();
+        let exit =
+            exit.lock()
+                .unwrap()
+                .take()
+                .unwrap_or(match self.config.success_regex.is_empty() {
+                    true => RunExit::Stopped,
+                    false => RunExit::Interrupted,
+                });
+        Ok(RunReport {
+            runner: RunnerKind::Uboot,
+            exit,
+            console,
+            tests,
+            duration: start.elapsed(),
+        })
     }
 
     fn detect_tftp_ip(&self) -> Option<String> {
@@ -586,25 +766,20 @@ impl Runner {
         Some(ip_string)
     }
 
-    fn uboot_loady(uboot: &mut UbootShell, addr: usize, file: impl Into<PathBuf>) {
-        println!("{}", "\r\nsend file".green());
-
-        let pb = ProgressBar::new(100);
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn core::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#>-"));
+    fn uboot_loady(&self, uboot: &mut UbootShell, addr: usize, file: impl Into<PathBuf>) {
+        let events = &self.ctx.events;
+        events.status("send file");
 
         let res = uboot
-            .loady(addr, file, |x, a| {
-                pb.set_length(a as _);
-                pb.set_position(x as _);
+            .loady(addr, file, |sent, total| {
+                events.emit(Event::TransferProgress {
+                    sent: sent as _,
+                    total: total as _,
+                });
             })
             .unwrap();
 
-        pb.finish_with_message("upload done");
-
-        println!("{}", res);
-        println!("send ok");
+        events.status(res);
+        events.status("send ok");
     }
 }
@@ -0,0 +1,186 @@
+//! Library entry point for programs driving ostool, e.g. an `xtask`.
+//!
+//! A [`Session`] builds and runs in-process and returns typed reports. All
+//! progress goes to the [`EventSink`](crate::event::EventSink) given to
+//! [`Session::events`], the console by default.
+//!
+//! ```no_run
+//! use ostool::{event::Event, run::qemu::RunQemuArgs, session::Session};
+//!
+//! # async fn xtask() -> anyhow::Result<()> {
+//! let (tx, rx) = std::sync::mpsc::channel::<Event>();
+//! let mut session = Session::new("kernel").events(tx).set("qemu.uefi=false")?;
+//! let build = session.build().await?;
+//! println!("built {:?} in {:?}", build.artifacts.elf, build.duration);
+//!
+//! let run = session
+//!     .run_qemu(RunQemuArgs {
+//!         qemu_config: None,
+//!         dtb_dump: false,
+//!         show_output: false,
+//!         cmdline_args: vec!["--filter".into(), "mm".into()],
+//!     })
+//!     .await?;
+//! println!("{:?}, matched {:?}", run.exit, run.matched());
+//! # drop(rx);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use object::Architecture;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    config::overrides::Overrides,
+    ctx::{AppContext, OutputArtifacts, PathConfig},
+    event::{EventSink, Events},
+    hooks::RunnerKind,
+    run::{
+        RunReport,
+        qemu::{self, RunQemuArgs},
+        uboot::{self, RunUbootArgs},
+    },
+};
+
+/// Result of [`Session::build`]
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildReport {
+    pub artifacts: OutputArtifacts,
+    #[serde(serialize_with = "arch_name")]
+    pub arch: Option<Architecture>,
+    #[serde(serialize_with = "crate::event::secs")]
+    pub duration: Duration,
+}
+
+fn arch_name<S: Serializer>(arch: &Option<Architecture>, s: S) -> Result<S::Ok, S::Error> {
+    match arch {
+        Some(arch) => s.serialize_some(&format!("{arch:?}").to_lowercase()),
+        None => s.serialize_none(),
+    }
+}
+
+/// Builds and runs a kernel the way `ostool build` and `ostool run` do
+pub struct Session {
+    ctx: AppContext,
+    build_config: Option<PathBuf>,
+}
+
+impl Session {
+    /// Session for the project in `workspace`, which holds `.build.toml`
+    pub fn new(workspace: impl Into<PathBuf>) -> Self {
+        let workspace = workspace.into();
+        Self {
+            ctx: AppContext {
+                paths: PathConfig {
+                    manifest: workspace.clone(),
+                    workspace,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            build_config: None,
+        }
+    }
+
+    /// Build config file, `.build.toml` in the workspace by default
+    pub fn build_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.build_config = Some(path.into());
+        self
+    }
+
+    /// Build profile defined in the `profiles` table
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.ctx.build_profile = Some(profile.into());
+        self
+    }
+
+    /// Debug build, and QEMU waiting for a debugger
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.ctx.debug = debug;
+        self
+    }
+
+    /// Use cached remote configs instead of downloading them
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.ctx.offline = offline;
+        self
+    }
+
+    /// Overrides a config key like `--set`, e.g. `qemu.uefi=true`
+    pub fn set(mut self, spec: &str) -> anyhow::Result<Self> {
+        self.ctx
+            .overrides
+            .extend(Overrides::parse(&[spec.to_string()])?);
+        Ok(self)
+    }
+
+    /// Where progress and console output go
+    pub fn events(mut self, sink: impl EventSink + 'static) -> Self {
+        self.ctx.events = Events::new(sink);
+        self
+    }
+
+    /// State of the last build, for what the builder methods do not cover
+    pub fn context(&self) -> &AppContext {
+        &self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut AppContext {
+        &mut self.ctx
+    }
+
+    pub async fn build(&mut self) -> anyhow::Result<BuildReport> {
+        self.ctx.runner = None;
+        let res = self.build_inner().await;
+        if let Err(e) = &res {
+            self.ctx.run_failure_hooks(e);
+        }
+        res
+    }
+
+    /// Builds, then runs the kernel in QEMU
+    pub async fn run_qemu(&mut self, args: RunQemuArgs) -> anyhow::Result<RunReport> {
+        self.ctx.runner = Some(RunnerKind::Qemu);
+        let res = match self.build_inner().await {
+            Ok(_) => qemu::run_qemu(self.ctx.clone(), args).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &res {
+            self.ctx.run_failure_hooks(e);
+        }
+        res
+    }
+
+    /// Builds, then boots the kernel on a board through U-Boot
+    pub async fn run_uboot(&mut self, args: RunUbootArgs) -> anyhow::Result<RunReport> {
+        self.ctx.runner = Some(RunnerKind::Uboot);
+        let res = match self.build_inner().await {
+            Ok(_) => uboot::run_uboot(self.ctx.clone(), args).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &res {
+            self.ctx.run_failure_hooks(e);
+        }
+        res
+    }
+
+    async fn build_inner(&mut self) -> anyhow::Result<BuildReport> {
+        let start = Instant::now();
+        self.ctx.paths.artifacts = OutputArtifacts::default();
+        let config = self
+            .ctx
+            .prepare_build_config(self.build_config.clone(), false)
+            .await?;
+        self.ctx.build_with_config(&config).await?;
+        Ok(BuildReport {
+            artifacts: self.ctx.paths.artifacts.clone(),
+            arch: self.ctx.arch,
+            duration: start.elapsed(),
+        })
+    }
+}
@@ -2,7 +2,7 @@ use std::io::{self, Read, Write};
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossterm::{
     event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
@@ -11,12 +11,14 @@ use crossterm::{
 
 type Tx = Box<dyn Write + Send>;
 type Rx = Box<dyn Read + Send>;
-type OnlineCallback = Box<dyn Fn(&TermHandle, &str) + Send + Sync>;
+type OnDataCallback = Box<dyn Fn(&TermHandle, &[u8]) + Send + Sync>;
 
 pub struct SerialTerm {
     tx: Arc<Mutex<Tx>>,
     rx: Arc<Mutex<Rx>>,
-    on_line: Option<OnlineCallback>,
+    on_data: Option<OnDataCallback>,
+    timeout: Option<Duration>,
+    timed_out: bool,
 }
 
 pub struct TermHandle {
@@ -42,17 +44,32 @@ enum KeySequenceState {
 }
 
 impl SerialTerm {
-    pub fn new<F>(tx: Tx, rx: Rx, on_line: F) -> Self
+    /// `on_data` receives everything read from the serial port, the terminal
+    /// does not print it itself.
+    pub fn new<F>(tx: Tx, rx: Rx, on_data: F) -> Self
     where
-        F: Fn(&TermHandle, &str) + Send + Sync + 'static,
+        F: Fn(&TermHandle, &[u8]) + Send + Sync + 'static,
     {
         SerialTerm {
             tx: Arc::new(Mutex::new(tx)),
             rx: Arc::new(Mutex::new(rx)),
-            on_line: Some(Box::new(on_line)),
+            on_data: Some(Box::new(on_data)),
+            timeout: None,
+            timed_out: false,
         }
     }
 
+    /// Stops the terminal once `timeout` has passed since [`SerialTerm::run`]
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the terminal was stopped by its timeout
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
     pub async fn run(&mut self) -> anyhow::Result<()> {
         // 启用raw模式
 
@@ -77,7 +94,7 @@ impl SerialTerm {
         let tx_port = self.tx.clone();
         let rx_port = self.rx.clone();
 
-        let on_line = self.on_line.take().unwrap();
+        let on_data = self.on_data.take().unwrap();
 
         let handle = Arc::new(TermHandle {
             is_running: AtomicBool::new(true),
@@ -86,13 +103,19 @@ impl SerialTerm {
         // 启动串口接收线程
         let rx_handle = thread::spawn({
             let handle = handle.clone();
-            move || Self::handle_serial_receive(rx_port, handle, on_line)
+            move || Self::handle_serial_receive(rx_port, handle, on_data)
         });
 
         // 主线程处理键盘输入
         let mut key_state = KeySequenceState::Normal;
+        let deadline = self.timeout.map(|t| Instant::now() + t);
 
         while handle.is_running() {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                self.timed_out = true;
+                handle.stop();
+                break;
+            }
             // 非阻塞读取键盘事件
             if event::poll(Duration::from_millis(10)).is_ok()
                 && let Ok(Event::Key(key)) = event::read()
@@ -141,35 +164,18 @@ impl SerialTerm {
     fn handle_serial_receive<F>(
         rx_port: Arc<Mutex<Rx>>,
         handle: Arc<TermHandle>,
-        on_line: F,
+        on_data: F,
     ) -> io::Result<()>
     where
-        F: Fn(&TermHandle, &str) + Send + Sync + 'static,
+        F: Fn(&TermHandle, &[u8]) + Send + Sync + 'static,
     {
         let mut buffer = [0u8; 1024];
-        let mut byte = [0u8; 1];
-        let mut line = Vec::with_capacity(0x1000);
 
         while handle.is_running() {
             // 从串口读取数据
             match rx_port.lock().unwrap().read(&mut buffer) {
                 Ok(bytes_read) if bytes_read > 0 => {
-                    // 将数据直接写入stdout
-                    let data = &buffer[..bytes_read];
-                    for &b in data {
-                        line.push(b);
-                        if b == b'\n' {
-                            byte[0] = b'\r';
-                            io::stdout().write_all(&byte)?;
-                            let line_str = String::from_utf8_lossy(&line);
-                            (on_line)(handle.as_ref(), &line_str);
-                            line.clear();
-                        }
-                        byte[0] = b;
-                        io::stdout().write_all(&byte)?;
-                    }
-
-                    io::stdout().flush()?;
+                    (on_data)(handle.as_ref(), &buffer[..bytes_read]);
                 }
                 Ok(_) => {
                     // 没有数据可读，短暂休眠
@@ -0,0 +1,211 @@
+//! Resolves code addresses printed by the kernel, e.g. in panic backtraces,
+//! to `function at file:line` using the DWARF line tables of the ELF.
+//! Falls back to the symbol table when the ELF has no debug info.
+
+use std::{borrow::Cow, path::Path, sync::Arc};
+
+use anyhow::Context as _;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
+use regex::Regex;
+
+type Reader = gimli::EndianArcSlice<gimli::RunTimeEndian>;
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+pub struct Symbolizer {
+    dwarf: addr2line::Context<Reader>,
+    /// Function symbols sorted by address
+    symbols: Vec<Symbol>,
+    /// Executable sections, addresses outside of them are never resolved
+    text: Vec<(u64, u64)>,
+    address_regex: Regex,
+}
+
+impl Symbolizer {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("can not read ELF: {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("can not load symbols: {}", path.display()))
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let file = object::File::parse(data)?;
+        let endian = if file.is_little_endian() {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|s| s.uncompressed_data().ok())
+                .unwrap_or_default();
+            Ok(gimli::EndianArcSlice::new(Arc::from(&*data), endian))
+        })?;
+        let dwarf = addr2line::Context::from_dwarf(dwarf)?;
+
+        let mut symbols = file
+            .symbols()
+            .filter(|s| s.kind() == SymbolKind::Text && s.address() != 0)
+            .filter_map(|s| {
+                Some(Symbol {
+                    address: s.address(),
+                    size: s.size(),
+                    name: s.name().ok()?.to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|s| s.address);
+
+        let text = file
+            .sections()
+            .filter(|s| s.kind() == SectionKind::Text && s.size() > 0)
+            .map(|s| (s.address(), s.address() + s.size()))
+            .collect();
+
+        Ok(Self {
+            dwarf,
+            symbols,
+            text,
+            address_regex: Regex::new(r"\b0x([0-9a-fA-F]{1,16})\b|\b([0-9a-fA-F]{16})\b").unwrap(),
+        })
+    }
+
+    /// Resolves `address` to `function at file:line`. Inlined frames are listed
+    /// innermost first. `None` if the address is not in the kernel code.
+    pub fn lookup(&self, address: u64) -> Option<String> {
+        if !self
+            .text
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&address))
+        {
+            return None;
+        }
+
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = self.dwarf.find_frames(address).skip_all_loads() {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|f| f.demangle().ok().map(|n| n.to_string()));
+                let location = frame.location.as_ref().and_then(|l| {
+                    Some(match l.line {
+                        Some(line) => format!("{}:{line}", l.file?),
+                        None => l.file?.to_string(),
+                    })
+                });
+
+                let function = function
+                    .or_else(|| self.symbol(address))
+                    .unwrap_or_else(|| "??".to_string());
+                frames.push(match location {
+                    Some(location) => format!("{function} at {location}"),
+                    None => function,
+                });
+            }
+        }
+
+        if frames.is_empty() {
+            return self.symbol(address);
+        }
+        Some(frames.join(", inlined into "))
+    }
+
+    /// Resolved addresses found in a console line, formatted as `0x...: function at file:line`.
+    pub fn annotate(&self, line: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        for caps in self.address_regex.captures_iter(line) {
+            let Some(hex) = caps.get(1).or_else(|| caps.get(2)) else {
+                continue;
+            };
+            let Ok(address) = u64::from_str_radix(hex.as_str(), 16) else {
+                continue;
+            };
+            if let Some(resolved) = self.lookup(address) {
+                out.push(format!("{address:#x}: {resolved}"));
+            }
+        }
+        out
+    }
+
+    /// `name+0xoffset` from the symbol table
+    fn symbol(&self, address: u64) -> Option<String> {
+        let index = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols.get(index.checked_sub(1)?)?;
+        if symbol.size != 0 && address >= symbol.address + symbol.size {
+            return None;
+        }
+
+        let name = addr2line::demangle_auto(Cow::Borrowed(symbol.name.as_str()), None);
+        let offset = address - symbol.address;
+        if offset == 0 {
+            Some(name.to_string())
+        } else {
+            Some(format!("{name}+{offset:#x}"))
+        }
+    }
+}
+
+/// `ostool addr2line`: resolves `addresses`, or annotates console lines read
+/// from stdin when none are given.
+pub fn addr2line(elf: &Path, addresses: &[String]) -> anyhow::Result<()> {
+    let symbolizer = Symbolizer::load(elf)?;
+
+    if addresses.is_empty() {
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            println!("{line}");
+            for note in symbolizer.annotate(&line) {
+                println!("  -> {note}");
+            }
+        }
+        return Ok(());
+    }
+
+    for address in addresses {
+        let hex = address.trim_start_matches("0x").trim_start_matches("0X");
+        let address = u64::from_str_radix(hex, 16)
+            .map_err(|_| anyhow!("invalid address `{address}`, expected hex"))?;
+        let resolved = symbolizer
+            .lookup(address)
+            .unwrap_or_else(|| "??".to_string());
+        println!("{address:#x}: {resolved}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_own_function() {
+        let exe = std::env::current_exe().unwrap();
+        let symbolizer = Symbolizer::load(&exe).unwrap();
+
+        let address = symbolizer
+            .symbols
+            .iter()
+            .find(|s| s.name.contains("test_resolve_own_function"))
+            .map(|s| s.address)
+            .unwrap();
+
+        let resolved = symbolizer.lookup(address).unwrap();
+        assert!(resolved.contains("test_resolve_own_function"), "{resolved}");
+        assert!(resolved.contains("symbolize.rs"), "{resolved}");
+
+        let line = format!("  #0 {address:#018x} and 0x0");
+        let notes = symbolizer.annotate(&line);
+        assert_eq!(notes.len(), 1, "{notes:?}");
+        assert!(
+            notes[0].starts_with(&format!("{address:#x}: ")),
+            "{notes:?}"
+        );
+    }
+}
@@ -5,11 +5,13 @@ use std::{
 };
 
 use anyhow::bail;
-use colored::Colorize;
+
+use crate::event::{Event, Events};
 
 pub struct Command {
     inner: std::process::Command,
     value_replace: Box<dyn Fn(&OsStr) -> String>,
+    events: Events,
 }
 
 impl Deref for Command {
@@ -42,21 +44,38 @@ impl Command {
         Self {
             inner: cmd,
             value_replace: Box::new(value_replace),
+            events: Events::default(),
         }
     }
 
-    pub fn print_cmd(&self) {
+    /// Sink the command line is reported to by [`Command::print_cmd`]
+    pub fn events(&mut self, events: Events) -> &mut Command {
+        self.events = events;
+        self
+    }
+
+    /// Program and arguments separated by spaces
+    pub fn cmd_line(&self) -> String {
         let mut cmd_str = self.get_program().to_string_lossy().to_string();
 
         for arg in self.get_args() {
             cmd_str += " ";
             cmd_str += arg.to_string_lossy().as_ref();
         }
+        cmd_str
+    }
 
-        println!("{}", cmd_str.purple().bold());
+    pub fn print_cmd(&self) {
+        self.events.emit(Event::Command {
+            command: self.cmd_line(),
+        });
     }
 
+    /// Runs to completion. With JSON events the program's stdout goes to stderr.
     pub fn run(&mut self) -> anyhow::Result<()> {
+        if self.events.is_json() {
+            self.stdout(std::io::stderr());
+        }
         self.print_cmd();
         let status = self.status()?;
         if !status.success() {
@@ -143,201 +162,3 @@ impl Command {
 
 //     Ok(config)
 // }
-
-pub fn replace_env_placeholders(input: &str) -> anyhow::Result<String> {
-    use std::env;
-
-    // 使用正则表达式匹配 ${env:VAR_NAME} 格式
-    // 由于我们要避免外部依赖，使用简单的字符串解析
-    let mut result = String::new();
-    let mut chars = input.chars().peekable();
-
-    while let Some(ch) = chars.next() {
-        if ch == '$' && chars.peek() == Some(&'{') {
-            // 开始可能的占位符
-            chars.next(); // 消耗 '{'
-            let mut placeholder = String::new();
-            let mut brace_count = 1;
-            let mut found_closing_brace = false;
-
-            // 收集占位符内容
-            for ch in chars.by_ref() {
-                if ch == '{' {
-                    brace_count += 1;
-                    placeholder.push(ch);
-                } else if ch == '}' {
-                    brace_count -= 1;
-                    if brace_count == 0 {
-                        found_closing_brace = true;
-                        break;
-                    } else {
-                        placeholder.push(ch);
-                    }
-                } else {
-                    placeholder.push(ch);
-                }
-            }
-
-            // 只有找到完整的占位符才进行处理
-            if found_closing_brace && placeholder.starts_with("env:") {
-                let env_var_name = &placeholder[4..]; // 跳过 "env:"
-
-                // 获取环境变量值，如果不存在则替换为空字符串
-                match env::var(env_var_name) {
-                    Ok(value) => {
-                        println!("Using {env_var_name}={value}");
-                        result.push_str(&value)
-                    }
-                    Err(_) => {
-                        // 环境变量不存在时替换为空字符串，不返回错误
-                        result.push_str("");
-                    }
-                }
-            } else {
-                // 不是完整的占位符或不是环境变量占位符，保持原样
-                result.push_str("${");
-                result.push_str(&placeholder);
-                if found_closing_brace {
-                    result.push('}');
-                }
-            }
-        } else {
-            result.push(ch);
-        }
-    }
-
-    Ok(result)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-
-    #[test]
-    fn test_replace_env_placeholders() {
-        // 设置测试环境变量
-        unsafe {
-            env::set_var("TEST_HOME", "/home/test");
-            env::set_var("TEST_PATH", "/usr/local/bin");
-        }
-
-        // 测试简单的环境变量替换
-        assert_eq!(
-            replace_env_placeholders("${env:TEST_HOME}").unwrap(),
-            "/home/test"
-        );
-
-        // 测试多个环境变量
-        assert_eq!(
-            replace_env_placeholders("${env:TEST_HOME}:${env:TEST_PATH}").unwrap(),
-            "/home/test:/usr/local/bin"
-        );
-
-        // 测试不存在的环境变量 - 应该返回空字符串而不是错误
-        let result = replace_env_placeholders("${env:NON_EXISTENT}");
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "");
-
-        // 测试混合内容
-        assert_eq!(
-            replace_env_placeholders("Path: ${env:TEST_HOME}/bin").unwrap(),
-            "Path: /home/test/bin"
-        );
-
-        // 测试非环境变量占位符
-        assert_eq!(
-            replace_env_placeholders("${not_env:placeholder}").unwrap(),
-            "${not_env:placeholder}"
-        );
-
-        // 测试无占位符的字符串
-        assert_eq!(
-            replace_env_placeholders("Just a normal string").unwrap(),
-            "Just a normal string"
-        );
-
-        // 测试空字符串
-        assert_eq!(replace_env_placeholders("").unwrap(), "");
-    }
-
-    #[test]
-    fn test_nested_braces() {
-        unsafe {
-            env::set_var("TEST_VAR", "value");
-        }
-
-        // 测试嵌套大括号的情况
-        assert_eq!(
-            replace_env_placeholders("${env:TEST_VAR} and ${other:placeholder}").unwrap(),
-            "value and ${other:placeholder}"
-        );
-    }
-
-    #[test]
-    fn test_real_env_vars() {
-        // 测试真实的环境变量（如果存在）
-        if let Ok(home) = env::var("HOME") {
-            assert_eq!(replace_env_placeholders("${env:HOME}").unwrap(), home);
-        }
-    }
-
-    #[test]
-    fn test_edge_cases() {
-        // 测试不完整的占位符
-        assert_eq!(replace_env_placeholders("${").unwrap(), "${");
-        assert_eq!(replace_env_placeholders("${env").unwrap(), "${env");
-        assert_eq!(replace_env_placeholders("${env:").unwrap(), "${env:");
-        assert_eq!(replace_env_placeholders("${env:VAR").unwrap(), "${env:VAR");
-
-        // 测试空的env变量名 - 应该返回空字符串而不是错误
-        let result = replace_env_placeholders("${env:}");
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "");
-
-        // 测试只包含$的字符串
-        assert_eq!(replace_env_placeholders("$").unwrap(), "$");
-        assert_eq!(replace_env_placeholders("$$").unwrap(), "$$");
-
-        // 测试包含特殊字符的环境变量名
-        unsafe {
-            env::set_var("TEST-VAR", "dash-value");
-            env::set_var("TEST_VAR", "underscore-value");
-        }
-        assert_eq!(
-            replace_env_placeholders("${env:TEST-VAR}").unwrap(),
-            "dash-value"
-        );
-        assert_eq!(
-            replace_env_placeholders("${env:TEST_VAR}").unwrap(),
-            "underscore-value"
-        );
-
-        // 测试空的环境变量值
-        unsafe {
-            env::set_var("EMPTY_VAR", "");
-        }
-        assert_eq!(replace_env_placeholders("${env:EMPTY_VAR}").unwrap(), "");
-    }
-
-    #[test]
-    fn test_malformed_placeholders() {
-        // 测试格式错误的占位符
-        assert_eq!(replace_env_placeholders("${env:VAR").unwrap(), "${env:VAR");
-        assert_eq!(replace_env_placeholders("${env}").unwrap(), "${env}");
-        assert_eq!(replace_env_placeholders("${:VAR}").unwrap(), "${:VAR}");
-
-        // 设置测试环境变量
-        unsafe {
-            env::set_var("VAR", "value");
-        }
-
-        // 测试混合的大括号
-        // 当遇到完整的占位符后停止，剩余字符由主循环继续处理
-        assert_eq!(replace_env_placeholders("${env:VAR}}").unwrap(), "value}");
-
-        // 测试其他格式错误的情况
-        assert_eq!(replace_env_placeholders("{env:VAR}").unwrap(), "{env:VAR}");
-        assert_eq!(replace_env_placeholders("$env:VAR}").unwrap(), "$env:VAR}");
-    }
-}